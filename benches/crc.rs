@@ -0,0 +1,56 @@
+//! Compares the CRC tables in crc::table with the crc_any crate
+//!
+//! Run with: cargo bench --bench crc
+
+use crc_any::CRC;
+use serial_com_rust::crc::table::{CrcParams, CrcTable, CRC16_DNP, CRC32};
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// How long each case runs for
+const RUN_TIME: Duration = Duration::from_millis(300);
+
+/// Run compute on data repeatedly and print its throughput
+fn bench<F: FnMut(&[u8]) -> u32>(name: &str, data: &[u8], mut compute: F) {
+    let start = Instant::now();
+    let mut n_runs: u64 = 0;
+    while start.elapsed() < RUN_TIME {
+        for _ in 0..64 {
+            black_box(compute(black_box(data)));
+        }
+        n_runs += 64;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    let mega_bytes = (n_runs * data.len() as u64) as f64 / 1e6;
+    println!(
+        "{:<28} {:>6} bytes: {:>8.1} MB/s",
+        name,
+        data.len(),
+        mega_bytes / seconds
+    );
+}
+
+fn bench_crc(name: &str, params: CrcParams, reference: fn() -> CRC, data: &[u8]) {
+    let bytewise: CrcTable<1> = CrcTable::new(params);
+    let slice_by_8: CrcTable<8> = CrcTable::new(params);
+    bench(&format!("{} crc_any", name), data, |data| {
+        let mut crc = reference();
+        crc.digest(data);
+        crc.get_crc() as u32
+    });
+    bench(&format!("{} table byte-wise", name), data, |data| {
+        bytewise.checksum(data)
+    });
+    bench(&format!("{} table slice-by-8", name), data, |data| {
+        slice_by_8.checksum(data)
+    });
+}
+
+fn main() {
+    let data: Vec<u8> = (0..4096u32).map(|i| (i * 31 + 7) as u8).collect();
+    for len in [16usize, 256, 4096].iter() {
+        bench_crc("CRC-16/DNP", CRC16_DNP, CRC::crc16dnp, &data[..*len]);
+        bench_crc("CRC-32", CRC32, CRC::crc32, &data[..*len]);
+    }
+}
@@ -0,0 +1,70 @@
+//! Share one device link between local processes
+//!
+//! Usage: serialcom-broker SOCKET_PATH DEVICE [--baud BAUD] [--reg-width 8|32]
+//!
+//! DEVICE is a tty path like /dev/ttyUSB0, or tcp:HOST:PORT for a serial port exposed over TCP.
+//! Clients connect to SOCKET_PATH with a UnixTransport.
+
+use serial_com_rust::binarycom::app::RegisterBitWidth;
+use serial_com_rust::broker::Broker;
+use serial_com_rust::error::SerialComResult;
+use serial_com_rust::transport::serial::{SerialPort, SerialSettings};
+use serial_com_rust::transport::tcp::TcpTransport;
+use serial_com_rust::transport::Transport;
+
+use std::process;
+
+const USAGE: &str = "Usage: serialcom-broker SOCKET_PATH DEVICE [--baud BAUD] [--reg-width 8|32]";
+
+fn main() -> SerialComResult<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut positional: Vec<&str> = Vec::new();
+    let mut settings = SerialSettings::default();
+    let mut register_bit_width = RegisterBitWidth::ThirtyTwo;
+    let mut i_arg = 0;
+    while i_arg < args.len() {
+        match args[i_arg].as_str() {
+            "--baud" => {
+                i_arg += 1;
+                settings.baud_rate = parse_or_exit(args.get(i_arg));
+            }
+            "--reg-width" => {
+                i_arg += 1;
+                register_bit_width = match parse_or_exit::<u32>(args.get(i_arg)) {
+                    8 => RegisterBitWidth::Eight,
+                    32 => RegisterBitWidth::ThirtyTwo,
+                    _ => exit_usage(),
+                };
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            arg => positional.push(arg),
+        }
+        i_arg += 1;
+    }
+    if positional.len() != 2 {
+        exit_usage();
+    }
+    let (socket_path, device_name) = (positional[0], positional[1]);
+    let device: Box<dyn Transport> = match device_name.strip_prefix("tcp:") {
+        Some(addr) => Box::new(TcpTransport::connect(addr)?),
+        None => Box::new(SerialPort::open(device_name, &settings)?),
+    };
+    let broker = Broker::bind(socket_path, device, register_bit_width)?;
+    println!("Sharing {} on {}", device_name, socket_path);
+    broker.run()
+}
+
+fn parse_or_exit<T: std::str::FromStr>(arg: Option<&String>) -> T {
+    match arg.map(|arg| arg.parse()) {
+        Some(Ok(val)) => val,
+        _ => exit_usage(),
+    }
+}
+
+fn exit_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
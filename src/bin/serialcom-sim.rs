@@ -0,0 +1,152 @@
+//! Simulated device, for developing host-side code without hardware
+//!
+//! Usage: serialcom-sim (--pty | --tcp ADDR | --stdio) [--reg-width 8|32] [--reg NUM=VAL]...
+//!                      [--stream sine|ramp|noise|counter] [--stream-bits 8|16]
+//!                      [--interval-ms MS] [--framing cobs|cobsr|slip|hdlc]
+//!                      [--checksum crc16-dnp|crc16-ccitt-false|crc16-modbus|crc32|crc32c|
+//!                                  crc8|none] [--reliable] [--address ADDR]
+//!
+//! With --pty, the path of the tty to connect the host to is printed. With --tcp, the simulator
+//! listens for a host on ADDR, e.g. 127.0.0.1:5000. With --stdio, the simulator talks over stdin
+//! and stdout. The framing and checksum default to COBS and CRC-16/DNP. --reliable turns on
+//! sequence numbers and ACK/NACK; the host must use a reliable link too. --address makes the
+//! simulator a device on a multi-drop bus, answering only requests to ADDR.
+
+use serial_com_rust::binarycom::{LinkConfig, RegisterBitWidth};
+use serial_com_rust::error::SerialComResult;
+use serial_com_rust::sim::{run_simulator_at, RegisterMap, StreamGenerator, StreamKind};
+use serial_com_rust::transport::pty::{PtyMaster, PtyPair};
+use serial_com_rust::transport::serial::{SerialPort, SerialSettings};
+use serial_com_rust::transport::tcp::TcpTransport;
+use serial_com_rust::transport::{stdio_transport, Transport};
+
+use std::fs::OpenOptions;
+use std::process;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: serialcom-sim (--pty | --tcp ADDR | --stdio) [--reg-width 8|32] \
+[--reg NUM=VAL]... [--stream sine|ramp|noise|counter] [--stream-bits 8|16] [--interval-ms MS] \
+[--framing cobs|cobsr|slip|hdlc] \
+[--checksum crc16-dnp|crc16-ccitt-false|crc16-modbus|crc32|crc32c|crc8|none] [--reliable] \
+[--address ADDR]";
+
+/// How long the simulator waits for host bytes before checking whether a stream message is due
+const READ_TIMEOUT: Duration = Duration::from_millis(5);
+
+enum Link {
+    Pty,
+    Tcp(String),
+    Stdio,
+}
+
+fn main() -> SerialComResult<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut link: Option<Link> = None;
+    let mut register_bit_width = RegisterBitWidth::ThirtyTwo;
+    let mut assignments: Vec<&str> = Vec::new();
+    let mut stream_kind: Option<StreamKind> = None;
+    let mut stream_bits: u8 = 16;
+    let mut interval = Duration::from_millis(100);
+    let mut link_config = LinkConfig::default();
+    let mut address: Option<u8> = None;
+    let mut i_arg = 0;
+    while i_arg < args.len() {
+        match args[i_arg].as_str() {
+            "--pty" => link = Some(Link::Pty),
+            "--tcp" => {
+                i_arg += 1;
+                link = Some(Link::Tcp(
+                    args.get(i_arg).cloned().unwrap_or_else(|| exit_usage()),
+                ));
+            }
+            "--stdio" => link = Some(Link::Stdio),
+            "--reg-width" => {
+                i_arg += 1;
+                register_bit_width = match parse_or_exit::<u32>(args.get(i_arg)) {
+                    8 => RegisterBitWidth::Eight,
+                    32 => RegisterBitWidth::ThirtyTwo,
+                    _ => exit_usage(),
+                };
+            }
+            "--reg" => {
+                i_arg += 1;
+                assignments.push(args.get(i_arg).unwrap_or_else(|| exit_usage()));
+            }
+            "--stream" => {
+                i_arg += 1;
+                stream_kind = Some(parse_or_exit(args.get(i_arg)));
+            }
+            "--stream-bits" => {
+                i_arg += 1;
+                stream_bits = parse_or_exit(args.get(i_arg));
+            }
+            "--interval-ms" => {
+                i_arg += 1;
+                interval = Duration::from_millis(parse_or_exit(args.get(i_arg)));
+            }
+            "--framing" => {
+                i_arg += 1;
+                link_config.framing = parse_or_exit(args.get(i_arg));
+            }
+            "--checksum" => {
+                i_arg += 1;
+                link_config.checksum = parse_or_exit(args.get(i_arg));
+            }
+            "--reliable" => link_config.reliable = true,
+            "--address" => {
+                i_arg += 1;
+                address = Some(parse_or_exit(args.get(i_arg)));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => exit_usage(),
+        }
+        i_arg += 1;
+    }
+
+    let mut registers = RegisterMap::new(register_bit_width);
+    for assignment in assignments {
+        registers.set_from_str(assignment)?;
+    }
+    let stream = match stream_kind {
+        Some(kind) => Some((StreamGenerator::new(kind, stream_bits)?, interval)),
+        None => None,
+    };
+    // Keeps the PTY slave open so reads from the master don't fail before the host connects
+    let mut _slave_keepalive: Option<SerialPort> = None;
+    let transport: Box<dyn Transport> = match link {
+        Some(Link::Pty) => {
+            let pty = PtyPair::open()?;
+            let slave = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&pty.slave_path)?;
+            _slave_keepalive = Some(SerialPort::from_file(slave, &SerialSettings::default())?);
+            eprintln!("Simulated device on {}", pty.slave_path.display());
+            Box::new(PtyMaster::new(pty.master, READ_TIMEOUT))
+        }
+        Some(Link::Tcp(addr)) => {
+            let mut transport = TcpTransport::listen(&addr)?;
+            transport.set_read_timeout(READ_TIMEOUT);
+            eprintln!("Simulated device listening on {}", transport.local_addr()?);
+            Box::new(transport)
+        }
+        Some(Link::Stdio) => Box::new(stdio_transport()),
+        None => exit_usage(),
+    };
+    run_simulator_at(transport, link_config, address, registers, stream)
+}
+
+fn parse_or_exit<T: std::str::FromStr>(arg: Option<&String>) -> T {
+    match arg.map(|arg| arg.parse()) {
+        Some(Ok(val)) => val,
+        _ => exit_usage(),
+    }
+}
+
+fn exit_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
@@ -1,34 +1,150 @@
-use crate::binarycom::hostreceiver::HostReceiver16;
+use crate::binarycom::errorreply::ErrorReply;
+use crate::binarycom::fragment::{Fragmenter, CMD_FRAGMENT};
+use crate::binarycom::hello::{
+    DeviceInfo, CAP_BLOCK_READ, CAP_FRAGMENTS, CAP_TAGGED, CMD_HELLO, PROTOCOL_VERSION_MAJOR,
+    PROTOCOL_VERSION_MINOR,
+};
+pub use crate::binarycom::hostreceiver::TaggedReply;
+use crate::binarycom::hostreceiver::{DeviceQueues, HostReceiver, LinkStats};
 use crate::binarycom::packers;
-use crate::binarycom::BinaryCom;
-use crate::error::SerialComResult;
+pub use crate::binarycom::reliable::RetryPolicy;
+use crate::binarycom::reliable::{CMD_ACK, CMD_NACK, CMD_SYNC};
+pub use crate::binarycom::supervisor::{LinkEvent, LinkState, LinkSupervisor, SupervisorConfig};
+use crate::binarycom::{
+    frame_data_len, max_data_len, send_message_headed, BROADCAST_ADDRESS, CMD_READ_REGS,
+    CMD_READ_REG_TAGGED, CMD_WRITE_REG_TAGGED, MAX_READ_REGS_LEN,
+};
+pub use crate::binarycom::{LinkConfig, RegisterBitWidth};
+use crate::error::{SerialComError, SerialComResult};
+use crate::framer::Framer;
+use crate::transport::Transport;
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
-use std::sync::mpsc;
+use std::io;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub enum RegisterBitWidth {
-    Eight,
-    ThirtyTwo,
+/// How often a reliable request checks for a NACK while it waits for its reply
+const NACK_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How long after sending a tagged request its reply may take
+const TAGGED_REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How many tagged requests read_many and write_many keep in flight
+const MAX_IN_FLIGHT: usize = 16;
+
+/// A tagged request waiting for its reply
+struct PendingRequest {
+    reg_num: u16,
+    is_read: bool,
+    sent_at: Instant,
 }
 
-pub struct BinaryComApp {
+impl PendingRequest {
+    fn matches(&self, reply: &Result<TaggedReply, ErrorReply>) -> bool {
+        match *reply {
+            Ok(TaggedReply::Read(reg_num, _)) => self.is_read && reg_num == self.reg_num,
+            Ok(TaggedReply::Write(reg_num)) => !self.is_read && reg_num == self.reg_num,
+            Err(error_reply) => {
+                (error_reply.command == CMD_READ_REG_TAGGED) == self.is_read
+                    && error_reply.reg_num == self.reg_num
+            }
+        }
+    }
+}
+
+/// Host side of the protocol, for a link of N byte messages
+///
+/// Sends requests to the device and waits for their replies, which a HostReceiver thread sorts
+/// onto queues. The device should be built for the same N, see DeviceResponder.
+pub struct BinaryComApp<const N: usize> {
     pub stream_thread_handle: thread::JoinHandle<()>,
-    hostreceiver: HostReceiver16,
-    outbuf: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping>,
+    /// Reply queues of the device, keyed None, or of each device on a bus
+    queues: HashMap<Option<u8>, DeviceQueues>,
+    rx_ack: mpsc::Receiver<(u8, u8)>,
+    /// Device that requests go to, on a bus
+    address: Option<u8>,
+    /// Shared with the LinkSupervisors, which write their pings under the same lock
+    transport: Arc<Mutex<Box<dyn Transport>>>,
+    link: LinkConfig,
+    /// Encodes requests; the HostReceiver thread has its own for decoding
+    framer: Box<dyn Framer + Send>,
+    retry_policy: RetryPolicy,
+    tx_seq: u8,
+    /// Tagged requests sent and not yet waited for, by tag
+    pending: HashMap<u8, PendingRequest>,
+    /// Replies to pending requests that arrived while waiting for another one
+    completed: HashMap<u8, Result<TaggedReply, ErrorReply>>,
+    next_tag: u8,
+    /// Message id of the next message sent in fragments
+    fragment_id: u8,
     regbitwidth: RegisterBitWidth,
+    /// What each device said in the hello handshake, keyed like queues
+    device_infos: HashMap<Option<u8>, DeviceInfo>,
+    /// Devices a session has been started with on a reliable link, keyed like queues
+    synced: HashSet<Option<u8>>,
+    /// Ping reply queues of the devices not yet supervised, keyed like queues
+    rx_pings: HashMap<Option<u8>, mpsc::Receiver<u8>>,
+    stats: Arc<LinkStats>,
 }
 
-impl BinaryComApp {
+pub type BinaryComApp16 = BinaryComApp<16>;
+
+impl<const N: usize> BinaryComApp<N> {
+    /// Longest encoded request, with room for the byte stuffing of any framer
+    const MAX_FRAME_LEN: usize = 2 * N + 2;
+
+    /// Setup the app to talk to a device over transport
+    ///
+    /// Spawns the HostReceiver thread, reading from a clone of transport, and the stream
+    /// handling thread, which prints the stream data.
     pub fn new(
-        register_bit_width: RegisterBitWidth, /*, stream_handler: Fn(&mut mpsc::Receiver<(u8, Vec<u8>)>)*/
-    ) -> BinaryComApp {
-        let (hr, rx_stream) = HostReceiver16::new();
+        register_bit_width: RegisterBitWidth,
+        transport: Box<dyn Transport>,
+    ) -> SerialComResult<BinaryComApp<N>> {
+        BinaryComApp::with_stream_handler(register_bit_width, transport, |_command, data| {
+            println!("The data is: {:?}", data)
+        })
+    }
+    /// Setup the app to talk to a device over transport
+    ///
+    /// Like new, but the stream handling thread calls stream_handler with the command and
+    /// unpacked data of each stream message.
+    pub fn with_stream_handler<F>(
+        register_bit_width: RegisterBitWidth,
+        transport: Box<dyn Transport>,
+        stream_handler: F,
+    ) -> SerialComResult<BinaryComApp<N>>
+    where
+        F: FnMut(u8, Vec<u32>) + Send + 'static,
+    {
+        BinaryComApp::with_link(
+            register_bit_width,
+            transport,
+            LinkConfig::default(),
+            stream_handler,
+        )
+    }
+    /// Setup the app to talk to a device over transport
+    ///
+    /// Like with_stream_handler, for a device that frames and checks messages as link says.
+    /// Unanswered requests are sent again as the default RetryPolicy says.
+    pub fn with_link<F>(
+        register_bit_width: RegisterBitWidth,
+        transport: Box<dyn Transport>,
+        link: LinkConfig,
+        mut stream_handler: F,
+    ) -> SerialComResult<BinaryComApp<N>>
+    where
+        F: FnMut(u8, Vec<u32>) + Send + 'static,
+    {
+        let (hostreceiver, rx_stream) = HostReceiver::<N>::with_link(transport.try_clone()?, link);
         let stream_thread = thread::spawn(move || loop {
             match rx_stream.recv() {
                 Ok((command, data_vec)) => match packers::unpack_stream(command, data_vec) {
-                    Ok(data) => println!("The data is: {:?}", data),
+                    Ok(data) => stream_handler(command, data),
                     Err(unpack_err) => {
                         println!("Error while unpacking stream data: {}", unpack_err)
                     }
@@ -39,60 +155,1697 @@ impl BinaryComApp {
                 }
             }
         });
-        BinaryComApp {
+        let HostReceiver {
+            rx_reg_read,
+            rx_reg_write,
+            rx_tagged,
+            rx_regs,
+            rx_hello,
+            rx_ping,
+            rx_sync,
+            rx_ack,
+            stats,
+            ..
+        } = hostreceiver;
+        let queues = DeviceQueues {
+            rx_reg_read,
+            rx_reg_write,
+            rx_tagged,
+            rx_regs,
+            rx_hello,
+            // Taken out for the supervisor below
+            rx_ping: mpsc::channel().1,
+            rx_sync,
+        };
+        Ok(BinaryComApp {
             stream_thread_handle: stream_thread,
-            hostreceiver: hr,
-            outbuf: arraydeque::ArrayDeque::new(),
+            queues: std::iter::once((None, queues)).collect(),
+            rx_ack,
+            address: None,
+            transport: Arc::new(Mutex::new(transport)),
+            link,
+            framer: link.framing.new_framer::<N>(),
+            retry_policy: RetryPolicy::default(),
+            tx_seq: 0,
+            pending: HashMap::new(),
+            completed: HashMap::new(),
+            next_tag: 0,
+            fragment_id: 0,
             regbitwidth: register_bit_width,
-        }
+            device_infos: HashMap::new(),
+            synced: HashSet::new(),
+            rx_pings: std::iter::once((None, rx_ping)).collect(),
+            stats,
+        })
     }
-    pub fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
-        match self.regbitwidth {
-            RegisterBitWidth::Eight => self
-                .outbuf
-                .host_write_reg8(reg_num, u8::try_from(reg_val)?)?,
-            RegisterBitWidth::ThirtyTwo => self.outbuf.host_write_reg32(reg_num, reg_val)?,
+    /// Setup the app to talk to the devices at addresses on a multi-drop bus, e.g. RS-485
+    ///
+    /// Like with_link, but each message carries the address of the device it's to or from.
+    /// Requests go to the device picked with select_device, the first of addresses to begin
+    /// with. stream_handler is called with the address, command and unpacked data of each
+    /// stream message from any of the devices.
+    pub fn with_bus<F>(
+        register_bit_width: RegisterBitWidth,
+        transport: Box<dyn Transport>,
+        link: LinkConfig,
+        addresses: &[u8],
+        mut stream_handler: F,
+    ) -> SerialComResult<BinaryComApp<N>>
+    where
+        F: FnMut(u8, u8, Vec<u32>) + Send + 'static,
+    {
+        let first_address = match addresses.first() {
+            Some(address) if !addresses.contains(&BROADCAST_ADDRESS) => *address,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "A bus needs device addresses, other than the broadcast address",
+                )
+                .into())
+            }
+        };
+        let (hostreceiver, rx_streams) =
+            HostReceiver::<N>::with_bus(transport.try_clone()?, link, addresses);
+        // The streams of all the devices are merged for the one stream handling thread
+        let (tx_merged, rx_merged) = mpsc::channel();
+        for (address, rx_stream) in rx_streams {
+            let tx_merged = tx_merged.clone();
+            thread::spawn(move || {
+                for (command, data_vec) in rx_stream.iter() {
+                    if tx_merged.send((address, command, data_vec)).is_err() {
+                        return;
+                    }
+                }
+            });
         }
-        loop {
-            match self
-                .hostreceiver
-                .rx_reg_write
-                .recv_timeout(Duration::from_millis(200))
-            {
-                Ok(reg_num_rec) => {
-                    if reg_num_rec == reg_num {
-                        return Ok(());
+        let stream_thread = thread::spawn(move || loop {
+            match rx_merged.recv() {
+                Ok((address, command, data_vec)) => {
+                    match packers::unpack_stream(command, data_vec) {
+                        Ok(data) => stream_handler(address, command, data),
+                        Err(unpack_err) => {
+                            println!("Error while unpacking stream data: {}", unpack_err)
+                        }
                     }
                 }
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    panic!("write_reg rx_reg_write timeout while waiting for response")
+                Err(mpsc::RecvError) => {
+                    println!("rx_stream disconnected, closing stream thread");
+                    return;
                 }
+            }
+        });
+        let HostReceiver {
+            rx_ack,
+            devices,
+            stats,
+            ..
+        } = hostreceiver;
+        let mut queues: HashMap<Option<u8>, DeviceQueues> = devices
+            .into_iter()
+            .map(|(address, queues)| (Some(address), queues))
+            .collect();
+        let rx_pings = queues
+            .iter_mut()
+            .map(|(address, queues)| {
+                (
+                    *address,
+                    std::mem::replace(&mut queues.rx_ping, mpsc::channel().1),
+                )
+            })
+            .collect();
+        Ok(BinaryComApp {
+            stream_thread_handle: stream_thread,
+            queues,
+            rx_ack,
+            address: Some(first_address),
+            transport: Arc::new(Mutex::new(transport)),
+            link,
+            framer: link.framing.new_framer::<N>(),
+            retry_policy: RetryPolicy::default(),
+            tx_seq: 0,
+            pending: HashMap::new(),
+            completed: HashMap::new(),
+            next_tag: 0,
+            fragment_id: 0,
+            regbitwidth: register_bit_width,
+            device_infos: HashMap::new(),
+            synced: HashSet::new(),
+            rx_pings,
+            stats,
+        })
+    }
+    /// Send the following requests to the device at address on the bus
+    ///
+    /// Tagged requests still pending are dropped.
+    pub fn select_device(&mut self, address: u8) -> SerialComResult<()> {
+        if !self.queues.contains_key(&Some(address)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No device at address {} on the bus", address),
+            )
+            .into());
+        }
+        self.address = Some(address);
+        self.pending.clear();
+        self.completed.clear();
+        Ok(())
+    }
+    /// Write a register on every device on the bus at once
+    ///
+    /// Devices don't reply to broadcasts, so this doesn't wait, and on a reliable link the
+    /// write isn't retransmitted.
+    pub fn broadcast_write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
+        if self.address.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Broadcasts need a bus, see with_bus",
+            )
+            .into());
+        }
+        let mut data: [u8; 6] = [0; 6];
+        let data_len = self.pack_write(reg_num, reg_val, &mut data)?;
+        let seq = if self.link.reliable {
+            Some(self.next_seq())
+        } else {
+            None
+        };
+        let mut frame: Vec<u8> = vec![0; Self::MAX_FRAME_LEN];
+        let frame_len = self.encode(
+            Some(BROADCAST_ADDRESS),
+            seq,
+            2,
+            &data[..data_len],
+            &mut frame,
+        )?;
+        write_shared(&self.transport, &frame[..frame_len])
+    }
+    /// Setup the app to talk to a device over transport, framing and checking messages as link
+    /// says
+    ///
+    /// Like with_link, but the register width isn't chosen by hand: the hello handshake is run
+    /// and the app configured from the device's answer. Fails with IncompatibleVersion if the
+    /// device speaks an incompatible protocol version.
+    pub fn connect<F>(
+        transport: Box<dyn Transport>,
+        link: LinkConfig,
+        stream_handler: F,
+    ) -> SerialComResult<BinaryComApp<N>>
+    where
+        F: FnMut(u8, Vec<u32>) + Send + 'static,
+    {
+        let mut app =
+            BinaryComApp::with_link(RegisterBitWidth::ThirtyTwo, transport, link, stream_handler)?;
+        app.handshake()?;
+        Ok(app)
+    }
+    /// Ask the device what it supports, and configure the app for it from the answer
+    ///
+    /// On a bus, asks the selected device. Fails with IncompatibleVersion if the device speaks an
+    /// incompatible protocol version.
+    pub fn handshake(&mut self) -> SerialComResult<DeviceInfo> {
+        let data = [PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR];
+        while self.queues().rx_hello.try_recv().is_ok() {}
+        let policy = self.retry_policy;
+        let device_info = self.request(CMD_HELLO, &data, policy, |queues, timeout| {
+            queues.rx_hello.recv_timeout(timeout)
+        })?;
+        device_info.check_version()?;
+        self.device_infos.insert(self.address, device_info);
+        Ok(device_info)
+    }
+    /// Start a new session with the selected device, on a reliable link
+    ///
+    /// The device forgets the seqs of the requests before, so it doesn't take this app's requests
+    /// for repeats of those, and starts its own seqs over; see reliable. Done before the first
+    /// request to each device.
+    pub fn sync(&mut self) -> SerialComResult<()> {
+        if !self.link.reliable {
+            return Ok(());
+        }
+        while self.queues().rx_sync.try_recv().is_ok() {}
+        let policy = self.retry_policy;
+        self.request(CMD_SYNC, &[], policy, |queues, timeout| {
+            queues.rx_sync.recv_timeout(timeout)
+        })?;
+        self.synced.insert(self.address);
+        Ok(())
+    }
+    /// What the selected device said in the hello handshake, if it has been run
+    pub fn device_info(&self) -> Option<&DeviceInfo> {
+        self.device_infos.get(&self.address)
+    }
+    /// Register width of the selected device: from the handshake, else as chosen by hand
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        self.device_info().map_or(self.regbitwidth, |device_info| {
+            device_info.register_bit_width
+        })
+    }
+    /// Whether tagged requests can be pipelined to the selected device
+    ///
+    /// Only devices that said so in a handshake are taken to support them; a broker, say, doesn't.
+    fn pipelines(&self) -> bool {
+        !self.link.reliable
+            && self
+                .device_info()
+                .is_some_and(|device_info| device_info.supports(CAP_TAGGED))
+    }
+    /// Start pinging the selected device to watch the link to it
+    ///
+    /// The LinkSupervisor tells its subscribers when the link is Connected, Degraded or Lost.
+    /// Each device can be supervised once.
+    pub fn supervise(&mut self, config: SupervisorConfig) -> SerialComResult<LinkSupervisor> {
+        let rx_ping = self.rx_pings.remove(&self.address).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "The device is already supervised",
+            )
+        })?;
+        Ok(LinkSupervisor::start(
+            Arc::clone(&self.transport),
+            self.link,
+            self.address,
+            rx_ping,
+            Arc::clone(&self.stats),
+            config,
+        ))
+    }
+    /// Counts of the frames received on the link
+    pub fn link_stats(&self) -> &LinkStats {
+        &self.stats
+    }
+    /// Set how unanswered requests are sent again, for the calls not given their own RetryPolicy
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+    /// Reply queues of the device requests go to
+    fn queues(&self) -> &DeviceQueues {
+        &self.queues[&self.address]
+    }
+    /// Encode a message to address, with seq on a reliable link, into frame
+    ///
+    /// Returns final message length
+    fn encode(
+        &self,
+        address: Option<u8>,
+        seq: Option<u8>,
+        command: u8,
+        data: &[u8],
+        frame: &mut [u8],
+    ) -> SerialComResult<usize> {
+        let mut header: [u8; 2] = [0; 2];
+        let mut header_len = 0;
+        for byte in address.into_iter().chain(seq) {
+            header[header_len] = byte;
+            header_len += 1;
+        }
+        send_message_headed(
+            &self.framer,
+            self.link.checksum,
+            &header[..header_len],
+            command,
+            data,
+            frame,
+        )
+    }
+    fn next_seq(&mut self) -> u8 {
+        let seq = self.tx_seq;
+        self.tx_seq = self.tx_seq.wrapping_add(1);
+        seq
+    }
+    /// Encode a message and write it to the transport
+    fn send(&mut self, command: u8, data: &[u8]) -> SerialComResult<()> {
+        let mut frame: Vec<u8> = vec![0; Self::MAX_FRAME_LEN];
+        let frame_len = self.encode(self.address, None, command, data, &mut frame)?;
+        write_shared(&self.transport, &frame[..frame_len])
+    }
+    /// Send a message that has no reply, on a reliable link sending it again until the device
+    /// ACKs it or the retries run out
+    fn send_unanswered(&mut self, command: u8, data: &[u8]) -> SerialComResult<()> {
+        let mut frame: Vec<u8> = vec![0; Self::MAX_FRAME_LEN];
+        if !self.link.reliable {
+            let frame_len = self.encode(self.address, None, command, data, &mut frame)?;
+            return write_shared(&self.transport, &frame[..frame_len]);
+        }
+        if !self.synced.contains(&self.address) {
+            self.sync()?;
+        }
+        let seq = self.next_seq();
+        let frame_len = self.encode(self.address, Some(seq), command, data, &mut frame)?;
+        let policy = self.retry_policy;
+        for attempt in 0..=policy.retries {
+            while self.rx_ack.try_recv().is_ok() {}
+            write_shared(&self.transport, &frame[..frame_len])?;
+            let acked = recv_matching(
+                &self.rx_ack,
+                policy.timeout(attempt),
+                |(ack_command, ack_seq)| *ack_command == CMD_NACK || *ack_seq == seq,
+            );
+            match acked {
+                Ok((CMD_ACK, _)) => return Ok(()),
+                Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
                 Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    panic!("write_reg rx_reg_write disconnected while waiting for response")
+                    return Err(SerialComError::Disconnected)
                 }
             }
         }
+        Err(SerialComError::RetriesExhausted)
     }
+    /// Send a request and wait for its reply, sending the request again until the reply arrives
+    /// or the retries policy allows run out
+    ///
+    /// wait_reply waits at most the given time for the reply on the device's queues, from which
+    /// the late replies to earlier requests are dropped before the first try. On a reliable
+    /// link the request carries a seq, so the device carries it out only once however many times
+    /// it's sent, and a NACK cuts the wait for a try short; a session is started with sync first if
+    /// there is none with the device. Fails with Timeout if no try is answered, RetriesExhausted
+    /// instead on a reliable link, and Disconnected if the HostReceiver thread has closed.
+    fn request<T, W>(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        policy: RetryPolicy,
+        mut wait_reply: W,
+    ) -> SerialComResult<T>
+    where
+        W: FnMut(&DeviceQueues, Duration) -> Result<T, mpsc::RecvTimeoutError>,
+    {
+        if self.link.reliable && command != CMD_SYNC && !self.synced.contains(&self.address) {
+            self.sync()?;
+        }
+        let seq = if self.link.reliable {
+            Some(self.next_seq())
+        } else {
+            None
+        };
+        let mut frame: Vec<u8> = vec![0; Self::MAX_FRAME_LEN];
+        let frame_len = self.encode(self.address, seq, command, data, &mut frame)?;
+        // Replies that came after an earlier request gave up would be taken for this one's
+        let queues = self.queues();
+        while queues.rx_reg_read.try_recv().is_ok() {}
+        while queues.rx_reg_write.try_recv().is_ok() {}
+        while queues.rx_regs.try_recv().is_ok() {}
+        for attempt in 0..=policy.retries {
+            // ACKs and NACKs of earlier tries are stale now
+            while self.rx_ack.try_recv().is_ok() {}
+            write_shared(&self.transport, &frame[..frame_len])?;
+            let deadline = Instant::now() + policy.timeout(attempt);
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    break;
+                }
+                match wait_reply(self.queues(), remaining.min(NACK_POLL_INTERVAL)) {
+                    Ok(reply) => return Ok(reply),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        return Err(SerialComError::Disconnected)
+                    }
+                }
+                let nacked = self
+                    .rx_ack
+                    .try_iter()
+                    .any(|(ack_command, _seq)| ack_command == CMD_NACK);
+                if nacked {
+                    break;
+                }
+            }
+        }
+        if self.link.reliable {
+            Err(SerialComError::RetriesExhausted)
+        } else {
+            Err(SerialComError::Timeout)
+        }
+    }
+    /// Write a register and wait for the device to acknowledge it
+    ///
+    /// An unanswered request is sent again as the app's RetryPolicy says. Fails with Timeout if
+    /// no try is answered, RetriesExhausted instead on a reliable link, and DeviceError if the
+    /// device rejects the write.
+    pub fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
+        self.write_reg_with(reg_num, reg_val, self.retry_policy)
+    }
+    /// Like write_reg, trying as policy says instead of the app's RetryPolicy
+    pub fn write_reg_with(
+        &mut self,
+        reg_num: u16,
+        reg_val: u32,
+        policy: RetryPolicy,
+    ) -> SerialComResult<()> {
+        let mut data: [u8; 6] = [0; 6];
+        let data_len = self.pack_write(reg_num, reg_val, &mut data)?;
+        self.request(2, &data[..data_len], policy, |queues, timeout| {
+            recv_matching(&queues.rx_reg_write, timeout, |reply| {
+                write_reply_reg_num(reply) == reg_num
+            })
+        })?
+        .map(|_| ())
+        .map_err(SerialComError::from)
+    }
+    /// Read a register
+    ///
+    /// Tried and failing like write_reg.
     pub fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
-        self.outbuf.host_read_reg(reg_num)?;
+        self.read_reg_with(reg_num, self.retry_policy)
+    }
+    /// Like read_reg, trying as policy says instead of the app's RetryPolicy
+    pub fn read_reg_with(&mut self, reg_num: u16, policy: RetryPolicy) -> SerialComResult<u32> {
+        let mut data: [u8; 2] = [0; 2];
+        packers::host_read_reg_pack(reg_num, &mut data)?;
+        self.request(1, &data, policy, |queues, timeout| {
+            recv_matching(&queues.rx_reg_read, timeout, |reply| {
+                read_reply_reg_num(reply) == reg_num
+            })
+        })?
+        .map(|(_, reg_val_rec)| reg_val_rec)
+        .map_err(SerialComError::from)
+    }
+    /// Read the count registers from start, in as few block reads as fit the link's frames
+    ///
+    /// Returns the values in register order. Only a device that said in a handshake that it has
+    /// block reads gets them; others, a broker say, are read with read_many instead. Tried and
+    /// failing like read_reg.
+    pub fn read_regs(&mut self, start: u16, count: u16) -> SerialComResult<Vec<u32>> {
+        let count = usize::from(count);
+        if usize::from(u16::MAX - start) + 1 < count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Register range goes past register 0xFFFF",
+            )
+            .into());
+        }
+        let block_reads = self
+            .device_info()
+            .is_some_and(|device_info| device_info.supports(CAP_BLOCK_READ));
+        if !block_reads {
+            let reg_nums: Vec<u16> = (start..=u16::MAX).take(count).collect();
+            return self.read_many(&reg_nums);
+        }
+        let block_len = self.block_read_len();
+        let policy = self.retry_policy;
+        let mut reg_vals: Vec<u32> = Vec::with_capacity(count);
+        while reg_vals.len() < count {
+            let block_start = start + u16::try_from(reg_vals.len())?;
+            let block_count = (count - reg_vals.len()).min(block_len);
+            let block_last = block_start + u16::try_from(block_count - 1)?;
+            let mut data: [u8; 3] = [0; 3];
+            packers::host_read_regs_pack(block_start, u8::try_from(block_count)?, &mut data)?;
+            let (_, block_vals) =
+                self.request(CMD_READ_REGS, &data, policy, |queues, timeout| {
+                    recv_matching(&queues.rx_regs, timeout, |reply| match reply {
+                        Ok((reply_start, _)) => *reply_start == block_start,
+                        Err(error_reply) => {
+                            (block_start..=block_last).contains(&error_reply.reg_num)
+                        }
+                    })
+                })??;
+            if block_vals.len() != block_count {
+                return Err(SerialComError::SliceTooSmall);
+            }
+            reg_vals.extend(block_vals);
+        }
+        Ok(reg_vals)
+    }
+    /// Most registers one block read reply to the selected device can carry
+    ///
+    /// The reply must fit both the HostReceiver's N byte frames and the device's buffers, and
+    /// carry at most MAX_READ_REGS_LEN bytes.
+    fn block_read_len(&self) -> usize {
+        let header_len = usize::from(self.address.is_some()) + usize::from(self.link.reliable);
+        let frame_data_len = frame_data_len(N, header_len, self.link.checksum);
+        // A device that hasn't said otherwise in a handshake is taken to be built for N too
+        let device_data_len = self.device_info().map_or(max_data_len(N), |device_info| {
+            usize::from(device_info.max_data_len)
+        });
+        let value_len = match self.register_bit_width() {
+            RegisterBitWidth::Eight => 1,
+            RegisterBitWidth::ThirtyTwo => 4,
+        };
+        // Less the first register number and count
+        let reply_len = frame_data_len.min(device_data_len).min(MAX_READ_REGS_LEN);
+        (reply_len.saturating_sub(3) / value_len).max(1)
+    }
+    /// Pack a register write for the register width
+    ///
+    /// returns the data length
+    fn pack_write(&self, reg_num: u16, reg_val: u32, data: &mut [u8]) -> SerialComResult<usize> {
+        let data_len = match self.register_bit_width() {
+            RegisterBitWidth::Eight => {
+                packers::host_write_reg8_pack(reg_num, u8::try_from(reg_val)?, data)?
+            }
+            RegisterBitWidth::ThirtyTwo => packers::host_write_reg32_pack(reg_num, reg_val, data)?,
+        };
+        Ok(usize::from(data_len))
+    }
+    /// Send a tagged request, returning its tag
+    fn start_tagged(
+        &mut self,
+        command: u8,
+        data: &mut [u8],
+        pending: PendingRequest,
+    ) -> SerialComResult<u8> {
+        if self.link.reliable {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Tagged requests can't be pipelined on a reliable link",
+            )
+            .into());
+        }
+        self.expire_tagged();
+        if self.pending.len() > usize::from(u8::MAX) {
+            return Err(SerialComError::QueueTooFull);
+        }
+        while self.pending.contains_key(&self.next_tag) {
+            self.next_tag = self.next_tag.wrapping_add(1);
+        }
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1);
+        data[0] = tag;
+        self.send(command, data)?;
+        self.pending.insert(tag, pending);
+        Ok(tag)
+    }
+    /// Send a tagged register read without waiting for the reply
+    ///
+    /// Returns the tag to pass to wait_tagged. Many tagged requests can be in flight at once, but
+    /// not on a reliable link.
+    pub fn start_read_reg(&mut self, reg_num: u16) -> SerialComResult<u8> {
+        let mut data: [u8; 3] = [0; 3];
+        packers::host_read_reg_pack(reg_num, &mut data[1..])?;
+        let pending = PendingRequest {
+            reg_num,
+            is_read: true,
+            sent_at: Instant::now(),
+        };
+        self.start_tagged(CMD_READ_REG_TAGGED, &mut data, pending)
+    }
+    /// Send a tagged register write without waiting for the reply
+    ///
+    /// Returns the tag to pass to wait_tagged.
+    pub fn start_write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<u8> {
+        let mut data: [u8; 7] = [0; 7];
+        let data_len = 1 + self.pack_write(reg_num, reg_val, &mut data[1..])?;
+        let pending = PendingRequest {
+            reg_num,
+            is_read: false,
+            sent_at: Instant::now(),
+        };
+        self.start_tagged(CMD_WRITE_REG_TAGGED, &mut data[..data_len], pending)
+    }
+    /// Wait for the reply to the tagged request with tag
+    ///
+    /// Replies to other pending requests that arrive meanwhile are kept for their own
+    /// wait_tagged, and other requests whose replies are overdue are forgotten. Tagged requests
+    /// aren't sent again; fails with Timeout if the reply doesn't come in time, and DeviceError if
+    /// the device rejected the request.
+    pub fn wait_tagged(&mut self, tag: u8) -> SerialComResult<TaggedReply> {
+        let deadline = match self.pending.get(&tag) {
+            Some(pending) => pending.sent_at + TAGGED_REPLY_TIMEOUT,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("No tagged request pending with tag {}", tag),
+                )
+                .into())
+            }
+        };
+        self.expire_tagged();
         loop {
-            match self
-                .hostreceiver
-                .rx_reg_read
-                .recv_timeout(Duration::from_millis(200))
-            {
-                Ok((reg_num_rec, reg_val_rec)) => {
-                    if reg_num_rec == reg_num {
-                        return Ok(reg_val_rec);
+            if let Some(reply) = self.completed.remove(&tag) {
+                self.pending.remove(&tag);
+                return reply.map_err(SerialComError::from);
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.queues().rx_tagged.recv_timeout(timeout) {
+                Ok((reply_tag, reply)) => {
+                    // Replies to requests that timed out, or that don't match, are dropped
+                    let matches = self
+                        .pending
+                        .get(&reply_tag)
+                        .is_some_and(|pending| pending.matches(&reply));
+                    if matches {
+                        self.completed.insert(reply_tag, reply);
                     }
                 }
                 Err(mpsc::RecvTimeoutError::Timeout) => {
-                    panic!("read_reg rx_reg_read timeout while waiting for response")
+                    self.pending.remove(&tag);
+                    return Err(SerialComError::Timeout);
                 }
                 Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    panic!("read_reg rx_reg_read disconnected while waiting for response")
+                    self.pending.remove(&tag);
+                    return Err(SerialComError::Disconnected);
+                }
+            }
+        }
+    }
+    /// Forget the pending requests still unanswered TAGGED_REPLY_TIMEOUT after they were sent
+    fn expire_tagged(&mut self) {
+        let now = Instant::now();
+        let completed = &self.completed;
+        self.pending.retain(|tag, pending| {
+            completed.contains_key(tag) || now < pending.sent_at + TAGGED_REPLY_TIMEOUT
+        });
+    }
+    /// Forget the pending requests with tags, and any replies to them, as nobody will wait for
+    /// them
+    fn cancel_tagged<I: IntoIterator<Item = u8>>(&mut self, tags: I) {
+        for tag in tags {
+            self.pending.remove(&tag);
+            self.completed.remove(&tag);
+        }
+    }
+    /// Read many registers, with up to 16 requests in flight at once
+    ///
+    /// Returns the values in the order of reg_nums. On a reliable link, or to a device that hasn't
+    /// said in a handshake that it has tagged requests, the registers are read one at a time. On
+    /// failure the requests still in flight are forgotten.
+    pub fn read_many(&mut self, reg_nums: &[u16]) -> SerialComResult<Vec<u32>> {
+        if !self.pipelines() {
+            return reg_nums
+                .iter()
+                .map(|reg_num| self.read_reg(*reg_num))
+                .collect();
+        }
+        let mut in_flight: VecDeque<(usize, u8)> = VecDeque::new();
+        let read = self.read_pipelined(reg_nums, &mut in_flight);
+        if read.is_err() {
+            self.cancel_tagged(in_flight.into_iter().map(|(_, tag)| tag));
+        }
+        read
+    }
+    /// Read reg_nums with up to 16 tagged reads in flight, kept in in_flight
+    fn read_pipelined(
+        &mut self,
+        reg_nums: &[u16],
+        in_flight: &mut VecDeque<(usize, u8)>,
+    ) -> SerialComResult<Vec<u32>> {
+        let mut reg_vals: Vec<u32> = vec![0; reg_nums.len()];
+        for (i_reg, reg_num) in reg_nums.iter().enumerate() {
+            if in_flight.len() == MAX_IN_FLIGHT {
+                self.finish_read(in_flight, &mut reg_vals)?;
+            }
+            in_flight.push_back((i_reg, self.start_read_reg(*reg_num)?));
+        }
+        while !in_flight.is_empty() {
+            self.finish_read(in_flight, &mut reg_vals)?;
+        }
+        Ok(reg_vals)
+    }
+    /// Wait for the oldest read in flight and put its value in reg_vals
+    fn finish_read(
+        &mut self,
+        in_flight: &mut VecDeque<(usize, u8)>,
+        reg_vals: &mut [u32],
+    ) -> SerialComResult<()> {
+        if let Some((i_reg, tag)) = in_flight.pop_front() {
+            if let TaggedReply::Read(_, reg_val) = self.wait_tagged(tag)? {
+                reg_vals[i_reg] = reg_val;
+            }
+        }
+        Ok(())
+    }
+    /// Write many registers, given as (register number, value), with up to 16 requests in
+    /// flight at once
+    ///
+    /// On a reliable link, or to a device that hasn't said in a handshake that it has tagged
+    /// requests, the registers are written one at a time. On failure the requests still in flight
+    /// are forgotten.
+    pub fn write_many(&mut self, writes: &[(u16, u32)]) -> SerialComResult<()> {
+        if !self.pipelines() {
+            return writes
+                .iter()
+                .try_for_each(|(reg_num, reg_val)| self.write_reg(*reg_num, *reg_val));
+        }
+        let mut in_flight: VecDeque<u8> = VecDeque::new();
+        let written = self.write_pipelined(writes, &mut in_flight);
+        if written.is_err() {
+            self.cancel_tagged(in_flight);
+        }
+        written
+    }
+    /// Write writes with up to 16 tagged writes in flight, kept in in_flight
+    fn write_pipelined(
+        &mut self,
+        writes: &[(u16, u32)],
+        in_flight: &mut VecDeque<u8>,
+    ) -> SerialComResult<()> {
+        for (reg_num, reg_val) in writes.iter() {
+            if in_flight.len() == MAX_IN_FLIGHT {
+                if let Some(tag) = in_flight.pop_front() {
+                    self.wait_tagged(tag)?;
+                }
+            }
+            in_flight.push_back(self.start_write_reg(*reg_num, *reg_val)?);
+        }
+        while let Some(tag) = in_flight.pop_front() {
+            self.wait_tagged(tag)?;
+        }
+        Ok(())
+    }
+    /// Send a message, as CMD_FRAGMENT messages if its data doesn't fit one frame
+    ///
+    /// Meant for messages with a command of 0x80 and up, such as calibration blobs, which the
+    /// device hands to RegisterFile::receive_message. Fragments are only sent to a device that
+    /// said in a handshake that it takes them. The device only replies if it rejects the message,
+    /// so this doesn't wait for a reply; on a reliable link each message is sent again until the
+    /// device ACKs it, as the RetryPolicy says.
+    pub fn send_long(&mut self, command: u8, data: &[u8]) -> SerialComResult<()> {
+        let header_len = usize::from(self.address.is_some()) + usize::from(self.link.reliable);
+        // A device that hasn't said otherwise in a handshake is taken to be built for N too
+        let device_data_len = self.device_info().map_or(max_data_len(N), |device_info| {
+            usize::from(device_info.max_data_len)
+        });
+        let chunk_len = frame_data_len(N, header_len, self.link.checksum).min(device_data_len);
+        if data.len() <= chunk_len {
+            return self.send_unanswered(command, data);
+        }
+        let takes_fragments = self
+            .device_info()
+            .is_some_and(|device_info| device_info.supports(CAP_FRAGMENTS));
+        if !takes_fragments {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The device hasn't said in a handshake that it takes fragments",
+            )
+            .into());
+        }
+        let mut fragmenter = Fragmenter::new(self.fragment_id, command, data, chunk_len)?;
+        self.fragment_id = self.fragment_id.wrapping_add(1);
+        let mut fragment: Vec<u8> = vec![0; chunk_len];
+        while let Some(fragment_len) = fragmenter.next_into(&mut fragment)? {
+            self.send_unanswered(CMD_FRAGMENT, &fragment[..fragment_len])?;
+        }
+        Ok(())
+    }
+}
+
+/// Write frame to a transport shared between threads, all of it under one lock
+pub(crate) fn write_shared(
+    transport: &Mutex<Box<dyn Transport>>,
+    frame: &[u8],
+) -> SerialComResult<()> {
+    let mut transport = transport
+        .lock()
+        .map_err(|_| io::Error::other("Transport lock poisoned"))?;
+    transport.write(frame)?;
+    transport.flush()
+}
+
+/// Register number of a write reply, or of the write an error reply rejects
+fn write_reply_reg_num(reply: &Result<u16, ErrorReply>) -> u16 {
+    match *reply {
+        Ok(reg_num) => reg_num,
+        Err(error_reply) => error_reply.reg_num,
+    }
+}
+
+/// Register number of a read reply, or of the read an error reply rejects
+fn read_reply_reg_num(reply: &Result<(u16, u32), ErrorReply>) -> u16 {
+    match *reply {
+        Ok((reg_num, _)) => reg_num,
+        Err(error_reply) => error_reply.reg_num,
+    }
+}
+
+/// Receive from rx until matches accepts a value, for at most timeout
+fn recv_matching<T, M>(
+    rx: &mpsc::Receiver<T>,
+    timeout: Duration,
+    matches: M,
+) -> Result<T, mpsc::RecvTimeoutError>
+where
+    M: Fn(&T) -> bool,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let value = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))?;
+        if matches(&value) {
+            return Ok(value);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::binarycom::device::{DeviceResponder, RegisterFile};
+#[cfg(test)]
+use crate::binarycom::errorreply::DeviceErrorCode;
+#[cfg(test)]
+use crate::crc::Checksum;
+#[cfg(test)]
+use crate::framer::{CobsFramer, Framing};
+#[cfg(test)]
+use crate::sim::RegisterMap;
+#[cfg(test)]
+use crate::transport::MemoryTransport;
+#[cfg(test)]
+use std::sync::atomic::Ordering;
+
+/// Reads as reg_num + 0x1000 and ignores writes
+#[cfg(test)]
+pub(crate) struct FakeRegisters;
+
+#[cfg(test)]
+impl RegisterFile for FakeRegisters {
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        RegisterBitWidth::ThirtyTwo
+    }
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
+        Ok(u32::from(reg_num) + 0x1000)
+    }
+    fn write_reg(&mut self, _reg_num: u16, _reg_val: u32) -> SerialComResult<()> {
+        Ok(())
+    }
+}
+
+/// Answers the requests read from transport with responder, until reading fails
+///
+/// Returns responder, to go on answering on another link.
+#[cfg(test)]
+pub(crate) fn fake_device<T, R, F, const N: usize>(
+    mut transport: T,
+    mut responder: DeviceResponder<R, F, N>,
+) -> DeviceResponder<R, F, N>
+where
+    T: Transport,
+    R: RegisterFile,
+    F: Framer,
+{
+    let mut readbuf: [u8; 16] = [0; 16];
+    while let Ok(n_read) = transport.read(&mut readbuf) {
+        responder
+            .process(&readbuf[..n_read], |bytes| transport.write(bytes))
+            .expect("Device couldn't reply");
+    }
+    responder
+}
+
+#[test]
+fn test_app_read_write_reg() {
+    let (host, dev) = MemoryTransport::pair();
+    thread::spawn(move || fake_device(dev, DeviceResponder::new(FakeRegisters)));
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    for reg_num in [0u16, 5, 0x1234, 0xFFFF].iter() {
+        let reg_val = app.read_reg(*reg_num).expect("Couldn't read reg");
+        assert_eq!(reg_val, u32::from(*reg_num) + 0x1000);
+        app.write_reg(*reg_num, 0xDEADBEEF)
+            .expect("Couldn't write reg");
+    }
+}
+
+/// Drops every fourth write and corrupts the one two after it
+#[cfg(test)]
+struct LossyTransport {
+    inner: Box<dyn Transport>,
+    n_writes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+impl LossyTransport {
+    fn new(inner: Box<dyn Transport>) -> LossyTransport {
+        LossyTransport {
+            inner,
+            n_writes: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Transport for LossyTransport {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        self.inner.read(buf)
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let i_write = self
+            .n_writes
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        match i_write % 4 {
+            1 => Ok(()),
+            3 => {
+                let mut corrupted = buf.to_vec();
+                corrupted[buf.len() / 2] ^= 0x01;
+                self.inner.write(&corrupted)
+            }
+            _ => self.inner.write(buf),
+        }
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        self.inner.flush()
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(LossyTransport {
+            inner: self.inner.try_clone()?,
+            n_writes: std::sync::Arc::clone(&self.n_writes),
+        }))
+    }
+}
+
+/// Drops every write while muted
+#[cfg(test)]
+pub(crate) struct MutableTransport {
+    inner: Box<dyn Transport>,
+    muted: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(test)]
+impl MutableTransport {
+    pub(crate) fn new(
+        inner: Box<dyn Transport>,
+        muted: Arc<std::sync::atomic::AtomicBool>,
+    ) -> MutableTransport {
+        MutableTransport { inner, muted }
+    }
+}
+
+#[cfg(test)]
+impl Transport for MutableTransport {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        self.inner.read(buf)
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        if self.muted.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        self.inner.flush()
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(MutableTransport {
+            inner: self.inner.try_clone()?,
+            muted: Arc::clone(&self.muted),
+        }))
+    }
+}
+
+/// Fails every read once closed is set, like a link that was hung up
+#[cfg(test)]
+struct ClosableTransport {
+    inner: Box<dyn Transport>,
+    closed: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(test)]
+impl Transport for ClosableTransport {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe).into());
+        }
+        self.inner.read(buf)
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        self.inner.flush()
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(ClosableTransport {
+            inner: self.inner.try_clone()?,
+            closed: Arc::clone(&self.closed),
+        }))
+    }
+}
+
+/// Waits delay before each write, like a device slow to reply
+#[cfg(test)]
+struct SlowTransport {
+    inner: Box<dyn Transport>,
+    delay: Duration,
+}
+
+#[cfg(test)]
+impl Transport for SlowTransport {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        self.inner.read(buf)
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        thread::sleep(self.delay);
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        self.inner.flush()
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(SlowTransport {
+            inner: self.inner.try_clone()?,
+            delay: self.delay,
+        }))
+    }
+}
+
+/// Logs every write it gets
+#[cfg(test)]
+struct LoggedRegisters {
+    writes: std::sync::Arc<std::sync::Mutex<Vec<(u16, u32)>>>,
+}
+
+#[cfg(test)]
+impl RegisterFile for LoggedRegisters {
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        RegisterBitWidth::ThirtyTwo
+    }
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
+        Ok(u32::from(reg_num))
+    }
+    fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
+        self.writes.lock().unwrap().push((reg_num, reg_val));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_app_reliable_lossy_link() {
+    let (host, dev) = MemoryTransport::pair();
+    let writes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let registers = LoggedRegisters {
+        writes: std::sync::Arc::clone(&writes),
+    };
+    let mut responder = DeviceResponder::new(registers);
+    responder.set_reliable(true);
+    thread::spawn(move || fake_device(LossyTransport::new(Box::new(dev)), responder));
+    let link = LinkConfig {
+        reliable: true,
+        ..LinkConfig::default()
+    };
+    let mut app = BinaryComApp16::with_link(
+        RegisterBitWidth::ThirtyTwo,
+        Box::new(LossyTransport::new(Box::new(host))),
+        link,
+        |_command, _data| {},
+    )
+    .expect("Couldn't make app");
+    app.set_retry_policy(RetryPolicy {
+        retries: 20,
+        timeout: Duration::from_millis(10),
+        backoff: 1,
+    });
+    // Pings in between the requests and their retransmissions
+    let _supervisor = app
+        .supervise(SupervisorConfig {
+            interval: Duration::from_millis(3),
+            timeout: Duration::from_millis(2),
+            ..SupervisorConfig::default()
+        })
+        .expect("Couldn't supervise");
+    let mut expected: Vec<(u16, u32)> = Vec::new();
+    for i in 0..30u32 {
+        let reg_num = (i % 4) as u16;
+        app.write_reg(reg_num, i).expect("Couldn't write reg");
+        expected.push((reg_num, i));
+        assert_eq!(
+            app.read_reg(reg_num).expect("Couldn't read reg"),
+            u32::from(reg_num)
+        );
+    }
+    // Every write carried out exactly once, in order
+    assert_eq!(*writes.lock().unwrap(), expected);
+}
+
+#[test]
+fn test_app_reliable_reconnect() {
+    let (host1, dev1) = MemoryTransport::pair();
+    let (host2, dev2) = MemoryTransport::pair();
+    let closed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let dev1 = ClosableTransport {
+        inner: Box::new(dev1),
+        closed: Arc::clone(&closed),
+    };
+    let writes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut responder = DeviceResponder::new(LoggedRegisters {
+        writes: Arc::clone(&writes),
+    });
+    responder.set_reliable(true);
+    // The device outlives the first host and is then answering a second one
+    thread::spawn(move || fake_device(dev2, fake_device(dev1, responder)));
+    let link = LinkConfig {
+        reliable: true,
+        ..LinkConfig::default()
+    };
+    let mut app = BinaryComApp16::with_link(
+        RegisterBitWidth::ThirtyTwo,
+        Box::new(host1),
+        link,
+        |_command, _data| {},
+    )
+    .expect("Couldn't make app");
+    for reg_num in 0..5u16 {
+        app.write_reg(reg_num, 1).expect("Couldn't write reg");
+    }
+    drop(app);
+    closed.store(true, Ordering::SeqCst);
+    // A new host starts over from seq 0, which the device has already seen
+    let mut app = BinaryComApp16::with_link(
+        RegisterBitWidth::ThirtyTwo,
+        Box::new(host2),
+        link,
+        |_command, _data| {},
+    )
+    .expect("Couldn't make app");
+    for reg_num in 0..5u16 {
+        app.write_reg(reg_num, 2).expect("Couldn't write reg");
+    }
+    let expected: Vec<(u16, u32)> = (0..5u16)
+        .map(|reg_num| (reg_num, 1))
+        .chain((0..5u16).map(|reg_num| (reg_num, 2)))
+        .collect();
+    assert_eq!(*writes.lock().unwrap(), expected);
+}
+
+#[test]
+fn test_app_retries_lossy_link() {
+    let (host, dev) = MemoryTransport::pair();
+    thread::spawn(move || {
+        fake_device(
+            LossyTransport::new(Box::new(dev)),
+            DeviceResponder::new(FakeRegisters),
+        )
+    });
+    let mut app = BinaryComApp16::new(
+        RegisterBitWidth::ThirtyTwo,
+        Box::new(LossyTransport::new(Box::new(host))),
+    )
+    .expect("Couldn't make app");
+    app.set_retry_policy(RetryPolicy {
+        retries: 20,
+        timeout: Duration::from_millis(10),
+        backoff: 1,
+    });
+    for reg_num in 0..30u16 {
+        assert_eq!(
+            app.read_reg(reg_num).expect("Couldn't read reg"),
+            u32::from(reg_num) + 0x1000
+        );
+        app.write_reg(reg_num, 1).expect("Couldn't write reg");
+    }
+}
+
+#[test]
+fn test_app_timeout() {
+    let (host, mut dev) = MemoryTransport::pair();
+    // Counts the requests and never answers
+    let (tx_request, rx_request) = mpsc::channel();
+    thread::spawn(move || {
+        let mut decoder = crate::cobs::COBSDecoder::new();
+        let mut readbuf: [u8; 16] = [0; 16];
+        while let Ok(n_read) = dev.read(&mut readbuf) {
+            decoder.push_slice(&readbuf[..n_read], |_frame| tx_request.send(()).unwrap());
+        }
+    });
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    let policy = RetryPolicy {
+        retries: 2,
+        timeout: Duration::from_millis(5),
+        backoff: 2,
+    };
+    match app.read_reg_with(3, policy) {
+        Err(SerialComError::Timeout) => {}
+        _ => panic!("Expected Timeout"),
+    }
+    match app.write_reg_with(3, 0, policy) {
+        Err(SerialComError::Timeout) => {}
+        _ => panic!("Expected Timeout"),
+    }
+    assert_eq!(rx_request.try_iter().count(), 6);
+    // Tagged requests nobody waits for are forgotten once overdue
+    let tag = app.start_read_reg(1).expect("Couldn't start read");
+    thread::sleep(TAGGED_REPLY_TIMEOUT);
+    app.start_read_reg(2).expect("Couldn't start read");
+    assert!(!app.pending.contains_key(&tag));
+    assert_eq!(app.pending.len(), 1);
+}
+
+#[test]
+fn test_app_slow_device() {
+    let (host, dev) = MemoryTransport::pair();
+    let dev = SlowTransport {
+        inner: Box::new(dev),
+        delay: Duration::from_millis(15),
+    };
+    let responder = DeviceResponder::new(RegisterMap::new(RegisterBitWidth::ThirtyTwo));
+    thread::spawn(move || fake_device(dev, responder));
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    let impatient = RetryPolicy {
+        retries: 0,
+        timeout: Duration::from_millis(10),
+        backoff: 1,
+    };
+    match app.read_reg_with(3, impatient) {
+        Err(SerialComError::Timeout) => {}
+        _ => panic!("Expected Timeout"),
+    }
+    // The reply to the read that timed out comes in
+    thread::sleep(Duration::from_millis(30));
+    app.write_reg(3, 42).expect("Couldn't write reg");
+    assert_eq!(app.read_reg(3).expect("Couldn't read reg"), 42);
+}
+
+#[test]
+fn test_app_read_write_many() {
+    let (host, dev) = MemoryTransport::pair();
+    thread::spawn(move || fake_device(dev, DeviceResponder::new(FakeRegisters)));
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    app.handshake().expect("Couldn't handshake");
+    let reg_nums: Vec<u16> = (0..200).collect();
+    let reg_vals = app.read_many(&reg_nums).expect("Couldn't read registers");
+    let expected: Vec<u32> = reg_nums.iter().map(|n| u32::from(*n) + 0x1000).collect();
+    assert_eq!(reg_vals, expected);
+    let writes: Vec<(u16, u32)> = reg_nums.iter().map(|n| (*n, 7)).collect();
+    app.write_many(&writes).expect("Couldn't write registers");
+    assert!(app.pending.is_empty());
+}
+
+#[test]
+fn test_app_read_regs() {
+    let (host, dev) = MemoryTransport::pair();
+    thread::spawn(move || fake_device(dev, DeviceResponder::new(FakeRegisters)));
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    app.handshake().expect("Couldn't handshake");
+    let expected = |start: u16, count: u16| -> Vec<u32> {
+        (0..count).map(|i| u32::from(start + i) + 0x1000).collect()
+    };
+    // Takes several block reads
+    assert_eq!(app.read_regs(10, 9).unwrap(), expected(10, 9));
+    assert_eq!(app.read_regs(3, 1).unwrap(), expected(3, 1));
+    assert_eq!(app.read_regs(0xFFFB, 5).unwrap(), expected(0xFFFB, 5));
+    assert!(app.read_regs(7, 0).unwrap().is_empty());
+    app.read_regs(0xFFFB, 6)
+        .expect_err("Should be error, past register 0xFFFF");
+    assert!(app.pending.is_empty());
+}
+
+#[test]
+fn test_app_read_regs_long_messages() {
+    let (host, dev) = MemoryTransport::pair();
+    let responder: DeviceResponder<_, CobsFramer, 64> =
+        DeviceResponder::with_framer(FakeRegisters, CobsFramer::default(), Checksum::Crc16Dnp);
+    thread::spawn(move || fake_device(dev, responder));
+    let mut app = BinaryComApp::<64>::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    let device_info = app.handshake().expect("Couldn't handshake");
+    assert_eq!(usize::from(device_info.max_data_len), max_data_len(64));
+    let reg_vals = app.read_regs(0, 28).unwrap();
+    assert_eq!(reg_vals, (0..28).map(|i| i + 0x1000).collect::<Vec<u32>>());
+    // 14 values fit each reply, not 2 as with 16 byte messages, after the hello reply
+    assert_eq!(app.link_stats().frames_ok(), 1 + 2);
+}
+
+#[test]
+fn test_app_read_regs_large_messages() {
+    let (host, dev) = MemoryTransport::pair();
+    let mut registers = RegisterMap::new(RegisterBitWidth::Eight);
+    for reg_num in 0..300u16 {
+        registers
+            .write_reg(reg_num, u32::from(reg_num as u8))
+            .unwrap();
+    }
+    let responder: DeviceResponder<_, CobsFramer, 1024> =
+        DeviceResponder::with_framer(registers, CobsFramer::default(), Checksum::Crc16Dnp);
+    thread::spawn(move || fake_device(dev, responder));
+    let mut app = BinaryComApp::<1024>::new(RegisterBitWidth::Eight, Box::new(host))
+        .expect("Couldn't make app");
+    app.handshake().expect("Couldn't handshake");
+    let reg_vals = app.read_regs(0, 300).unwrap();
+    assert_eq!(
+        reg_vals,
+        (0..300u32).map(|i| i & 0xFF).collect::<Vec<u32>>()
+    );
+    // Replies carry at most MAX_READ_REGS_LEN bytes however long the messages, so 252 values
+    assert_eq!(app.link_stats().frames_ok(), 1 + 2);
+}
+
+#[test]
+fn test_app_read_regs_without_block_reads() {
+    let (host, dev) = MemoryTransport::pair();
+    let mut responder = DeviceResponder::new(FakeRegisters);
+    responder.set_capabilities(CAP_TAGGED);
+    thread::spawn(move || fake_device(dev, responder));
+    let mut app = BinaryComApp16::connect(Box::new(host), LinkConfig::default(), |_, _| {})
+        .expect("Couldn't connect");
+    assert!(!app.device_info().unwrap().supports(CAP_BLOCK_READ));
+    assert_eq!(app.read_regs(4, 3).unwrap(), [0x1004, 0x1005, 0x1006]);
+}
+
+#[test]
+fn test_app_tagged_out_of_order() {
+    let (host, mut dev) = MemoryTransport::pair();
+    // Sends replies in batches of 4, last first
+    thread::spawn(move || {
+        let mut responder = DeviceResponder::new(FakeRegisters);
+        let mut replies: Vec<Vec<u8>> = Vec::new();
+        let mut readbuf: [u8; 16] = [0; 16];
+        while let Ok(n_read) = dev.read(&mut readbuf) {
+            for byte in readbuf[..n_read].iter() {
+                if let Ok(true) = responder.receive_byte(*byte) {
+                    let (slice1, slice2) = responder.reply();
+                    replies.push([slice1, slice2].concat());
+                }
+            }
+            if replies.len() == 4 {
+                for reply in replies.drain(..).rev() {
+                    dev.write(&reply).expect("Device couldn't reply");
                 }
             }
         }
+    });
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    let read_tags: Vec<u8> = [10u16, 11, 12]
+        .iter()
+        .map(|reg_num| app.start_read_reg(*reg_num).expect("Couldn't start read"))
+        .collect();
+    let write_tag = app.start_write_reg(13, 5).expect("Couldn't start write");
+    for (tag, reg_num) in read_tags.iter().zip([10u16, 11, 12].iter()) {
+        assert_eq!(
+            app.wait_tagged(*tag).expect("No reply"),
+            TaggedReply::Read(*reg_num, u32::from(*reg_num) + 0x1000)
+        );
+    }
+    assert_eq!(
+        app.wait_tagged(write_tag).expect("No reply"),
+        TaggedReply::Write(13)
+    );
+    app.wait_tagged(write_tag)
+        .expect_err("Should be error, already waited for");
+}
+
+#[test]
+fn test_app_tagged_unanswered() {
+    // Nothing answers on dev
+    let (host, _dev) = MemoryTransport::pair();
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    // Tagged requests nobody waits for are forgotten once overdue
+    app.start_read_reg(1).expect("Couldn't start read");
+    thread::sleep(TAGGED_REPLY_TIMEOUT);
+    let tag = app.start_read_reg(2).expect("Couldn't start read");
+    assert_eq!(app.pending.keys().collect::<Vec<&u8>>(), [&tag]);
+    app.wait_tagged(tag)
+        .expect_err("Should be error, nothing answers");
+    // As if the device had said in a handshake that it has tagged requests
+    let device_info = DeviceResponder::new(FakeRegisters).device_info();
+    app.device_infos.insert(None, device_info);
+    // The reads in flight after the one that timed out aren't left pending
+    app.read_many(&[3, 4, 5])
+        .expect_err("Should be error, nothing answers");
+    assert!(app.pending.is_empty() && app.completed.is_empty());
+    app.write_many(&[(3, 1), (4, 2)])
+        .expect_err("Should be error, nothing answers");
+    assert!(app.pending.is_empty() && app.completed.is_empty());
+}
+
+/// Messages the host sent, as (command, data)
+#[cfg(test)]
+type Messages = std::sync::Arc<std::sync::Mutex<Vec<(u8, Vec<u8>)>>>;
+
+/// Keeps every message the host sends
+#[cfg(test)]
+struct BlobRegisters {
+    messages: Messages,
+}
+
+#[cfg(test)]
+impl RegisterFile for BlobRegisters {
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        RegisterBitWidth::ThirtyTwo
+    }
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
+        Ok(u32::from(reg_num))
+    }
+    fn write_reg(&mut self, _reg_num: u16, _reg_val: u32) -> SerialComResult<()> {
+        Ok(())
+    }
+    fn receive_message(&mut self, command: u8, data: &[u8]) -> SerialComResult<()> {
+        self.messages.lock().unwrap().push((command, data.to_vec()));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_app_long_messages() {
+    let block: Vec<u8> = (0..100).collect();
+    for reliable in [false, true].iter() {
+        let (host, mut dev) = MemoryTransport::pair();
+        // 8 bytes of data per frame, so 4 of a message per fragment
+        let link = LinkConfig {
+            framing: Framing::Slip,
+            checksum: Checksum::Crc32,
+            reliable: *reliable,
+        };
+        let messages = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let registers = BlobRegisters {
+            messages: std::sync::Arc::clone(&messages),
+        };
+        let mut responder: DeviceResponder<_, _> =
+            DeviceResponder::with_framer(registers, link.framing.new_framer::<16>(), link.checksum);
+        responder.set_reliable(*reliable);
+        let capabilities = responder.device_info().capabilities;
+        responder.set_capabilities(capabilities | CAP_FRAGMENTS);
+        let device_block = block.clone();
+        thread::spawn(move || {
+            responder
+                .stream_long(0x8A, &device_block, |bytes| dev.write(bytes))
+                .expect("Device couldn't stream");
+            fake_device(dev, responder)
+        });
+        let (tx, rx) = mpsc::channel();
+        let mut app = BinaryComApp16::connect(Box::new(host), link, move |command, data| {
+            let _ = tx.send((command, data));
+        })
+        .expect("Couldn't connect");
+        let samples: Vec<u32> = block.iter().map(|byte| u32::from(*byte)).collect();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            (0x8A, samples)
+        );
+        app.send_long(0x90, &block).expect("Couldn't send");
+        app.send_long(0x91, &block[..3]).expect("Couldn't send");
+        // Answered after the messages before it are taken
+        assert_eq!(app.read_reg(5).expect("Couldn't read reg"), 5);
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [(0x90, block.clone()), (0x91, block[..3].to_vec())]
+        );
+    }
+}
+
+#[test]
+fn test_app_long_messages_unsupported() {
+    let (host, dev) = MemoryTransport::pair();
+    // FakeRegisters takes no messages, so the device doesn't report CAP_FRAGMENTS
+    thread::spawn(move || fake_device(dev, DeviceResponder::new(FakeRegisters)));
+    let mut app = BinaryComApp16::connect(Box::new(host), LinkConfig::default(), |_, _| {})
+        .expect("Couldn't connect");
+    assert!(!app.device_info().unwrap().supports(CAP_FRAGMENTS));
+    let block: Vec<u8> = (0..100).collect();
+    match app.send_long(0x90, &block) {
+        Err(SerialComError::Io(error)) => {
+            assert_eq!(error.kind(), io::ErrorKind::Unsupported)
+        }
+        _ => panic!("Expected Unsupported"),
+    }
+}
+
+#[test]
+fn test_app_bus() {
+    let (host, mut dev) = MemoryTransport::pair();
+    // Three devices on one bus, each with its address in register 0
+    thread::spawn(move || {
+        let mut responders: Vec<DeviceResponder<RegisterMap>> = (1..=3u8)
+            .map(|address| {
+                let mut registers = RegisterMap::new(RegisterBitWidth::ThirtyTwo);
+                registers.write_reg(0, u32::from(address)).unwrap();
+                let mut responder = DeviceResponder::new(registers);
+                responder.set_address(Some(address));
+                responder
+            })
+            .collect();
+        responders[1].stream(0x8A, &[7, 8]).unwrap();
+        let (slice1, slice2) = responders[1].reply();
+        dev.write(&[slice1, slice2].concat()).unwrap();
+        let mut readbuf: [u8; 16] = [0; 16];
+        while let Ok(n_read) = dev.read(&mut readbuf) {
+            for responder in responders.iter_mut() {
+                responder
+                    .process(&readbuf[..n_read], |bytes| dev.write(bytes))
+                    .expect("Device couldn't reply");
+            }
+        }
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut app = BinaryComApp16::with_bus(
+        RegisterBitWidth::ThirtyTwo,
+        Box::new(host),
+        LinkConfig::default(),
+        &[1, 2, 3],
+        move |address, command, data| {
+            let _ = tx.send((address, command, data));
+        },
+    )
+    .expect("Couldn't make app");
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+        (2, 0x8A, vec![7, 8])
+    );
+    for address in 1..=3u8 {
+        app.select_device(address).unwrap();
+        assert_eq!(app.read_reg(0).unwrap(), u32::from(address));
+        app.write_reg(1, u32::from(address) * 10).unwrap();
+    }
+    app.broadcast_write_reg(2, 55).unwrap();
+    for address in 1..=3u8 {
+        app.select_device(address).unwrap();
+        assert_eq!(app.read_reg(1).unwrap(), u32::from(address) * 10);
+        assert_eq!(app.read_reg(2).unwrap(), 55);
+    }
+    app.select_device(4)
+        .expect_err("Should be error, no device at 4");
+}
+
+#[test]
+fn test_app_connect() {
+    let (host, dev) = MemoryTransport::pair();
+    let mut registers = RegisterMap::new(RegisterBitWidth::Eight);
+    registers.write_reg(1, 0x42).unwrap();
+    thread::spawn(move || {
+        crate::sim::run_simulator(Box::new(dev), LinkConfig::default(), registers, None)
+    });
+    let mut app = BinaryComApp16::connect(Box::new(host), LinkConfig::default(), |_, _| {})
+        .expect("Couldn't connect");
+    let device_info = *app.device_info().expect("No device info");
+    assert_eq!(device_info.register_bit_width, RegisterBitWidth::Eight);
+    assert_eq!(device_info.max_data_len, 11);
+    assert!(device_info.supports(CAP_TAGGED | CAP_BLOCK_READ));
+    assert_eq!(app.read_many(&[1, 2]).unwrap(), [0x42, 0]);
+    assert_eq!(app.read_regs(0, 12).unwrap()[..3], [0, 0x42, 0]);
+    app.write_reg(2, 0x1FF)
+        .expect_err("Should be error, 8 bit registers");
+}
+
+#[test]
+fn test_app_connect_incompatible() {
+    let (host, mut dev) = MemoryTransport::pair();
+    // Answers anything with a hello reply from a later major version
+    thread::spawn(move || {
+        let mut decoder = crate::cobs::COBSDecoder::new();
+        let mut readbuf: [u8; 16] = [0; 16];
+        let mut device_info = DeviceResponder::new(FakeRegisters).device_info();
+        device_info.version_major = PROTOCOL_VERSION_MAJOR + 1;
+        let mut data: [u8; 7] = [0; 7];
+        device_info.pack(&mut data).unwrap();
+        let mut reply: [u8; 16] = [0; 16];
+        let reply_len = crate::binarycom::send_message_into(CMD_HELLO, &data, &mut reply).unwrap();
+        while let Ok(n_read) = dev.read(&mut readbuf) {
+            let mut n_frames = 0;
+            decoder.push_slice(&readbuf[..n_read], |_frame| n_frames += 1);
+            for _ in 0..n_frames {
+                dev.write(&reply[..reply_len]).unwrap();
+            }
+        }
+    });
+    match BinaryComApp16::connect(Box::new(host), LinkConfig::default(), |_, _| {}) {
+        Err(SerialComError::IncompatibleVersion(major, minor)) => {
+            assert_eq!(major, PROTOCOL_VERSION_MAJOR + 1);
+            assert_eq!(minor, PROTOCOL_VERSION_MINOR);
+        }
+        _ => panic!("Expected IncompatibleVersion"),
+    }
+}
+
+/// Registers 0 to 3, of which 0 is read-only, holding values up to 100
+#[cfg(test)]
+struct StrictRegisters {
+    values: [u32; 4],
+}
+
+#[cfg(test)]
+impl RegisterFile for StrictRegisters {
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        RegisterBitWidth::ThirtyTwo
+    }
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
+        self.values
+            .get(usize::from(reg_num))
+            .copied()
+            .ok_or(SerialComError::DeviceError {
+                code: DeviceErrorCode::UnknownRegister,
+                reg_num,
+            })
+    }
+    fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
+        let code = match reg_num {
+            0 => DeviceErrorCode::ReadOnly,
+            1..=3 if reg_val > 100 => DeviceErrorCode::OutOfRange,
+            1..=3 => {
+                self.values[usize::from(reg_num)] = reg_val;
+                return Ok(());
+            }
+            _ => DeviceErrorCode::UnknownRegister,
+        };
+        Err(SerialComError::DeviceError { code, reg_num })
+    }
+}
+
+#[test]
+fn test_app_device_errors() {
+    for reliable in [false, true].iter() {
+        let (host, dev) = MemoryTransport::pair();
+        let reliable = *reliable;
+        let mut responder = DeviceResponder::new(StrictRegisters { values: [0; 4] });
+        responder.set_reliable(reliable);
+        thread::spawn(move || fake_device(dev, responder));
+        let link = LinkConfig {
+            reliable,
+            ..LinkConfig::default()
+        };
+        let mut app =
+            BinaryComApp16::connect(Box::new(host), link, |_, _| {}).expect("Couldn't make app");
+        let expect_error =
+            |result: SerialComResult<()>, expected: DeviceErrorCode, reg: u16| match result {
+                Err(SerialComError::DeviceError { code, reg_num }) => {
+                    assert_eq!((code, reg_num), (expected, reg))
+                }
+                _ => panic!("Expected DeviceError"),
+            };
+        expect_error(app.write_reg(0, 1), DeviceErrorCode::ReadOnly, 0);
+        expect_error(app.write_reg(2, 101), DeviceErrorCode::OutOfRange, 2);
+        expect_error(
+            app.read_reg(9).map(|_| ()),
+            DeviceErrorCode::UnknownRegister,
+            9,
+        );
+        app.write_reg(2, 100).expect("Couldn't write reg");
+        assert_eq!(app.read_reg(2).expect("Couldn't read reg"), 100);
+        assert_eq!(app.read_regs(1, 2).expect("Couldn't read regs"), [0, 100]);
+        expect_error(
+            app.read_regs(3, 2).map(|_| ()),
+            DeviceErrorCode::UnknownRegister,
+            4,
+        );
+        if !reliable {
+            let tag = app.start_write_reg(0, 1).expect("Couldn't start write");
+            expect_error(
+                app.wait_tagged(tag).map(|_| ()),
+                DeviceErrorCode::ReadOnly,
+                0,
+            );
+            expect_error(
+                app.read_many(&[1, 7, 2]).map(|_| ()),
+                DeviceErrorCode::UnknownRegister,
+                7,
+            );
+            // The reads in flight after the failed one aren't left pending
+            assert!(app.pending.is_empty() && app.completed.is_empty());
+            let writes: Vec<(u16, u32)> = (1..=3).map(|reg_num| (reg_num, 101)).collect();
+            expect_error(app.write_many(&writes), DeviceErrorCode::OutOfRange, 1);
+            assert!(app.pending.is_empty() && app.completed.is_empty());
+        }
     }
 }
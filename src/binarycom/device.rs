@@ -0,0 +1,1105 @@
+use crate::binarycom::errorreply::{DeviceErrorCode, ErrorReply, CMD_ERROR, ERROR_REPLY_LEN};
+use crate::binarycom::fragment::{Fragmenter, Reassembler, CMD_FRAGMENT};
+use crate::binarycom::hello::{
+    DeviceInfo, CAP_BLOCK_READ, CAP_TAGGED, CMD_HELLO, PROTOCOL_VERSION_MAJOR,
+    PROTOCOL_VERSION_MINOR,
+};
+use crate::binarycom::packers;
+use crate::binarycom::reliable::{Deduplicator, CMD_ACK, CMD_NACK, CMD_SYNC};
+use crate::binarycom::{
+    frame_data_len, max_data_len, send_message_headed, unpack_frame_headed, RegisterBitWidth,
+    BROADCAST_ADDRESS, CMD_PING, CMD_READ_REGS, CMD_READ_REG_TAGGED, CMD_WRITE_REG_TAGGED,
+    MAX_READ_REGS_LEN,
+};
+use crate::crc::Checksum;
+use crate::error::{SerialComError, SerialComResult};
+use crate::framer::{CobsFramer, Framer};
+
+use core::convert::TryFrom;
+use core::time::Duration;
+
+/// Registers of a device, read and written by the host through a DeviceResponder
+///
+/// Implemented by firmware or simulators.
+pub trait RegisterFile {
+    /// Width of the register values exchanged with the host
+    fn register_bit_width(&self) -> RegisterBitWidth;
+    /// Return the value of register reg_num
+    ///
+    /// A SerialComError::DeviceError is sent to the host as an error reply.
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32>;
+    /// Set register reg_num to reg_val
+    ///
+    /// A SerialComError::DeviceError is sent to the host as an error reply, e.g. with
+    /// DeviceErrorCode::ReadOnly.
+    fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()>;
+    /// Take a message from the host with a command of 0x80 and up, e.g. a calibration blob, sent
+    /// whole or in fragments
+    ///
+    /// A SerialComError::DeviceError is sent to the host as an error reply. The default rejects
+    /// every message with DeviceErrorCode::UnsupportedCommand.
+    fn receive_message(&mut self, _command: u8, _data: &[u8]) -> SerialComResult<()> {
+        Err(SerialComError::DeviceError {
+            code: DeviceErrorCode::UnsupportedCommand,
+            reg_num: 0,
+        })
+    }
+}
+
+/// Longest message the device reassembles from fragments
+const MAX_REASSEMBLED_LEN: usize = 256;
+
+/// How long after its first fragment a fragmented message must be complete
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many encoded messages of up to N bytes outbuf holds: an ACK, a CMD_SYNC and a reply, with
+/// room for byte stuffing and CRC-32 trailers
+const OUTBUF_MESSAGES: usize = 5;
+
+/// The last reply on a reliable link, kept to answer a retransmitted request
+#[derive(Clone, Copy)]
+struct SentReply<const N: usize> {
+    request_seq: u8,
+    /// Seq of the CMD_SYNC sent just before the reply, if it was the first of a session
+    sync_seq: Option<u8>,
+    seq: u8,
+    command: u8,
+    data: [u8; N],
+    data_len: usize,
+}
+
+/// Device side of the protocol, for a link of N byte messages
+///
+/// Decodes request frames from the host, carries out register reads (command 1) and writes
+/// (command 2) on a RegisterFile, and encodes the replies. Tagged reads and writes (commands 3 and
+/// 4) are the same with a tag byte before the data, which is echoed before the reply data. The
+/// hello (command 5) is answered with device_info, and pings (command 6) are echoed. Block reads
+/// (command 8) are answered with as many registers as fit the reply and MAX_READ_REGS_LEN, and
+/// rejected with OutOfRange if more are asked for. Requests the device rejects are answered with
+/// an error reply, see binarycom::errorreply. Messages with a command of 0x80 and up, once
+/// reassembled if the host sent them in fragments, are handed to RegisterFile::receive_message
+/// and answered only if it rejects them.
+///
+/// Frames are decoded and encoded by framer, and checked with checksum. See binarycom::reliable
+/// for what changes with set_reliable. On a multi-drop bus, set_address makes the responder
+/// ignore requests addressed to other devices; requests to BROADCAST_ADDRESS are carried out but
+/// not answered.
+///
+/// Requests and replies carry up to max_data_len(N) bytes of data, as on a BinaryComApp<N>.
+pub struct DeviceResponder<R: RegisterFile, F: Framer = CobsFramer, const N: usize = 16> {
+    pub registers: R,
+    framer: F,
+    checksum: Checksum,
+    reliable: bool,
+    /// This device's address, on a bus
+    address: Option<u8>,
+    /// CAP_* bits reported in the hello reply
+    capabilities: u8,
+    rx_seqs: Deduplicator,
+    /// Sequence number of the next request, sent in NACKs
+    expected_seq: u8,
+    tx_seq: u8,
+    /// Whether this session's CMD_SYNC has been sent to the host
+    synced: bool,
+    last_reply: Option<SentReply<N>>,
+    reassembler: Reassembler<MAX_REASSEMBLED_LEN>,
+    /// Time since any fixed instant, for the reassembly timeout, see set_time
+    now: Duration,
+    /// Message id of the next fragmented stream message
+    fragment_id: u8,
+    /// Encoded messages waiting to be taken with reply, in outbuf[..outbuf_len]
+    outbuf: [[u8; N]; OUTBUF_MESSAGES],
+    outbuf_len: usize,
+}
+
+impl<R: RegisterFile> DeviceResponder<R> {
+    /// Responder for the default link: COBS with CRC-16/DNP
+    pub fn new(registers: R) -> DeviceResponder<R> {
+        DeviceResponder::with_framer(registers, CobsFramer::default(), Checksum::Crc16Dnp)
+    }
+}
+
+impl<R: RegisterFile, F: Framer, const N: usize> DeviceResponder<R, F, N> {
+    /// Longest request or reply data
+    const MAX_DATA_LEN: usize = max_data_len(N);
+
+    pub fn with_framer(registers: R, framer: F, checksum: Checksum) -> DeviceResponder<R, F, N> {
+        DeviceResponder {
+            registers,
+            framer,
+            checksum,
+            reliable: false,
+            address: None,
+            capabilities: CAP_TAGGED | CAP_BLOCK_READ,
+            rx_seqs: Deduplicator::default(),
+            expected_seq: 0,
+            tx_seq: 0,
+            synced: false,
+            last_reply: None,
+            reassembler: Reassembler::new(REASSEMBLY_TIMEOUT),
+            now: Duration::from_secs(0),
+            fragment_id: 0,
+            outbuf: [[0; N]; OUTBUF_MESSAGES],
+            outbuf_len: 0,
+        }
+    }
+
+    /// Use sequence numbers and ACK/NACK, for a link with LinkConfig::reliable set
+    pub fn set_reliable(&mut self, reliable: bool) {
+        self.reliable = reliable;
+    }
+
+    /// Set the time since any fixed instant, e.g. from a tick counter, for timing out messages
+    /// the host sends in fragments
+    ///
+    /// Without it, an incomplete message is only dropped when the next one starts.
+    pub fn set_time(&mut self, now: Duration) {
+        self.now = now;
+    }
+
+    /// Put an address byte before each message, for a multi-drop bus, and answer only requests
+    /// to address
+    ///
+    /// On a bus, frames that can't be decoded are dropped without a NACK, as the device they were
+    /// for is unknown.
+    pub fn set_address(&mut self, address: Option<u8>) {
+        self.address = address;
+    }
+
+    /// Set the CAP_* bits reported in the hello reply
+    ///
+    /// The default is CAP_TAGGED | CAP_BLOCK_READ. Firmware that sends stream messages should add
+    /// CAP_STREAM, and firmware whose RegisterFile takes messages with receive_message should add
+    /// CAP_FRAGMENTS, as hosts only send fragments to a device that reports it.
+    pub fn set_capabilities(&mut self, capabilities: u8) {
+        self.capabilities = capabilities;
+    }
+
+    /// What the device reports in its hello reply
+    pub fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            version_major: PROTOCOL_VERSION_MAJOR,
+            version_minor: PROTOCOL_VERSION_MINOR,
+            max_data_len: Self::MAX_DATA_LEN as u16,
+            register_bit_width: self.registers.register_bit_width(),
+            checksum: self.checksum,
+            capabilities: self.capabilities,
+        }
+    }
+
+    /// Bytes before the command: the address on a bus, then the seq on a reliable link
+    fn header_len(&self) -> usize {
+        usize::from(self.address.is_some()) + usize::from(self.reliable)
+    }
+
+    /// Feed one byte received from the host
+    ///
+    /// When the byte completes a request frame, the request is carried out and the reply is
+    /// encoded, ready to be taken with reply.
+    ///
+    /// Returns Ok(true) when a reply is ready. Returns Err if the frame couldn't be decoded or the
+    /// request was too malformed for an error reply; the frame is dropped either way. On a
+    /// reliable link, the ACK or NACK is always a reply, so Ok(true) is returned instead of those
+    /// errors, except that bad frames on a bus aren't NACKed. Pings aren't ACKed, see supervisor.
+    /// Requests to other addresses, and broadcasts, give Ok(false).
+    pub fn receive_byte(&mut self, byte: u8) -> SerialComResult<bool> {
+        let mut command: u8 = 0;
+        let mut data: [u8; N] = [0; N];
+        let mut header: [u8; 2] = [0; 2];
+        let header = &mut header[..self.header_len()];
+        let frame = match self.framer.push(byte) {
+            None => return Ok(false),
+            Some(frame) => frame,
+        };
+        let checksum = self.checksum;
+        let unpacked = frame.and_then(|frame| {
+            unpack_frame_headed(
+                frame,
+                checksum,
+                header,
+                &mut command,
+                &mut data[..Self::MAX_DATA_LEN],
+            )
+        });
+        let data_len = match unpacked {
+            Ok(data_len) => data_len,
+            Err(_) if self.reliable && self.address.is_none() => {
+                self.outbuf_len = 0;
+                self.queue(Some(self.expected_seq), CMD_NACK, &[])?;
+                return Ok(true);
+            }
+            Err(unpack_error) => return Err(unpack_error),
+        };
+        let broadcast = match self.address {
+            None => false,
+            Some(_) if header[0] == BROADCAST_ADDRESS => true,
+            Some(address) if header[0] == address => false,
+            Some(_) => return Ok(false),
+        };
+        if !self.reliable {
+            let replied = self.respond(command, &data[..data_len])?;
+            return Ok(replied && !broadcast);
+        }
+        self.outbuf_len = 0;
+        if broadcast {
+            // Broadcasts aren't retransmitted, so there are no repeats to drop
+            let _ = self.carry_out(None, command, &data[..data_len]);
+            return Ok(false);
+        }
+        if command == CMD_PING {
+            // Pings are outside the seq space, see supervisor: answered each time they come
+            let nonce = *data[..data_len]
+                .first()
+                .ok_or(SerialComError::SliceTooSmall)?;
+            self.queue_next(CMD_PING, &[nonce])?;
+            return Ok(true);
+        }
+        let seq = header[header.len() - 1];
+        if command == CMD_SYNC {
+            // The host starts a new session
+            self.rx_seqs.reset();
+            self.last_reply = None;
+            self.tx_seq = 0;
+            self.synced = false;
+        }
+        self.queue(Some(seq), CMD_ACK, &[])?;
+        if self.rx_seqs.is_new(seq) {
+            self.expected_seq = seq.wrapping_add(1);
+            self.last_reply = None;
+            if command == CMD_SYNC {
+                self.sync()?;
+            } else {
+                // The host gets no reply to a request too malformed for an error reply, and
+                // retransmits until it gives up
+                let _ = self.carry_out(Some(seq), command, &data[..data_len]);
+            }
+        } else if let Some(reply) = self.last_reply.filter(|reply| reply.request_seq == seq) {
+            if let Some(sync_seq) = reply.sync_seq {
+                self.queue(Some(sync_seq), CMD_SYNC, &[])?;
+            }
+            self.queue(
+                Some(reply.seq),
+                reply.command,
+                &reply.data[..reply.data_len],
+            )?;
+        }
+        Ok(true)
+    }
+
+    /// Carry out a decoded request and encode the reply
+    ///
+    /// Returns Ok(true) when a reply is ready to be taken with reply. Rejected requests, including
+    /// unknown commands, get an error reply; Err is returned for requests too malformed for one.
+    pub fn respond(&mut self, command: u8, data: &[u8]) -> SerialComResult<bool> {
+        self.outbuf_len = 0;
+        self.carry_out(None, command, data)
+    }
+
+    /// Carry out a request and queue the reply after what's already in outbuf
+    ///
+    /// request_seq is the request's sequence number, on a reliable link. Returns Ok(false) for the
+    /// messages that have no reply, see take_message.
+    fn carry_out(
+        &mut self,
+        request_seq: Option<u8>,
+        command: u8,
+        data: &[u8],
+    ) -> SerialComResult<bool> {
+        if command == CMD_FRAGMENT {
+            return self.take_fragment(request_seq, data);
+        }
+        if command >= 0x80 {
+            let received = self.registers.receive_message(command, data);
+            return self.take_message(request_seq, command, received);
+        }
+        let mut reply: [u8; N] = [0; N];
+        let reply = &mut reply[..Self::MAX_DATA_LEN];
+        let (untagged_command, tag_len) = match command {
+            CMD_READ_REG_TAGGED => (1u8, 1),
+            CMD_WRITE_REG_TAGGED => (2u8, 1),
+            _ => (command, 0),
+        };
+        let tag = if tag_len > 0 {
+            *data.first().ok_or(SerialComError::SliceTooSmall)?
+        } else {
+            0
+        };
+        reply[0] = tag;
+        let executed = self.execute(untagged_command, &data[tag_len..], &mut reply[tag_len..]);
+        let (command, data_len) = match executed {
+            Ok(reply_len) => (command, tag_len + usize::from(reply_len)),
+            Err(SerialComError::DeviceError { code, reg_num }) => {
+                let error_reply = ErrorReply {
+                    command,
+                    tag,
+                    code,
+                    reg_num,
+                };
+                (CMD_ERROR, usize::from(error_reply.pack(reply)?))
+            }
+            Err(error) => return Err(error),
+        };
+        self.queue_reply(request_seq, command, &reply[..data_len])?;
+        Ok(true)
+    }
+
+    /// Queue the reply to a request after what's already in outbuf, on a reliable link
+    /// remembering it to answer the request if it's sent again
+    fn queue_reply(
+        &mut self,
+        request_seq: Option<u8>,
+        command: u8,
+        data: &[u8],
+    ) -> SerialComResult<()> {
+        let request_seq = match request_seq {
+            None => return self.queue(None, command, data),
+            Some(request_seq) => request_seq,
+        };
+        let sync_seq = if self.synced {
+            None
+        } else {
+            Some(self.sync()?)
+        };
+        let seq = self.next_seq();
+        let mut sent = SentReply {
+            request_seq,
+            sync_seq,
+            seq,
+            command,
+            data: [0; N],
+            data_len: data.len(),
+        };
+        sent.data[..data.len()].copy_from_slice(data);
+        self.last_reply = Some(sent);
+        self.queue(Some(seq), command, data)
+    }
+
+    /// Carry out an untagged request, putting the reply data in reply_data
+    ///
+    /// returns Result with length of reply data
+    fn execute(&mut self, command: u8, data: &[u8], reply_data: &mut [u8]) -> SerialComResult<u8> {
+        let reply_len = match command {
+            1u8 => {
+                let reg_num = packers::dev_read_reg_unpack(data)?;
+                let reg_val = self.registers.read_reg(reg_num)?;
+                match self.registers.register_bit_width() {
+                    RegisterBitWidth::Eight => {
+                        let reg_val =
+                            u8::try_from(reg_val).map_err(|_| SerialComError::DeviceError {
+                                code: DeviceErrorCode::OutOfRange,
+                                reg_num,
+                            })?;
+                        packers::dev_read_reg8_pack(reg_num, reg_val, reply_data)?
+                    }
+                    RegisterBitWidth::ThirtyTwo => {
+                        packers::dev_read_reg32_pack(reg_num, reg_val, reply_data)?
+                    }
+                }
+            }
+            2u8 => {
+                let (reg_num, reg_val) = match self.registers.register_bit_width() {
+                    RegisterBitWidth::Eight => {
+                        let (reg_num, reg_val) = packers::dev_write_reg8_unpack(data)?;
+                        (reg_num, u32::from(reg_val))
+                    }
+                    RegisterBitWidth::ThirtyTwo => packers::dev_write_reg32_unpack(data)?,
+                };
+                self.registers.write_reg(reg_num, reg_val)?;
+                packers::dev_write_reg_pack(reg_num, reply_data)?
+            }
+            CMD_HELLO => self.device_info().pack(reply_data)?,
+            CMD_PING => {
+                reply_data[0] = *data.first().ok_or(SerialComError::SliceTooSmall)?;
+                1
+            }
+            CMD_READ_REGS => self.read_regs(data, reply_data)?,
+            _ => {
+                return Err(SerialComError::DeviceError {
+                    code: DeviceErrorCode::UnsupportedCommand,
+                    reg_num: 0,
+                })
+            }
+        };
+        Ok(reply_len)
+    }
+
+    /// Take a fragment of a message from the host, handing the message to
+    /// RegisterFile::receive_message once it's whole
+    ///
+    /// Returns like take_message. Fragments that can't be reassembled are dropped with an Err.
+    fn take_fragment(&mut self, request_seq: Option<u8>, data: &[u8]) -> SerialComResult<bool> {
+        let (command, received) = match self.reassembler.push(data, self.now)? {
+            None => return Ok(false),
+            Some((command, whole)) => (command, self.registers.receive_message(command, whole)),
+        };
+        self.take_message(request_seq, command, received)
+    }
+
+    /// Answer a message handed to RegisterFile::receive_message, which gave received
+    ///
+    /// Only rejected messages are answered, with an error reply; returns Ok(true) when it's ready.
+    fn take_message(
+        &mut self,
+        request_seq: Option<u8>,
+        command: u8,
+        received: SerialComResult<()>,
+    ) -> SerialComResult<bool> {
+        let (code, reg_num) = match received {
+            Ok(()) => return Ok(false),
+            Err(SerialComError::DeviceError { code, reg_num }) => (code, reg_num),
+            Err(error) => return Err(error),
+        };
+        let error_reply = ErrorReply {
+            command,
+            tag: 0,
+            code,
+            reg_num,
+        };
+        let mut reply: [u8; ERROR_REPLY_LEN] = [0; ERROR_REPLY_LEN];
+        let reply_len = usize::from(error_reply.pack(&mut reply)?);
+        self.queue_reply(request_seq, CMD_ERROR, &reply[..reply_len])?;
+        Ok(true)
+    }
+
+    /// Carry out a block read, putting the reply data in reply_data
+    ///
+    /// returns Result with length of reply data
+    fn read_regs(&mut self, data: &[u8], reply_data: &mut [u8]) -> SerialComResult<u8> {
+        let (start, count) = packers::dev_read_regs_unpack(data)?;
+        let count = usize::from(count);
+        let width = self.registers.register_bit_width();
+        let value_len = match width {
+            RegisterBitWidth::Eight => 1,
+            RegisterBitWidth::ThirtyTwo => 4,
+        };
+        if 3 + count * value_len > reply_data.len().min(MAX_READ_REGS_LEN) {
+            return Err(SerialComError::DeviceError {
+                code: DeviceErrorCode::OutOfRange,
+                reg_num: start,
+            });
+        }
+        if usize::from(u16::MAX - start) + 1 < count {
+            return Err(SerialComError::DeviceError {
+                code: DeviceErrorCode::UnknownRegister,
+                reg_num: u16::MAX,
+            });
+        }
+        let data_len = 3 + count * value_len;
+        for (value, reg_num) in reply_data[3..data_len]
+            .chunks_mut(value_len)
+            .zip(start..=u16::MAX)
+        {
+            let reg_val = self.registers.read_reg(reg_num)?;
+            match width {
+                RegisterBitWidth::Eight => {
+                    value[0] = u8::try_from(reg_val).map_err(|_| SerialComError::DeviceError {
+                        code: DeviceErrorCode::OutOfRange,
+                        reg_num,
+                    })?
+                }
+                RegisterBitWidth::ThirtyTwo => value.copy_from_slice(&reg_val.to_be_bytes()),
+            }
+        }
+        packers::dev_write_reg_pack(start, reply_data)?;
+        reply_data[2] = u8::try_from(count)?;
+        Ok(u8::try_from(data_len)?)
+    }
+
+    /// Encode a stream message (command 0x80 and up), ready to be taken with reply
+    pub fn stream(&mut self, command: u8, data: &[u8]) -> SerialComResult<()> {
+        self.outbuf_len = 0;
+        if self.reliable {
+            self.queue_next(command, data)
+        } else {
+            self.queue(None, command, data)
+        }
+    }
+
+    /// Queue a message that answers no request with the next seq, after this session's CMD_SYNC
+    /// if it's the first message of the session
+    fn queue_next(&mut self, command: u8, data: &[u8]) -> SerialComResult<()> {
+        if !self.synced {
+            self.sync()?;
+        }
+        let seq = self.next_seq();
+        self.queue(Some(seq), command, data)
+    }
+
+    /// Send a stream message, as CMD_FRAGMENT messages if its data doesn't fit one frame, calling
+    /// write with each encoded message
+    ///
+    /// Each fragment is as long as the link's frames allow, see frame_data_len, so the host must
+    /// be built for the same N.
+    pub fn stream_long<W>(&mut self, command: u8, data: &[u8], mut write: W) -> SerialComResult<()>
+    where
+        W: FnMut(&[u8]) -> SerialComResult<()>,
+    {
+        let chunk_len = frame_data_len(N, self.header_len(), self.checksum).min(Self::MAX_DATA_LEN);
+        if data.len() <= chunk_len {
+            self.stream(command, data)?;
+            return write(&self.outbuf.as_flattened()[..self.outbuf_len]);
+        }
+        let mut fragmenter = Fragmenter::new(self.fragment_id, command, data, chunk_len)?;
+        self.fragment_id = self.fragment_id.wrapping_add(1);
+        let mut fragment: [u8; N] = [0; N];
+        while let Some(fragment_len) = fragmenter.next_into(&mut fragment)? {
+            self.stream(CMD_FRAGMENT, &fragment[..fragment_len])?;
+            write(&self.outbuf.as_flattened()[..self.outbuf_len])?;
+        }
+        Ok(())
+    }
+
+    fn next_seq(&mut self) -> u8 {
+        let seq = self.tx_seq;
+        self.tx_seq = self.tx_seq.wrapping_add(1);
+        seq
+    }
+
+    /// Queue the CMD_SYNC that tells the host this device's seqs start over
+    ///
+    /// returns its seq
+    fn sync(&mut self) -> SerialComResult<u8> {
+        let seq = self.next_seq();
+        self.queue(Some(seq), CMD_SYNC, &[])?;
+        self.synced = true;
+        Ok(seq)
+    }
+
+    /// Encode a message after what's already in outbuf, with seq on a reliable link and the
+    /// address on a bus
+    fn queue(&mut self, seq: Option<u8>, command: u8, data: &[u8]) -> SerialComResult<()> {
+        if data.len() > Self::MAX_DATA_LEN {
+            return Err(SerialComError::SliceTooBig);
+        }
+        let mut header: [u8; 2] = [0; 2];
+        let mut header_len = 0;
+        if let Some(address) = self.address {
+            header[0] = address;
+            header_len += 1;
+        }
+        if let Some(seq) = seq {
+            header[header_len] = seq;
+            header_len += 1;
+        }
+        let outbuf = self.outbuf.as_flattened_mut();
+        let frame_len = send_message_headed(
+            &self.framer,
+            self.checksum,
+            &header[..header_len],
+            command,
+            data,
+            &mut outbuf[self.outbuf_len..],
+        )
+        .map_err(|_| SerialComError::QueueTooFull)?;
+        self.outbuf_len += frame_len;
+        Ok(())
+    }
+
+    /// The encoded replies or stream message, as two slices to be sent in order
+    pub fn reply(&self) -> (&[u8], &[u8]) {
+        (&self.outbuf.as_flattened()[..self.outbuf_len], &[])
+    }
+
+    /// Feed bytes received from the host, calling write with each encoded reply
+    ///
+    /// Frames that can't be decoded, or are too malformed for an error reply, are dropped without
+    /// a reply, other than the NACK or ACK on a reliable link. Only errors from write are returned.
+    pub fn process<W>(&mut self, bytes: &[u8], mut write: W) -> SerialComResult<()>
+    where
+        W: FnMut(&[u8]) -> SerialComResult<()>,
+    {
+        for byte in bytes {
+            if let Ok(true) = self.receive_byte(*byte) {
+                write(&self.outbuf.as_flattened()[..self.outbuf_len])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::binarycom::{send_message_into, unpack_frame_with, BinaryCom};
+
+#[cfg(test)]
+struct TestRegisters {
+    width: RegisterBitWidth,
+    values: [u32; 4],
+}
+
+#[cfg(test)]
+impl RegisterFile for TestRegisters {
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        self.width
+    }
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
+        self.values
+            .get(usize::from(reg_num))
+            .copied()
+            .ok_or(SerialComError::DeviceError {
+                code: DeviceErrorCode::UnknownRegister,
+                reg_num,
+            })
+    }
+    fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
+        let reg = self
+            .values
+            .get_mut(usize::from(reg_num))
+            .ok_or(SerialComError::DeviceError {
+                code: DeviceErrorCode::UnknownRegister,
+                reg_num,
+            })?;
+        *reg = reg_val;
+        Ok(())
+    }
+}
+
+/// Feed the host's encoded request to responder and decode the reply
+#[cfg(test)]
+fn exchange(
+    responder: &mut DeviceResponder<TestRegisters>,
+    request: &arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping>,
+) -> Option<(u8, Vec<u8>)> {
+    let mut reply: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let request: Vec<u8> = request.iter().copied().collect();
+    responder
+        .process(&request, |bytes| {
+            for byte in bytes {
+                reply.push_back(*byte);
+            }
+            Ok(())
+        })
+        .expect("Couldn't process request");
+    if reply.is_empty() {
+        return None;
+    }
+    let mut command: u8 = 0;
+    let mut data: [u8; 11] = [0; 11];
+    let data_len = reply
+        .receive_message(&mut command, &mut data)
+        .expect("Couldn't receive reply");
+    Some((command, data[..data_len].to_vec()))
+}
+
+#[test]
+fn test_device_responder_reg32() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::ThirtyTwo,
+        values: [0, 0x12345678, 0, 0],
+    });
+    let mut request: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    request.host_read_reg(1).unwrap();
+    let (command, data) = exchange(&mut responder, &request).expect("No reply");
+    assert_eq!(command, 1);
+    assert_eq!(
+        packers::host_read_reg_unpack(&data).unwrap(),
+        (1, 0x12345678)
+    );
+    request.host_write_reg32(3, 0xFF00FF00).unwrap();
+    let (command, data) = exchange(&mut responder, &request).expect("No reply");
+    assert_eq!(command, 2);
+    assert_eq!(packers::host_write_reg_unpack(&data).unwrap(), 3);
+    assert_eq!(responder.registers.values[3], 0xFF00FF00);
+    request.host_read_reg(10).unwrap();
+    let (command, data) = exchange(&mut responder, &request).expect("No reply");
+    assert_eq!(command, CMD_ERROR);
+    assert_eq!(
+        ErrorReply::unpack(&data).unwrap(),
+        ErrorReply {
+            command: 1,
+            tag: 0,
+            code: DeviceErrorCode::UnknownRegister,
+            reg_num: 10,
+        }
+    );
+}
+
+#[test]
+fn test_device_responder_reg8() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::Eight,
+        values: [0, 0, 0, 0],
+    });
+    let mut request: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    request.host_write_reg8(2, 0xAB).unwrap();
+    let (command, data) = exchange(&mut responder, &request).expect("No reply");
+    assert_eq!(command, 2);
+    assert_eq!(packers::host_write_reg_unpack(&data).unwrap(), 2);
+    request.host_read_reg(2).unwrap();
+    let (command, data) = exchange(&mut responder, &request).expect("No reply");
+    assert_eq!(command, 1);
+    assert_eq!(data.len(), 3);
+    assert_eq!(packers::host_read_reg_unpack(&data).unwrap(), (2, 0xAB));
+    responder.registers.values[3] = 0x100;
+    request.host_read_reg(3).unwrap();
+    let (command, data) = exchange(&mut responder, &request).expect("No reply");
+    assert_eq!(command, CMD_ERROR);
+    assert_eq!(
+        ErrorReply::unpack(&data).unwrap().code,
+        DeviceErrorCode::OutOfRange
+    );
+}
+
+#[test]
+fn test_device_responder_rejects_unknown_command() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::ThirtyTwo,
+        values: [0; 4],
+    });
+    let mut request: [u8; 16] = [0; 16];
+    let request_len = send_message_into(0x20, &[1, 2], &mut request).unwrap();
+    let mut reply: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    responder
+        .process(&request[..request_len], |bytes| {
+            for byte in bytes {
+                reply.push_back(*byte);
+            }
+            Ok(())
+        })
+        .expect("Couldn't process request");
+    let mut command: u8 = 0;
+    let mut data: [u8; 11] = [0; 11];
+    let data_len = reply
+        .receive_message(&mut command, &mut data)
+        .expect("Couldn't receive reply");
+    assert_eq!(command, CMD_ERROR);
+    assert_eq!(
+        ErrorReply::unpack(&data[..data_len]).unwrap(),
+        ErrorReply {
+            command: 0x20,
+            tag: 0,
+            code: DeviceErrorCode::UnsupportedCommand,
+            reg_num: 0,
+        }
+    );
+}
+
+#[test]
+fn test_device_responder_read_regs() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::ThirtyTwo,
+        values: [0x10, 0x1122_3344, 0x30, 0x40],
+    });
+    let mut request: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let mut request_data: [u8; 3] = [0; 3];
+    let mut block_read = |start: u16, count: u8| {
+        packers::host_read_regs_pack(start, count, &mut request_data).unwrap();
+        request.send_message(&CMD_READ_REGS, &request_data).unwrap();
+        exchange(&mut responder, &request).expect("No reply")
+    };
+    let (command, data) = block_read(1, 2);
+    assert_eq!(command, CMD_READ_REGS);
+    assert_eq!(
+        packers::host_read_regs_unpack(&data).unwrap(),
+        (1, vec![0x1122_3344, 0x30])
+    );
+    assert_eq!(block_read(2, 0).1, [0, 2, 0]);
+    // Three 32-bit values don't fit one reply
+    let (command, data) = block_read(0, 3);
+    assert_eq!(command, CMD_ERROR);
+    assert_eq!(
+        ErrorReply::unpack(&data).unwrap().code,
+        DeviceErrorCode::OutOfRange
+    );
+    let (command, data) = block_read(3, 2);
+    assert_eq!(command, CMD_ERROR);
+    assert_eq!(
+        ErrorReply::unpack(&data).unwrap(),
+        ErrorReply {
+            command: CMD_READ_REGS,
+            tag: 0,
+            code: DeviceErrorCode::UnknownRegister,
+            reg_num: 4,
+        }
+    );
+    responder.registers.width = RegisterBitWidth::Eight;
+    responder.registers.values[1] = 0x20;
+    packers::host_read_regs_pack(0, 4, &mut request_data).unwrap();
+    request.send_message(&CMD_READ_REGS, &request_data).unwrap();
+    let (_, data) = exchange(&mut responder, &request).expect("No reply");
+    assert_eq!(data, [0, 0, 4, 0x10, 0x20, 0x30, 0x40]);
+    assert_eq!(
+        packers::host_read_regs_unpack(&data).unwrap(),
+        (0, vec![0x10, 0x20, 0x30, 0x40])
+    );
+}
+
+#[test]
+fn test_device_responder_read_regs_long_messages() {
+    let mut responder: DeviceResponder<_, CobsFramer, 1024> = DeviceResponder::with_framer(
+        TestRegisters {
+            width: RegisterBitWidth::ThirtyTwo,
+            values: [0; 4],
+        },
+        CobsFramer::default(),
+        Checksum::Crc16Dnp,
+    );
+    // 100 32-bit values fit a 1024 byte message, but not a reply of MAX_READ_REGS_LEN bytes
+    let mut request_data: [u8; 3] = [0; 3];
+    packers::host_read_regs_pack(0, 100, &mut request_data).unwrap();
+    let mut request: [u8; 16] = [0; 16];
+    let request_len = send_message_into(CMD_READ_REGS, &request_data, &mut request).unwrap();
+    let mut reply: Vec<u8> = Vec::new();
+    responder
+        .process(&request[..request_len], |bytes| {
+            reply.extend_from_slice(bytes);
+            Ok(())
+        })
+        .expect("Couldn't process request");
+    let mut decoder = CobsFramer::<1024>::default();
+    let mut replies: Vec<(u8, Vec<u8>)> = Vec::new();
+    decoder.push_slice(&reply, |frame| {
+        let mut command: u8 = 0;
+        let mut data: [u8; 16] = [0; 16];
+        let data_len =
+            unpack_frame_with(frame.unwrap(), Checksum::Crc16Dnp, &mut command, &mut data).unwrap();
+        replies.push((command, data[..data_len].to_vec()));
+    });
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].0, CMD_ERROR);
+    assert_eq!(
+        ErrorReply::unpack(&replies[0].1).unwrap().code,
+        DeviceErrorCode::OutOfRange
+    );
+}
+
+#[test]
+fn test_device_responder_drops_corrupt_frame() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::ThirtyTwo,
+        values: [0, 0, 0, 0],
+    });
+    let mut request: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    request.host_write_reg32(1, 5).unwrap();
+    if let Some(el) = request.get_mut(4) {
+        *el ^= 0x40;
+    }
+    assert_eq!(exchange(&mut responder, &request), None);
+    assert_eq!(responder.registers.values[1], 0);
+    // The next good frame is handled normally
+    request.host_write_reg32(1, 5).unwrap();
+    assert!(exchange(&mut responder, &request).is_some());
+    assert_eq!(responder.registers.values[1], 5);
+}
+
+#[test]
+fn test_device_responder_hdlc_crc32() {
+    let framer = crate::framer::HdlcFramer::<16>::default();
+    let mut responder: DeviceResponder<_, _> = DeviceResponder::with_framer(
+        TestRegisters {
+            width: RegisterBitWidth::ThirtyTwo,
+            values: [0, 0x7E7D_7E7D, 0, 0],
+        },
+        framer,
+        Checksum::Crc32,
+    );
+    let mut request: [u8; 32] = [0; 32];
+    let request_len = crate::binarycom::send_message_framed(
+        &crate::framer::HdlcFramer::<16>::default(),
+        Checksum::Crc32,
+        1,
+        &[0, 1],
+        &mut request,
+    )
+    .unwrap();
+    let mut reply: Vec<u8> = Vec::new();
+    responder
+        .process(&request[..request_len], |bytes| {
+            reply.extend_from_slice(bytes);
+            Ok(())
+        })
+        .expect("Couldn't process request");
+    let mut host_framer = crate::framer::HdlcFramer::<16>::default();
+    let mut command: u8 = 0;
+    let mut data: [u8; 11] = [0; 11];
+    let mut data_len: usize = 0;
+    host_framer.push_slice(&reply, |frame| {
+        data_len = unpack_frame_with(frame.unwrap(), Checksum::Crc32, &mut command, &mut data)
+            .expect("Couldn't unpack reply")
+    });
+    assert_eq!(command, 1);
+    assert_eq!(
+        packers::host_read_reg_unpack(&data[..data_len]).unwrap(),
+        (1, 0x7E7D_7E7D)
+    );
+}
+
+#[test]
+fn test_device_responder_address() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::ThirtyTwo,
+        values: [0; 4],
+    });
+    responder.set_reliable(true);
+    responder.set_address(Some(5));
+    let framer = CobsFramer::<16>::default();
+    let mut request: [u8; 32] = [0; 32];
+    let mut data: [u8; 6] = [0; 6];
+    packers::host_write_reg32_pack(2, 0xABCD, &mut data).unwrap();
+    // [address][seq][command][data]
+    let mut exchange_headed = |header: &[u8]| -> Vec<(u8, u8, u8)> {
+        let request_len =
+            send_message_headed(&framer, Checksum::Crc16Dnp, header, 2, &data, &mut request)
+                .unwrap();
+        let mut reply: Vec<u8> = Vec::new();
+        responder
+            .process(&request[..request_len], |bytes| {
+                reply.extend_from_slice(bytes);
+                Ok(())
+            })
+            .expect("Couldn't process request");
+        let mut host_framer = CobsFramer::<16>::default();
+        let mut replies: Vec<(u8, u8, u8)> = Vec::new();
+        host_framer.push_slice(&reply, |frame| {
+            let mut header: [u8; 2] = [0; 2];
+            let mut command: u8 = 0;
+            let mut reply_data: [u8; 11] = [0; 11];
+            unpack_frame_headed(
+                frame.unwrap(),
+                Checksum::Crc16Dnp,
+                &mut header,
+                &mut command,
+                &mut reply_data,
+            )
+            .expect("Couldn't unpack reply");
+            replies.push((header[0], header[1], command));
+        });
+        replies
+    };
+    assert_eq!(exchange_headed(&[4, 0]), []);
+    // The first reply of the session goes after a CMD_SYNC
+    assert_eq!(
+        exchange_headed(&[5, 1]),
+        [(5, 1, CMD_ACK), (5, 0, CMD_SYNC), (5, 1, 2)]
+    );
+    assert_eq!(exchange_headed(&[BROADCAST_ADDRESS, 2]), []);
+    assert_eq!(responder.registers.values[2], 0xABCD);
+}
+
+#[test]
+fn test_device_responder_sync() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::ThirtyTwo,
+        values: [0; 4],
+    });
+    responder.set_reliable(true);
+    let framer = CobsFramer::<16>::default();
+    // Sends [seq][command][data] writing value to register 2, returns the (seq, command) replies
+    let mut exchange_sequenced = |seq: u8, command: u8, value: u32| -> Vec<(u8, u8)> {
+        let mut data: [u8; 6] = [0; 6];
+        packers::host_write_reg32_pack(2, value, &mut data).unwrap();
+        let data_len = if command == CMD_SYNC { 0 } else { 6 };
+        let mut request: [u8; 32] = [0; 32];
+        let request_len = send_message_headed(
+            &framer,
+            Checksum::Crc16Dnp,
+            &[seq],
+            command,
+            &data[..data_len],
+            &mut request,
+        )
+        .unwrap();
+        let mut reply: Vec<u8> = Vec::new();
+        responder
+            .process(&request[..request_len], |bytes| {
+                reply.extend_from_slice(bytes);
+                Ok(())
+            })
+            .expect("Couldn't process request");
+        let mut host_framer = CobsFramer::<16>::default();
+        let mut replies: Vec<(u8, u8)> = Vec::new();
+        host_framer.push_slice(&reply, |frame| {
+            let mut header: [u8; 1] = [0; 1];
+            let mut command: u8 = 0;
+            let mut reply_data: [u8; 11] = [0; 11];
+            unpack_frame_headed(
+                frame.unwrap(),
+                Checksum::Crc16Dnp,
+                &mut header,
+                &mut command,
+                &mut reply_data,
+            )
+            .expect("Couldn't unpack reply");
+            replies.push((header[0], command));
+        });
+        replies
+    };
+    // The first reply after starting up goes after a CMD_SYNC, also when it's sent again
+    let first_replies = [(0, CMD_ACK), (0, CMD_SYNC), (1, 2)];
+    assert_eq!(exchange_sequenced(0, 2, 1), first_replies);
+    assert_eq!(exchange_sequenced(0, 2, 9), first_replies);
+    for seq in 1..4u8 {
+        exchange_sequenced(seq, 2, 1);
+    }
+    // A new host starting over from seq 0 looks like a repeat
+    assert_eq!(exchange_sequenced(0, 2, 7), [(0, CMD_ACK)]);
+    // Until it starts a session
+    assert_eq!(
+        exchange_sequenced(0, CMD_SYNC, 0),
+        [(0, CMD_ACK), (0, CMD_SYNC)]
+    );
+    assert_eq!(exchange_sequenced(1, 2, 7), [(1, CMD_ACK), (1, 2)]);
+    // A ping isn't ACKed and doesn't stop the last reply being sent again
+    assert_eq!(exchange_sequenced(0, CMD_PING, 0), [(2, CMD_PING)]);
+    assert_eq!(exchange_sequenced(1, 2, 8), [(1, CMD_ACK), (1, 2)]);
+    assert_eq!(responder.registers.values[2], 7);
+}
+
+#[test]
+fn test_device_responder_fragments() {
+    let mut responder = DeviceResponder::new(TestRegisters {
+        width: RegisterBitWidth::ThirtyTwo,
+        values: [0; 4],
+    });
+    let message: Vec<u8> = (0..20).collect();
+    // TestRegisters takes no messages, so the whole one is rejected, but not its fragments
+    let mut fragmenter = Fragmenter::new(0, 0x90, &message, 11).unwrap();
+    let mut request: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let mut replies: Vec<Option<(u8, Vec<u8>)>> = Vec::new();
+    while request.send_fragment(&mut fragmenter).unwrap().is_some() {
+        replies.push(exchange(&mut responder, &request));
+    }
+    let error_reply = ErrorReply {
+        command: 0x90,
+        tag: 0,
+        code: DeviceErrorCode::UnsupportedCommand,
+        reg_num: 0,
+    };
+    let mut error_data: [u8; ERROR_REPLY_LEN] = [0; ERROR_REPLY_LEN];
+    error_reply.pack(&mut error_data).unwrap();
+    assert_eq!(
+        replies,
+        [None, None, Some((CMD_ERROR, error_data.to_vec()))]
+    );
+    // A long stream message goes in fragments
+    let mut frames: Vec<u8> = Vec::new();
+    responder
+        .stream_long(0x8A, &message, |bytes| {
+            frames.extend_from_slice(bytes);
+            Ok(())
+        })
+        .expect("Couldn't stream");
+    let mut decoder = CobsFramer::<16>::default();
+    let mut reassembler = Reassembler::<64>::new(REASSEMBLY_TIMEOUT);
+    let mut reassembled: Option<(u8, Vec<u8>)> = None;
+    decoder.push_slice(&frames, |frame| {
+        let mut command: u8 = 0;
+        let mut data: [u8; 11] = [0; 11];
+        let data_len =
+            unpack_frame_with(frame.unwrap(), Checksum::Crc16Dnp, &mut command, &mut data).unwrap();
+        assert_eq!(command, CMD_FRAGMENT);
+        let whole = reassembler
+            .push(&data[..data_len], Duration::from_secs(0))
+            .unwrap();
+        if let Some((command, whole)) = whole {
+            reassembled = Some((command, whole.to_vec()));
+        }
+    });
+    assert_eq!(reassembled, Some((0x8A, message)));
+}
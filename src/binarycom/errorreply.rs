@@ -0,0 +1,149 @@
+//! Error replies: a device rejecting a request
+//!
+//! A device that can't carry out a request answers CMD_ERROR instead of the usual reply, with the
+//! data [command of the request][tag][error code][register number high byte][low byte]. The tag
+//! is the request's on tagged requests and 0 otherwise, and the register number is 0 for
+//! requests that aren't about a register. The host hands the error to the request waiting for the
+//! reply, which fails with SerialComError::DeviceError.
+
+use crate::error::{SerialComError, SerialComResult};
+
+/// Error reply to a request
+pub const CMD_ERROR: u8 = 7;
+
+/// Length of the packed ErrorReply
+pub const ERROR_REPLY_LEN: usize = 5;
+
+/// Why a device rejected a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceErrorCode {
+    /// No register with that number
+    UnknownRegister,
+    /// The register can't be written
+    ReadOnly,
+    /// The value is out of the register's range
+    OutOfRange,
+    /// The device can't carry out the request right now
+    Busy,
+    /// The device doesn't know the request's command
+    UnsupportedCommand,
+    /// A code this crate doesn't know, e.g. from a newer device
+    Other(u8),
+}
+
+impl DeviceErrorCode {
+    /// Code sent in error replies
+    pub fn id(self) -> u8 {
+        match self {
+            DeviceErrorCode::UnknownRegister => 1,
+            DeviceErrorCode::ReadOnly => 2,
+            DeviceErrorCode::OutOfRange => 3,
+            DeviceErrorCode::Busy => 4,
+            DeviceErrorCode::UnsupportedCommand => 5,
+            DeviceErrorCode::Other(id) => id,
+        }
+    }
+
+    /// The DeviceErrorCode sent as id
+    pub fn from_id(id: u8) -> DeviceErrorCode {
+        match id {
+            1 => DeviceErrorCode::UnknownRegister,
+            2 => DeviceErrorCode::ReadOnly,
+            3 => DeviceErrorCode::OutOfRange,
+            4 => DeviceErrorCode::Busy,
+            5 => DeviceErrorCode::UnsupportedCommand,
+            id => DeviceErrorCode::Other(id),
+        }
+    }
+}
+
+impl core::fmt::Display for DeviceErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            DeviceErrorCode::UnknownRegister => write!(f, "unknown register"),
+            DeviceErrorCode::ReadOnly => write!(f, "read-only register"),
+            DeviceErrorCode::OutOfRange => write!(f, "value out of range"),
+            DeviceErrorCode::Busy => write!(f, "device busy"),
+            DeviceErrorCode::UnsupportedCommand => write!(f, "unsupported command"),
+            DeviceErrorCode::Other(id) => write!(f, "error code {}", id),
+        }
+    }
+}
+
+/// What a device sends in an error reply
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorReply {
+    /// Command of the rejected request
+    pub command: u8,
+    /// Tag of the rejected request, if it was tagged
+    pub tag: u8,
+    pub code: DeviceErrorCode,
+    pub reg_num: u16,
+}
+
+impl ErrorReply {
+    /// Pack into data
+    ///
+    /// returns Result with length of data
+    pub fn pack(&self, data: &mut [u8]) -> SerialComResult<u8> {
+        if data.len() < ERROR_REPLY_LEN {
+            return Err(SerialComError::SliceTooSmall);
+        }
+        let reg_num = self.reg_num.to_be_bytes();
+        data[..ERROR_REPLY_LEN].copy_from_slice(&[
+            self.command,
+            self.tag,
+            self.code.id(),
+            reg_num[0],
+            reg_num[1],
+        ]);
+        Ok(ERROR_REPLY_LEN as u8)
+    }
+
+    /// Unpack from the data of an error reply
+    pub fn unpack(data: &[u8]) -> SerialComResult<ErrorReply> {
+        if data.len() < ERROR_REPLY_LEN {
+            return Err(SerialComError::SliceTooSmall);
+        }
+        Ok(ErrorReply {
+            command: data[0],
+            tag: data[1],
+            code: DeviceErrorCode::from_id(data[2]),
+            reg_num: u16::from_be_bytes([data[3], data[4]]),
+        })
+    }
+}
+
+impl From<ErrorReply> for SerialComError {
+    fn from(reply: ErrorReply) -> SerialComError {
+        SerialComError::DeviceError {
+            code: reply.code,
+            reg_num: reply.reg_num,
+        }
+    }
+}
+
+#[test]
+fn test_error_reply_pack_unpack() {
+    let reply = ErrorReply {
+        command: 4,
+        tag: 9,
+        code: DeviceErrorCode::ReadOnly,
+        reg_num: 0x1234,
+    };
+    let mut data: [u8; 6] = [0; 6];
+    assert_eq!(reply.pack(&mut data).unwrap(), 5);
+    assert_eq!(data[..5], [4, 9, 2, 0x12, 0x34]);
+    assert_eq!(ErrorReply::unpack(&data[..5]).unwrap(), reply);
+    ErrorReply::unpack(&data[..4]).expect_err("Should be error, too short");
+    for id in 0..=255u8 {
+        assert_eq!(DeviceErrorCode::from_id(id).id(), id);
+    }
+    match SerialComError::from(reply) {
+        SerialComError::DeviceError {
+            code: DeviceErrorCode::ReadOnly,
+            reg_num: 0x1234,
+        } => {}
+        _ => panic!("Expected DeviceError"),
+    }
+}
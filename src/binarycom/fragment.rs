@@ -0,0 +1,241 @@
+//! Fragmentation and reassembly of messages longer than one frame
+//!
+//! A long message is sent as a run of CMD_FRAGMENT messages, each with the data
+//! [message id][fragment index][fragment count][command][part of the message data]. The receiver
+//! collects the parts in a Reassembler and gets back the command and the whole data. Fragments
+//! must arrive in order; a message missing a fragment, or not completed within the reassembly
+//! timeout, is dropped.
+//!
+//! A device that said CAP_FRAGMENTS in the hello handshake takes fragments from the host, see
+//! BinaryComApp::send_long, and sends long stream messages as fragments, see
+//! DeviceResponder::stream_long. Both size the fragments to the LinkConfig, see frame_data_len.
+//!
+//! Up to 255 fragments make one message, so on a link of 16 byte buffers a message carries up to
+//! 255 * 7 bytes of data.
+
+use crate::error::{SerialComError, SerialComResult};
+
+use core::convert::TryFrom;
+use core::time::Duration;
+
+/// One part of a long message
+pub const CMD_FRAGMENT: u8 = 0x7D;
+
+/// Bytes of each fragment's data taken by the message id, fragment index, fragment count and
+/// command
+pub const FRAGMENT_HEADER_LEN: usize = 4;
+
+/// Splits a long message into fragments
+///
+/// Call next_into, or BinaryCom::send_fragment or send_fragment_with, until it returns None.
+#[derive(Clone, Debug)]
+pub struct Fragmenter<'a> {
+    id: u8,
+    command: u8,
+    data: &'a [u8],
+    chunk_len: usize,
+    count: u8,
+    index: u8,
+}
+
+impl<'a> Fragmenter<'a> {
+    /// Fragments of the message command and data, each at most max_data_len bytes of message
+    /// data
+    ///
+    /// max_data_len is usually the link's BinaryCom::MAX_DATA_LEN. id tells this message's
+    /// fragments from those of the one before, so it should change from message to message.
+    pub fn new(
+        id: u8,
+        command: u8,
+        data: &'a [u8],
+        max_data_len: usize,
+    ) -> SerialComResult<Fragmenter<'a>> {
+        let chunk_len = max_data_len
+            .checked_sub(FRAGMENT_HEADER_LEN)
+            .filter(|chunk_len| *chunk_len > 0)
+            .ok_or(SerialComError::SliceTooSmall)?;
+        let count = data.len().div_ceil(chunk_len).max(1);
+        let count = u8::try_from(count).map_err(|_| SerialComError::SliceTooBig)?;
+        Ok(Fragmenter {
+            id,
+            command,
+            data,
+            chunk_len,
+            count,
+            index: 0,
+        })
+    }
+
+    /// Number of fragments the message is split into
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    /// Put the data of the next fragment in data
+    ///
+    /// Returns its length, or None once every fragment has been given.
+    pub fn next_into(&mut self, data: &mut [u8]) -> SerialComResult<Option<usize>> {
+        if self.index == self.count {
+            return Ok(None);
+        }
+        let start = usize::from(self.index) * self.chunk_len;
+        let chunk = &self.data[start..self.data.len().min(start + self.chunk_len)];
+        let data_len = FRAGMENT_HEADER_LEN + chunk.len();
+        if data.len() < data_len {
+            return Err(SerialComError::SliceTooSmall);
+        }
+        data[..FRAGMENT_HEADER_LEN].copy_from_slice(&[
+            self.id,
+            self.index,
+            self.count,
+            self.command,
+        ]);
+        data[FRAGMENT_HEADER_LEN..data_len].copy_from_slice(chunk);
+        self.index += 1;
+        Ok(Some(data_len))
+    }
+}
+
+/// The message a Reassembler is collecting
+#[derive(Clone, Copy)]
+struct Partial {
+    id: u8,
+    command: u8,
+    count: u8,
+    next_index: u8,
+    started: Duration,
+}
+
+/// Collects fragments back into messages of up to N bytes of data
+///
+/// Times are passed in as the time since any fixed instant, e.g. from a tick counter, so this
+/// works without std.
+pub struct Reassembler<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    partial: Option<Partial>,
+    timeout: Duration,
+}
+
+impl<const N: usize> Reassembler<N> {
+    /// Drops messages whose last fragment comes more than timeout after their first
+    pub fn new(timeout: Duration) -> Reassembler<N> {
+        Reassembler {
+            buf: [0; N],
+            len: 0,
+            partial: None,
+            timeout,
+        }
+    }
+
+    /// Take the data of a CMD_FRAGMENT message, received at time now
+    ///
+    /// Returns the command and data of the whole message once its last fragment is in. A
+    /// fragment that doesn't follow the one before gives FragmentOutOfOrder, and one that comes
+    /// too late FragmentTimeout; the partial message is dropped either way.
+    pub fn push(&mut self, data: &[u8], now: Duration) -> SerialComResult<Option<(u8, &[u8])>> {
+        if data.len() < FRAGMENT_HEADER_LEN {
+            return Err(SerialComError::COBSTooLittleData);
+        }
+        let (id, index, count, command) = (data[0], data[1], data[2], data[3]);
+        let chunk = &data[FRAGMENT_HEADER_LEN..];
+        if index == 0 {
+            self.len = 0;
+            self.partial = Some(Partial {
+                id,
+                command,
+                count,
+                next_index: 0,
+                started: now,
+            });
+        }
+        let partial = match self.partial.take() {
+            Some(partial)
+                if partial.id == id
+                    && partial.command == command
+                    && partial.count == count
+                    && partial.next_index == index
+                    && index < count =>
+            {
+                partial
+            }
+            _ => return Err(SerialComError::FragmentOutOfOrder),
+        };
+        if now.saturating_sub(partial.started) > self.timeout {
+            return Err(SerialComError::FragmentTimeout);
+        }
+        if self.len + chunk.len() > N {
+            return Err(SerialComError::SliceTooSmall);
+        }
+        self.buf[self.len..self.len + chunk.len()].copy_from_slice(chunk);
+        self.len += chunk.len();
+        if index + 1 == count {
+            return Ok(Some((command, &self.buf[..self.len])));
+        }
+        self.partial = Some(Partial {
+            next_index: index + 1,
+            ..partial
+        });
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_fragment_round_trip() {
+    let message: Vec<u8> = (0..100).collect();
+    let mut reassembler = Reassembler::<128>::new(Duration::from_millis(10));
+    for data_len in [0usize, 1, 7, 8, 99, 100].iter() {
+        let mut fragmenter = Fragmenter::new(5, 0x85, &message[..*data_len], 11).unwrap();
+        assert_eq!(usize::from(fragmenter.count()), data_len.div_ceil(7).max(1));
+        let mut data: [u8; 11] = [0; 11];
+        let mut reassembled: Option<(u8, Vec<u8>)> = None;
+        while let Some(fragment_len) = fragmenter.next_into(&mut data).unwrap() {
+            assert!(reassembled.is_none());
+            reassembled = reassembler
+                .push(&data[..fragment_len], Duration::from_millis(1))
+                .unwrap()
+                .map(|(command, data)| (command, data.to_vec()));
+        }
+        assert_eq!(reassembled, Some((0x85, message[..*data_len].to_vec())));
+    }
+    Fragmenter::new(0, 0x85, &[0; 256 * 7], 11).expect_err("Should be error, too many fragments");
+}
+
+#[test]
+fn test_reassembler_drops_bad_messages() {
+    let message: Vec<u8> = (0..30).collect();
+    let mut fragments: Vec<Vec<u8>> = Vec::new();
+    let mut fragmenter = Fragmenter::new(1, 0x90, &message, 11).unwrap();
+    let mut data: [u8; 11] = [0; 11];
+    while let Some(fragment_len) = fragmenter.next_into(&mut data).unwrap() {
+        fragments.push(data[..fragment_len].to_vec());
+    }
+    let now = Duration::from_secs(1);
+    let mut reassembler = Reassembler::<64>::new(Duration::from_millis(10));
+    // Missing fragment
+    reassembler.push(&fragments[0], now).unwrap();
+    match reassembler.push(&fragments[2], now) {
+        Err(SerialComError::FragmentOutOfOrder) => {}
+        _ => panic!("Expected FragmentOutOfOrder"),
+    }
+    reassembler
+        .push(&fragments[3], now)
+        .expect_err("Should be error, message was dropped");
+    // Too slow
+    reassembler.push(&fragments[0], now).unwrap();
+    match reassembler.push(&fragments[1], now + Duration::from_millis(11)) {
+        Err(SerialComError::FragmentTimeout) => {}
+        _ => panic!("Expected FragmentTimeout"),
+    }
+    // Too long for the buffer
+    let mut reassembler = Reassembler::<16>::new(Duration::from_millis(10));
+    reassembler.push(&fragments[0], now).unwrap();
+    reassembler.push(&fragments[1], now).unwrap();
+    reassembler
+        .push(&fragments[2], now)
+        .expect_err("Should be error, buffer too small");
+    // A new first fragment starts over
+    reassembler.push(&fragments[0], now).unwrap();
+    reassembler.push(&fragments[0], now).unwrap();
+    reassembler.push(&fragments[1], now).unwrap();
+}
@@ -0,0 +1,135 @@
+//! Hello handshake: what a device supports
+//!
+//! The host sends CMD_HELLO with data [major][minor], its protocol version. The device answers
+//! CMD_HELLO with its DeviceInfo:
+//! [major][minor][max data length high byte][low byte][register width in bits][checksum id]
+//! [capabilities]. A host and device are compatible when their major versions match.
+//!
+//! The hello is framed and checked like any other message, so both ends must already agree on
+//! the LinkConfig; the checksum in the reply only confirms it.
+
+use crate::binarycom::RegisterBitWidth;
+use crate::crc::Checksum;
+use crate::error::{SerialComError, SerialComResult};
+
+/// Hello request and reply
+pub const CMD_HELLO: u8 = 5;
+
+/// Protocol version spoken by this crate; devices with another major version are incompatible
+pub const PROTOCOL_VERSION_MAJOR: u8 = 1;
+/// 1 added CMD_PING, 2 CMD_ERROR, 3 CMD_READ_REGS
+pub const PROTOCOL_VERSION_MINOR: u8 = 3;
+
+/// Capability bit: tagged register reads and writes (commands 3 and 4)
+pub const CAP_TAGGED: u8 = 1 << 0;
+/// Capability bit: the device sends stream messages (commands 0x80 and up)
+pub const CAP_STREAM: u8 = 1 << 1;
+/// Capability bit: the device sends and takes messages longer than one frame as fragments
+pub const CAP_FRAGMENTS: u8 = 1 << 2;
+/// Capability bit: block reads of consecutive registers (CMD_READ_REGS)
+pub const CAP_BLOCK_READ: u8 = 1 << 3;
+
+/// Length of the packed DeviceInfo
+pub const DEVICE_INFO_LEN: usize = 7;
+
+/// What a device reports in its hello reply
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub version_major: u8,
+    pub version_minor: u8,
+    /// Longest message data the device takes in a request
+    pub max_data_len: u16,
+    pub register_bit_width: RegisterBitWidth,
+    pub checksum: Checksum,
+    /// CAP_* bits
+    pub capabilities: u8,
+}
+
+impl DeviceInfo {
+    /// Pack into data
+    ///
+    /// returns Result with length of data
+    pub fn pack(&self, data: &mut [u8]) -> SerialComResult<u8> {
+        if data.len() < DEVICE_INFO_LEN {
+            return Err(SerialComError::SliceTooSmall);
+        }
+        let width_bits = match self.register_bit_width {
+            RegisterBitWidth::Eight => 8,
+            RegisterBitWidth::ThirtyTwo => 32,
+        };
+        let max_data_len = self.max_data_len.to_be_bytes();
+        data[..DEVICE_INFO_LEN].copy_from_slice(&[
+            self.version_major,
+            self.version_minor,
+            max_data_len[0],
+            max_data_len[1],
+            width_bits,
+            self.checksum.id(),
+            self.capabilities,
+        ]);
+        Ok(DEVICE_INFO_LEN as u8)
+    }
+
+    /// Unpack from the data of a hello reply
+    pub fn unpack(data: &[u8]) -> SerialComResult<DeviceInfo> {
+        if data.len() < DEVICE_INFO_LEN {
+            return Err(SerialComError::SliceTooSmall);
+        }
+        let register_bit_width = match data[4] {
+            8 => RegisterBitWidth::Eight,
+            32 => RegisterBitWidth::ThirtyTwo,
+            _ => return Err(SerialComError::InvalidDeviceInfo),
+        };
+        Ok(DeviceInfo {
+            version_major: data[0],
+            version_minor: data[1],
+            max_data_len: u16::from_be_bytes([data[2], data[3]]),
+            register_bit_width,
+            checksum: Checksum::from_id(data[5]).ok_or(SerialComError::InvalidDeviceInfo)?,
+            capabilities: data[6],
+        })
+    }
+
+    /// Check that the device speaks a protocol version compatible with this crate's
+    pub fn check_version(&self) -> SerialComResult<()> {
+        if self.version_major != PROTOCOL_VERSION_MAJOR {
+            return Err(SerialComError::IncompatibleVersion(
+                self.version_major,
+                self.version_minor,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the device has all of the CAP_* bits in capabilities
+    pub fn supports(&self, capabilities: u8) -> bool {
+        self.capabilities & capabilities == capabilities
+    }
+}
+
+#[test]
+fn test_device_info_pack_unpack() {
+    let info = DeviceInfo {
+        version_major: PROTOCOL_VERSION_MAJOR,
+        version_minor: 3,
+        max_data_len: 0x1234,
+        register_bit_width: RegisterBitWidth::Eight,
+        checksum: Checksum::Crc32c,
+        capabilities: CAP_TAGGED | CAP_FRAGMENTS,
+    };
+    let mut data: [u8; 8] = [0; 8];
+    assert_eq!(info.pack(&mut data).unwrap(), 7);
+    assert_eq!(data[..7], [1, 3, 0x12, 0x34, 8, 4, 0b101]);
+    let unpacked = DeviceInfo::unpack(&data[..7]).unwrap();
+    assert_eq!(unpacked, info);
+    assert!(unpacked.check_version().is_ok());
+    assert!(unpacked.supports(CAP_TAGGED));
+    assert!(!unpacked.supports(CAP_TAGGED | CAP_STREAM));
+    data[0] = 2;
+    match DeviceInfo::unpack(&data[..7]).unwrap().check_version() {
+        Err(SerialComError::IncompatibleVersion(2, 3)) => {}
+        _ => panic!("Expected IncompatibleVersion"),
+    }
+    data[4] = 16;
+    DeviceInfo::unpack(&data[..7]).expect_err("Should be error, no 16 bit registers");
+}
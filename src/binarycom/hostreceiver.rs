@@ -1,65 +1,367 @@
+use crate::binarycom::errorreply::{ErrorReply, CMD_ERROR};
+use crate::binarycom::fragment::{Reassembler, CMD_FRAGMENT};
+use crate::binarycom::hello::{DeviceInfo, CMD_HELLO};
 use crate::binarycom::packers;
-use crate::binarycom::BinaryCom;
-use crate::error::SerialComResult;
+use crate::binarycom::reliable::{Deduplicator, CMD_ACK, CMD_NACK, CMD_SYNC};
+use crate::binarycom::{
+    unpack_frame_headed, LinkConfig, CMD_PING, CMD_READ_REGS, CMD_READ_REG_TAGGED,
+    CMD_WRITE_REG_TAGGED,
+};
+use crate::crc::Checksum;
+use crate::error::{SerialComError, SerialComResult};
+use crate::framer::{CobsFramer, Framer};
+use crate::transport::Transport;
 
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest message the receive thread reassembles from fragments
+const MAX_REASSEMBLED_LEN: usize = 16384;
+
+/// How long after its first fragment a fragmented message must be complete
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reply to a tagged register request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaggedReply {
+    /// Register number and value
+    Read(u16, u32),
+    /// Register number
+    Write(u16),
+}
+
+/// Counts of the frames the receive thread has decoded, for judging the link's health
+#[derive(Debug, Default)]
+pub struct LinkStats {
+    frames_ok: AtomicU64,
+    frames_bad: AtomicU64,
+}
 
-pub struct HostReceiver16 {
+impl LinkStats {
+    /// Frames decoded with a good checksum
+    pub fn frames_ok(&self) -> u64 {
+        self.frames_ok.load(Ordering::Relaxed)
+    }
+    /// Frames that couldn't be decoded or failed their checksum
+    pub fn frames_bad(&self) -> u64 {
+        self.frames_bad.load(Ordering::Relaxed)
+    }
+}
+
+/// Queues for the replies from one device on a bus
+pub struct DeviceQueues {
+    pub rx_reg_read: mpsc::Receiver<Result<(u16, u32), ErrorReply>>,
+    pub rx_reg_write: mpsc::Receiver<Result<u16, ErrorReply>>,
+    pub rx_tagged: mpsc::Receiver<(u8, Result<TaggedReply, ErrorReply>)>,
+    /// First register number and values of block reads
+    pub rx_regs: mpsc::Receiver<Result<(u16, Vec<u32>), ErrorReply>>,
+    pub rx_hello: mpsc::Receiver<DeviceInfo>,
+    /// Nonces of ping replies
+    pub rx_ping: mpsc::Receiver<u8>,
+    /// Starts of the device's sessions, see reliable
+    pub rx_sync: mpsc::Receiver<()>,
+}
+
+/// Host side receive thread for a link of N byte messages
+///
+/// Replies to register reads and writes are queued on rx_reg_read and rx_reg_write, replies to
+/// tagged ones on rx_tagged as (tag, reply), block read replies on rx_regs, hello replies on
+/// rx_hello, and the nonces of ping replies on rx_ping. An error reply from the device is queued
+/// as Err in place of the reply to the request it rejects. Decoded and bad frames are counted in
+/// stats. On a reliable link, ACKs and NACKs are queued on rx_ack as (command, seq), repeated
+/// messages from the device are dropped, and a CMD_SYNC from the device is queued on rx_sync.
+/// Fragmented messages are reassembled and then queued like any other.
+///
+/// On a multi-drop bus, see with_bus, the replies from each device are queued on its queues in
+/// devices instead, and messages from unknown addresses are dropped.
+pub struct HostReceiver<const N: usize> {
     pub rx_thread_handle: thread::JoinHandle<()>,
-    pub rx_reg_read: mpsc::Receiver<(u16, u32)>,
-    pub rx_reg_write: mpsc::Receiver<u16>,
+    pub rx_reg_read: mpsc::Receiver<Result<(u16, u32), ErrorReply>>,
+    pub rx_reg_write: mpsc::Receiver<Result<u16, ErrorReply>>,
+    pub rx_tagged: mpsc::Receiver<(u8, Result<TaggedReply, ErrorReply>)>,
+    pub rx_regs: mpsc::Receiver<Result<(u16, Vec<u32>), ErrorReply>>,
+    pub rx_hello: mpsc::Receiver<DeviceInfo>,
+    pub rx_ping: mpsc::Receiver<u8>,
+    pub rx_sync: mpsc::Receiver<()>,
+    pub rx_ack: mpsc::Receiver<(u8, u8)>,
+    /// Queues of each device on a bus, by address
+    pub devices: HashMap<u8, DeviceQueues>,
+    pub stats: Arc<LinkStats>,
 }
 
-impl HostReceiver16 {
-    /// returns both a HostReceiver16 and rx_stream: the receiver for streaming messages
-    pub fn new() -> (HostReceiver16, mpsc::Receiver<(u8, Vec<u8>)>) {
-        let (mut tx_reg_read, tmp_rx_reg_read) = mpsc::channel();
-        let (mut tx_reg_write, tmp_rx_reg_write) = mpsc::channel();
-        let (mut tx_stream, rx_stream) = mpsc::channel();
+pub type HostReceiver16 = HostReceiver<16>;
+
+/// Where the messages from one device go
+struct Route {
+    tx_reg_read: mpsc::Sender<Result<(u16, u32), ErrorReply>>,
+    tx_reg_write: mpsc::Sender<Result<u16, ErrorReply>>,
+    tx_tagged: mpsc::Sender<(u8, Result<TaggedReply, ErrorReply>)>,
+    tx_regs: mpsc::Sender<Result<(u16, Vec<u32>), ErrorReply>>,
+    tx_hello: mpsc::Sender<DeviceInfo>,
+    tx_ping: mpsc::Sender<u8>,
+    tx_sync: mpsc::Sender<()>,
+    tx_stream: mpsc::Sender<(u8, Vec<u8>)>,
+    rx_seqs: Deduplicator,
+    reassembler: Box<Reassembler<MAX_REASSEMBLED_LEN>>,
+}
+
+impl Route {
+    fn new() -> (Route, DeviceQueues, mpsc::Receiver<(u8, Vec<u8>)>) {
+        let (tx_reg_read, rx_reg_read) = mpsc::channel();
+        let (tx_reg_write, rx_reg_write) = mpsc::channel();
+        let (tx_tagged, rx_tagged) = mpsc::channel();
+        let (tx_regs, rx_regs) = mpsc::channel();
+        let (tx_hello, rx_hello) = mpsc::channel();
+        let (tx_ping, rx_ping) = mpsc::channel();
+        let (tx_sync, rx_sync) = mpsc::channel();
+        let (tx_stream, rx_stream) = mpsc::channel();
+        let route = Route {
+            tx_reg_read,
+            tx_reg_write,
+            tx_tagged,
+            tx_regs,
+            tx_hello,
+            tx_ping,
+            tx_sync,
+            tx_stream,
+            rx_seqs: Deduplicator::default(),
+            reassembler: Box::new(Reassembler::new(REASSEMBLY_TIMEOUT)),
+        };
+        let queues = DeviceQueues {
+            rx_reg_read,
+            rx_reg_write,
+            rx_tagged,
+            rx_regs,
+            rx_hello,
+            rx_ping,
+            rx_sync,
+        };
+        (route, queues, rx_stream)
+    }
+
+    /// Queue a message, once reassembled if it's a fragment
+    fn route(&mut self, command: u8, data: &[u8], now: Duration) -> SerialComResult<()> {
+        let whole = if command == CMD_FRAGMENT {
+            self.reassembler.push(data, now)?
+        } else {
+            Some((command, data))
+        };
+        match whole {
+            Some((command, data)) => message_router(
+                command,
+                data,
+                &mut self.tx_reg_read,
+                &mut self.tx_reg_write,
+                &mut self.tx_tagged,
+                &mut self.tx_regs,
+                &mut self.tx_hello,
+                &mut self.tx_ping,
+                &mut self.tx_sync,
+                &mut self.tx_stream,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<const N: usize> HostReceiver<N> {
+    /// returns both a HostReceiver and rx_stream: the receiver for streaming messages
+    ///
+    /// The receive thread reads bytes from transport until it returns an error.
+    pub fn new(transport: Box<dyn Transport>) -> (HostReceiver<N>, mpsc::Receiver<(u8, Vec<u8>)>) {
+        HostReceiver::with_framer(transport, CobsFramer::<N>::default(), Checksum::Crc16Dnp)
+    }
+
+    /// Like new, for a link framed and checked as link says
+    pub fn with_link(
+        transport: Box<dyn Transport>,
+        link: LinkConfig,
+    ) -> (HostReceiver<N>, mpsc::Receiver<(u8, Vec<u8>)>) {
+        let (receiver, mut rx_streams) = HostReceiver::start(
+            transport,
+            link.framing.new_framer::<N>(),
+            link.checksum,
+            link.reliable,
+            None,
+        );
+        (receiver, rx_streams.remove(&None).expect("No stream route"))
+    }
+
+    /// Like with_link, for a multi-drop bus of devices at addresses
+    ///
+    /// Each message starts with the address of the device it's to or from, see
+    /// DeviceResponder::set_address. Returns the receiver for the streaming messages of each
+    /// device, by address.
+    #[allow(clippy::type_complexity)]
+    pub fn with_bus(
+        transport: Box<dyn Transport>,
+        link: LinkConfig,
+        addresses: &[u8],
+    ) -> (HostReceiver<N>, HashMap<u8, mpsc::Receiver<(u8, Vec<u8>)>>) {
+        let (receiver, rx_streams) = HostReceiver::start(
+            transport,
+            link.framing.new_framer::<N>(),
+            link.checksum,
+            link.reliable,
+            Some(addresses),
+        );
+        let rx_streams = rx_streams
+            .into_iter()
+            .filter_map(|(address, rx_stream)| address.map(|address| (address, rx_stream)))
+            .collect();
+        (receiver, rx_streams)
+    }
+
+    /// Like new, for a link that uses framer and checksum instead of COBS and CRC-16/DNP
+    pub fn with_framer<F: Framer + Send + 'static>(
+        transport: Box<dyn Transport>,
+        framer: F,
+        checksum: Checksum,
+    ) -> (HostReceiver<N>, mpsc::Receiver<(u8, Vec<u8>)>) {
+        let (receiver, mut rx_streams) =
+            HostReceiver::start(transport, framer, checksum, false, None);
+        (receiver, rx_streams.remove(&None).expect("No stream route"))
+    }
+
+    /// Start the receive thread, with a route for each address on a bus or one route keyed
+    /// None otherwise
+    #[allow(clippy::type_complexity)]
+    fn start<F: Framer + Send + 'static>(
+        mut transport: Box<dyn Transport>,
+        mut framer: F,
+        checksum: Checksum,
+        reliable: bool,
+        addresses: Option<&[u8]>,
+    ) -> (
+        HostReceiver<N>,
+        HashMap<Option<u8>, mpsc::Receiver<(u8, Vec<u8>)>>,
+    ) {
+        let keys: Vec<Option<u8>> = match addresses {
+            Some(addresses) => addresses.iter().map(|address| Some(*address)).collect(),
+            None => vec![None],
+        };
+        let mut routes: HashMap<Option<u8>, Route> = HashMap::new();
+        let mut devices: HashMap<Option<u8>, DeviceQueues> = HashMap::new();
+        let mut rx_streams = HashMap::new();
+        for key in keys {
+            let (route, queues, rx_stream) = Route::new();
+            routes.insert(key, route);
+            devices.insert(key, queues);
+            rx_streams.insert(key, rx_stream);
+        }
+        // On a bus these queues never get anything
+        let queues = devices.remove(&None).unwrap_or_else(|| Route::new().1);
+        let (tx_ack, tmp_rx_ack) = mpsc::channel();
+        let addressed = addresses.is_some();
+        let header_len = usize::from(addressed) + usize::from(reliable);
+        let stats = Arc::new(LinkStats::default());
+        let thread_stats = stats.clone();
         let thread_handle = thread::spawn(move || {
-            let mut inbuf: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> =
-                arraydeque::ArrayDeque::new();
+            let mut readbuf: [u8; 64] = [0; 64];
+            let mut header: [u8; 2] = [0; 2];
             let mut command: u8 = 0;
-            let mut data: [u8; 11] = [0; 11];
+            let mut data: [u8; N] = [0; N];
+            let start = Instant::now();
             loop {
-                match inbuf.receive_message(&mut command, &mut data) {
-                    Ok(data_len) => {
-                        if let Err(route_error) = message_router(
-                            command,
-                            &data[0..data_len],
-                            &mut tx_reg_read,
-                            &mut tx_reg_write,
-                            &mut tx_stream,
-                        ) {
-                            println!(
-                                "Error while routing and queuing message from device to host: {}",
-                                route_error
-                            );
+                let n_read = match transport.read(&mut readbuf) {
+                    Ok(n_read) => n_read,
+                    Err(read_error) => {
+                        println!(
+                            "Error while reading from transport, closing receive thread: {}",
+                            read_error
+                        );
+                        return;
+                    }
+                };
+                framer.push_slice(&readbuf[..n_read], |frame| {
+                    let header = &mut header[..header_len];
+                    let unpacked = frame.and_then(|frame| {
+                        unpack_frame_headed(frame, checksum, header, &mut command, &mut data)
+                    });
+                    let data_len = match unpacked {
+                        Ok(data_len) => {
+                            thread_stats.frames_ok.fetch_add(1, Ordering::Relaxed);
+                            data_len
+                        }
+                        Err(recv_error) => {
+                            thread_stats.frames_bad.fetch_add(1, Ordering::Relaxed);
+                            println!("Error while receiving dev -> host message: {}", recv_error);
+                            return;
+                        }
+                    };
+                    let address = if addressed { Some(header[0]) } else { None };
+                    if reliable {
+                        let seq = header[header_len - 1];
+                        if command == CMD_ACK || command == CMD_NACK {
+                            // Nobody waiting on ACKs isn't an error
+                            let _ = tx_ack.send((command, seq));
+                            return;
+                        }
+                    }
+                    let route = match routes.get_mut(&address) {
+                        Some(route) => route,
+                        None => {
+                            println!("Error: message from unknown address {:?}", address);
+                            return;
+                        }
+                    };
+                    if reliable {
+                        if command == CMD_SYNC {
+                            // The device's seqs start over
+                            route.rx_seqs.reset();
+                        }
+                        if !route.rx_seqs.is_new(header[header_len - 1]) {
+                            return;
                         }
                     }
-                    Err(recv_error) => {
-                        println!("Error while receiving dev -> host message: {}", recv_error)
+                    if let Err(route_error) =
+                        route.route(command, &data[..data_len], start.elapsed())
+                    {
+                        println!(
+                            "Error while routing and queuing message from device to host: {}",
+                            route_error
+                        );
                     }
-                }
+                });
             }
         });
+        let devices = devices
+            .into_iter()
+            .filter_map(|(address, queues)| address.map(|address| (address, queues)))
+            .collect();
         (
-            HostReceiver16 {
+            HostReceiver {
                 rx_thread_handle: thread_handle,
-                rx_reg_read: tmp_rx_reg_read,
-                rx_reg_write: tmp_rx_reg_write,
+                rx_reg_read: queues.rx_reg_read,
+                rx_reg_write: queues.rx_reg_write,
+                rx_tagged: queues.rx_tagged,
+                rx_regs: queues.rx_regs,
+                rx_hello: queues.rx_hello,
+                rx_ping: queues.rx_ping,
+                rx_sync: queues.rx_sync,
+                rx_ack: tmp_rx_ack,
+                devices,
+                stats,
             },
-            rx_stream,
+            rx_streams,
         )
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn message_router(
     command: u8,
     data: &[u8],
-    tx_reg_read: &mut mpsc::Sender<(u16, u32)>,
-    tx_reg_write: &mut mpsc::Sender<u16>,
+    tx_reg_read: &mut mpsc::Sender<Result<(u16, u32), ErrorReply>>,
+    tx_reg_write: &mut mpsc::Sender<Result<u16, ErrorReply>>,
+    tx_tagged: &mut mpsc::Sender<(u8, Result<TaggedReply, ErrorReply>)>,
+    tx_regs: &mut mpsc::Sender<Result<(u16, Vec<u32>), ErrorReply>>,
+    tx_hello: &mut mpsc::Sender<DeviceInfo>,
+    tx_ping: &mut mpsc::Sender<u8>,
+    tx_sync: &mut mpsc::Sender<()>,
     tx_stream: &mut mpsc::Sender<(u8, Vec<u8>)>,
 ) -> SerialComResult<()> {
     match command {
@@ -69,14 +371,52 @@ fn message_router(
         1u8 => {
             // read register
             let (reg_num, reg_val) = packers::host_read_reg_unpack(data)?;
-            tx_reg_read.send((reg_num, reg_val))?;
+            tx_reg_read.send(Ok((reg_num, reg_val)))?;
         }
         2u8 => {
             // write register
             let reg_num = packers::host_write_reg_unpack(data)?;
-            tx_reg_write.send(reg_num)?;
+            tx_reg_write.send(Ok(reg_num))?;
+        }
+        CMD_READ_REG_TAGGED => {
+            let tag = *data.first().ok_or(SerialComError::SliceTooSmall)?;
+            let (reg_num, reg_val) = packers::host_read_reg_unpack(&data[1..])?;
+            tx_tagged.send((tag, Ok(TaggedReply::Read(reg_num, reg_val))))?;
+        }
+        CMD_WRITE_REG_TAGGED => {
+            let tag = *data.first().ok_or(SerialComError::SliceTooSmall)?;
+            let reg_num = packers::host_write_reg_unpack(&data[1..])?;
+            tx_tagged.send((tag, Ok(TaggedReply::Write(reg_num))))?;
+        }
+        CMD_HELLO => {
+            tx_hello.send(DeviceInfo::unpack(data)?)?;
+        }
+        CMD_PING => {
+            tx_ping.send(*data.first().ok_or(SerialComError::SliceTooSmall)?)?;
         }
-        0x3u8..=0x7Fu8 => {
+        CMD_READ_REGS => {
+            tx_regs.send(Ok(packers::host_read_regs_unpack(data)?))?;
+        }
+        CMD_ERROR => {
+            let error_reply = ErrorReply::unpack(data)?;
+            match error_reply.command {
+                1u8 => tx_reg_read.send(Err(error_reply))?,
+                2u8 => tx_reg_write.send(Err(error_reply))?,
+                CMD_READ_REGS => tx_regs.send(Err(error_reply))?,
+                CMD_READ_REG_TAGGED | CMD_WRITE_REG_TAGGED => {
+                    tx_tagged.send((error_reply.tag, Err(error_reply)))?
+                }
+                _ => println!(
+                    "Error: device rejected command 0x{:02X}: {}",
+                    error_reply.command, error_reply.code
+                ),
+            }
+        }
+        CMD_SYNC => {
+            // Nobody waiting on syncs isn't an error
+            let _ = tx_sync.send(());
+        }
+        0x9u8..=0x7Fu8 => {
             println!("Error: unexpected command received: 0x{:02X}", command);
         }
         0x80u8..=0xFFu8 => {
@@ -85,3 +425,65 @@ fn message_router(
     }
     Ok(())
 }
+
+#[cfg(test)]
+use crate::binarycom::fragment::Fragmenter;
+#[cfg(test)]
+use crate::binarycom::send_message_framed;
+#[cfg(test)]
+use crate::binarycom::BinaryCom;
+#[cfg(test)]
+use crate::framer::SlipFramer;
+#[cfg(test)]
+use crate::transport::MemoryTransport;
+
+#[test]
+fn test_host_receiver_slip() {
+    let (host, mut dev) = MemoryTransport::pair();
+    let (receiver, rx_stream) =
+        HostReceiver16::with_framer(Box::new(host), SlipFramer::<16>::default(), Checksum::Crc8);
+    let framer = SlipFramer::<16>::default();
+    let mut frame: [u8; 32] = [0; 32];
+    let mut data: [u8; 6] = [0; 6];
+    packers::dev_read_reg32_pack(0xC0, 0xDBC0_7E00, &mut data).unwrap();
+    let frame_len = send_message_framed(&framer, Checksum::Crc8, 1, &data, &mut frame).unwrap();
+    dev.write(&frame[..frame_len]).unwrap();
+    let frame_len =
+        send_message_framed(&framer, Checksum::Crc8, 0x8C, &[0xC0, 0xDB], &mut frame).unwrap();
+    dev.write(&frame[..frame_len]).unwrap();
+    dev.flush().unwrap();
+    assert_eq!(
+        receiver
+            .rx_reg_read
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap(),
+        Ok((0xC0, 0xDBC0_7E00))
+    );
+    assert_eq!(
+        rx_stream.recv_timeout(Duration::from_secs(1)).unwrap(),
+        (0x8C, vec![0xC0, 0xDB])
+    );
+}
+
+#[test]
+fn test_host_receiver_reassembles() {
+    let (host, mut dev) = MemoryTransport::pair();
+    let (_receiver, rx_stream) = HostReceiver16::new(Box::new(host));
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let block: Vec<u8> = (0..200u8).collect();
+    for id in 0..2u8 {
+        let mut fragmenter = Fragmenter::new(id, 0x85, &block, 11).unwrap();
+        while buf.send_fragment(&mut fragmenter).unwrap().is_some() {
+            let frame: Vec<u8> = buf.iter().copied().collect();
+            dev.write(&frame).unwrap();
+        }
+    }
+    dev.flush().unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            rx_stream.recv_timeout(Duration::from_secs(1)).unwrap(),
+            (0x85, block.clone())
+        );
+    }
+}
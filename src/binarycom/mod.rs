@@ -1,25 +1,96 @@
+#[cfg(feature = "std")]
 pub mod app;
+pub mod device;
+pub mod errorreply;
+pub mod fragment;
+pub mod hello;
+#[cfg(feature = "std")]
 pub mod hostreceiver;
 pub mod packers;
+pub mod reliable;
+#[cfg(feature = "std")]
+pub mod supervisor;
 
 //use crate::circbuf::CircBufExt;
 #[cfg(test)]
 use crate::circbuf::CircBufExt;
-use crate::cobs::COBSExt;
-use crate::crc::CRCExt;
+use crate::cobs::{COBSExt, COBSVariant};
+use crate::crc::{compute_crc_slices, CRCExt, Checksum};
 use crate::error::{SerialComError, SerialComResult};
+use crate::framer::{Framer, Framing};
+use fragment::{Fragmenter, CMD_FRAGMENT};
 
 #[cfg(test)]
 use rand::prelude::*;
 
-/// Meant to be used as methods on arraydeque::ArrayDeque<[u8; N], arraydeque::Wrapping>
+/// Register read with a tag: [tag][register number], answered with [tag][read reply data]
+///
+/// The tag lets the host match replies to requests with many of them in flight.
+pub const CMD_READ_REG_TAGGED: u8 = 3;
+/// Register write with a tag: [tag][write data], answered with [tag][write reply data]
+pub const CMD_WRITE_REG_TAGGED: u8 = 4;
+/// Keepalive: [nonce], answered with the same
+pub const CMD_PING: u8 = 6;
+/// Block read: [first register number high byte][low byte][count], answered with
+/// [first register number high byte][low byte][count][values], each value as wide as a register
+pub const CMD_READ_REGS: u8 = 8;
+/// Most data a block read reply carries, whatever the link's message size, as a reply's length
+/// must fit a byte
+pub const MAX_READ_REGS_LEN: usize = 255;
+
+/// Address that every device on a bus carries out, without replying
+pub const BROADCAST_ADDRESS: u8 = 0xFF;
+
+/// Width of device register values sent over the link
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterBitWidth {
+    Eight,
+    ThirtyTwo,
+}
+
+/// How messages are framed and checked on one link
+///
+/// Both ends of a link must use the same LinkConfig. The default, COBS with CRC-16/DNP and no
+/// reliability layer, is what send_message and receive_message use.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConfig {
+    pub framing: Framing,
+    pub checksum: Checksum,
+    /// Sequence numbers, ACK/NACK and retransmission, see reliable
+    pub reliable: bool,
+}
+
+/// Max data length of one message in an n byte buffer, on the default link
+///
+/// BinaryCom::MAX_DATA_LEN of an ArrayDeque of n bytes. The host and device sides size their
+/// buffers from this, so ones built for the same n fit each other.
+pub const fn max_data_len(n: usize) -> usize {
+    n - 5 - (n - 2) / 255
+}
+
+/// Max data length of one message in an n byte frame, with header_len bytes before the command
+/// (see send_message_headed) and a checksum trailer
+///
+/// Like max_data_len for other LinkConfigs, leaving one byte of framing overhead as COBS does.
+pub fn frame_data_len(n: usize, header_len: usize, checksum: Checksum) -> usize {
+    // Less the command and the framing overhead
+    n.saturating_sub(3 + header_len + checksum.trailer_len())
+}
+
+/// Meant to be used as methods on arraydeque::ArrayDeque<u8, N, arraydeque::Wrapping>
 pub trait BinaryCom {
+    /// Max data length of one message
+    ///
+    /// That is the max message size - 5: 1 byte overhead, 1 byte comma, 1 byte command, 2 bytes
+    /// checksum. Buffers over 259 bytes lose one more byte per 254 to COBS overhead. This is for
+    /// the default link; other LinkConfigs have other overheads.
+    const MAX_DATA_LEN: usize;
+
     /// Put a message in output buffer
     ///
     /// Do appropriate formatting, byte stuffing, checksum, etc.
     ///
-    /// The max data buffer size is the max message size - 5. That is 1 byte overhead, 1 byte comma,
-    /// 1 byte command, 2 bytes checksum.
+    /// The max data buffer size is MAX_DATA_LEN.
     ///
     /// Returns final message length
     fn send_message(&mut self, command: &u8, data: &[u8]) -> SerialComResult<usize>;
@@ -28,12 +99,60 @@ pub trait BinaryCom {
     ///
     /// Do appropriate formatting, byte stuffing, checksum, etc.
     ///
-    /// The data buffer must be the max message size - 5. That is 1 byte overhead, 1 byte comma,
-    /// 1 byte command, 2 bytes checksum.
+    /// The data buffer must be at least MAX_DATA_LEN long.
     ///
     /// Returns the received data length
     fn receive_message(&mut self, command: &mut u8, data: &mut [u8]) -> SerialComResult<usize>;
 
+    /// Put a message in output buffer, framed by framer instead of COBS and checked by checksum
+    /// instead of CRC-16/DNP
+    ///
+    /// Returns final message length
+    fn send_message_with<F: Framer + ?Sized>(
+        &mut self,
+        framer: &F,
+        checksum: Checksum,
+        command: &u8,
+        data: &[u8],
+    ) -> SerialComResult<usize>;
+
+    /// Read message from input buffer, framed by framer instead of COBS and checked by checksum
+    /// instead of CRC-16/DNP
+    ///
+    /// Bytes are taken from the buffer until framer completes a frame. Without a complete frame
+    /// in the buffer, they stay in framer for the next call and this returns
+    /// COBSDecodeNoCommaFound.
+    ///
+    /// Returns the received data length
+    fn receive_message_with<F: Framer + ?Sized>(
+        &mut self,
+        framer: &mut F,
+        checksum: Checksum,
+        command: &mut u8,
+        data: &mut [u8],
+    ) -> SerialComResult<usize>;
+
+    /// Put the next fragment of a long message in output buffer
+    ///
+    /// The fragmenter's max_data_len must be at most MAX_DATA_LEN. See fragment.
+    ///
+    /// Returns final message length, or None once every fragment has been sent
+    fn send_fragment(&mut self, fragmenter: &mut Fragmenter) -> SerialComResult<Option<usize>>;
+
+    /// Put the next fragment of a long message in output buffer, framed by framer instead of COBS
+    /// and checked by checksum instead of CRC-16/DNP
+    ///
+    /// The fragmenter's max_data_len must leave room for the link's overheads, see
+    /// frame_data_len.
+    ///
+    /// Returns final message length, or None once every fragment has been sent
+    fn send_fragment_with<F: Framer + ?Sized>(
+        &mut self,
+        framer: &F,
+        checksum: Checksum,
+        fragmenter: &mut Fragmenter,
+    ) -> SerialComResult<Option<usize>>;
+
     /// Initiate register read
     ///
     /// Meant to be used on host to read a device register
@@ -69,52 +188,38 @@ pub trait BinaryCom {
     }
 }
 
-impl BinaryCom for arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> {
+impl<const N: usize> BinaryCom for arraydeque::ArrayDeque<u8, N, arraydeque::Wrapping> {
+    /// N - 5, less one more byte of COBS overhead for each 254 bytes past that
+    const MAX_DATA_LEN: usize = max_data_len(N);
+
     fn send_message(&mut self, command: &u8, data: &[u8]) -> SerialComResult<usize> {
-        if data.len() > 16 - 5 {
+        if data.len() > Self::MAX_DATA_LEN {
             return Err(SerialComError::SliceTooBig);
         }
+        let mut frame: [u8; N] = [0; N];
+        let frame_len = send_message_into(*command, data, &mut frame)?;
         self.clear();
-        self.push_back(*command);
-        for el in data {
-            self.push_back(*el);
-        }
-        let (crc_high_byte, crc_low_byte) = self.compute_crc_bytes(self.len())?;
-        self.push_back(crc_high_byte);
-        self.push_back(crc_low_byte);
-        self.cobs_encode()?;
+        self.extend(frame[..frame_len].iter().copied());
         Ok(self.len())
     }
     fn receive_message(&mut self, command: &mut u8, data: &mut [u8]) -> SerialComResult<usize> {
-        if data.len() < 16 - 5 {
-            println!("receive_message: data len: {}", data.len());
+        if data.len() < Self::MAX_DATA_LEN {
             return Err(SerialComError::SliceTooSmall);
         }
         let msg_chk_size = self.cobs_decode()?;
+        if msg_chk_size < 3 {
+            return Err(SerialComError::COBSTooLittleData);
+        }
         let msg_size = msg_chk_size - 2;
         let data_size = msg_size - 1;
         let (crc_high_byte, crc_low_byte) = self.compute_crc_bytes(msg_size)?;
         *command = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
-        for i in 0..(data_size) {
-            data[i] = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
+        for el in data.iter_mut().take(data_size) {
+            *el = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
         }
         let crc_rec_high_byte = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
         let crc_rec_low_byte = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
-        if crc_high_byte != crc_rec_high_byte {
-            #[cfg(test)]
-            {
-                println!(
-                    "Error CRCMismatch: received: {:02X}{:02X}, computed: {:02X}{:02X}",
-                    crc_rec_high_byte, crc_rec_low_byte, crc_high_byte, crc_low_byte
-                );
-                println!(
-                    "Error for message command: {:02X} data size: {}",
-                    command, data_size
-                )
-            }
-            return Err(SerialComError::CRCMismatch);
-        }
-        if crc_low_byte != crc_rec_low_byte {
+        if crc_high_byte != crc_rec_high_byte || crc_low_byte != crc_rec_low_byte {
             #[cfg(test)]
             {
                 println!(
@@ -130,51 +235,183 @@ impl BinaryCom for arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> {
         }
         Ok(data_size)
     }
-}
-
-impl BinaryCom for arraydeque::ArrayDeque<[u8; 64], arraydeque::Wrapping> {
-    fn send_message(&mut self, command: &u8, data: &[u8]) -> SerialComResult<usize> {
-        if data.len() > 64 - 5 {
-            return Err(SerialComError::SliceTooBig);
-        }
+    fn send_message_with<F: Framer + ?Sized>(
+        &mut self,
+        framer: &F,
+        checksum: Checksum,
+        command: &u8,
+        data: &[u8],
+    ) -> SerialComResult<usize> {
+        let mut frame: [u8; N] = [0; N];
+        let frame_len = send_message_framed(framer, checksum, *command, data, &mut frame)?;
         self.clear();
-        self.push_back(*command);
-        for el in data {
-            self.push_back(*el);
-        }
-        let (crc_high_byte, crc_low_byte) = self.compute_crc_bytes(self.len())?;
-        self.push_back(crc_high_byte);
-        self.push_back(crc_low_byte);
-        self.cobs_encode()?;
+        self.extend(frame[..frame_len].iter().copied());
         Ok(self.len())
     }
-    fn receive_message(&mut self, command: &mut u8, data: &mut [u8]) -> SerialComResult<usize> {
-        if data.len() < 64 - 5 {
-            return Err(SerialComError::SliceTooSmall);
-        }
-        let msg_chk_size = self.cobs_decode()?;
-        let msg_size = msg_chk_size - 2;
-        let data_size = msg_size - 1;
-        let (crc_high_byte, crc_low_byte) = self.compute_crc_bytes(msg_size)?;
-        *command = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
-        for i in 0..(data_size) {
-            data[i] = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
+    fn receive_message_with<F: Framer + ?Sized>(
+        &mut self,
+        framer: &mut F,
+        checksum: Checksum,
+        command: &mut u8,
+        data: &mut [u8],
+    ) -> SerialComResult<usize> {
+        while let Some(byte) = self.pop_front() {
+            if let Some(frame) = framer.push(byte) {
+                return unpack_frame_with(frame?, checksum, command, data);
+            }
         }
-        let crc_rec_high_byte = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
-        let crc_rec_low_byte = self.pop_front().ok_or(SerialComError::QueueIndexingError)?;
-        if crc_high_byte != crc_rec_high_byte {
-            return Err(SerialComError::CRCMismatch);
+        Err(SerialComError::COBSDecodeNoCommaFound)
+    }
+    fn send_fragment(&mut self, fragmenter: &mut Fragmenter) -> SerialComResult<Option<usize>> {
+        let mut data: [u8; N] = [0; N];
+        match fragmenter.next_into(&mut data[..Self::MAX_DATA_LEN])? {
+            Some(data_len) => self
+                .send_message(&CMD_FRAGMENT, &data[..data_len])
+                .map(Some),
+            None => Ok(None),
         }
-        if crc_low_byte != crc_rec_low_byte {
-            return Err(SerialComError::CRCMismatch);
+    }
+    fn send_fragment_with<F: Framer + ?Sized>(
+        &mut self,
+        framer: &F,
+        checksum: Checksum,
+        fragmenter: &mut Fragmenter,
+    ) -> SerialComResult<Option<usize>> {
+        let mut data: [u8; N] = [0; N];
+        match fragmenter.next_into(&mut data)? {
+            Some(data_len) => self
+                .send_message_with(framer, checksum, &CMD_FRAGMENT, &data[..data_len])
+                .map(Some),
+            None => Ok(None),
         }
-        Ok(data_size)
     }
 }
 
+/// Encode a message with a checksum trailer into dst as one frame of framer
+///
+/// Returns final message length
+pub fn send_message_framed<F: Framer + ?Sized>(
+    framer: &F,
+    checksum: Checksum,
+    command: u8,
+    data: &[u8],
+    dst: &mut [u8],
+) -> SerialComResult<usize> {
+    let trailer = checksum.trailer(&[&[command], data]);
+    framer.encode_into(&[&[command], data, &trailer[..checksum.trailer_len()]], dst)
+}
+
+/// Encode a message with header bytes before the command into dst as one frame of framer
+///
+/// The header is the device address on a bus, then the sequence number on a reliable link.
+///
+/// Returns final message length
+pub fn send_message_headed<F: Framer + ?Sized>(
+    framer: &F,
+    checksum: Checksum,
+    header: &[u8],
+    command: u8,
+    data: &[u8],
+    dst: &mut [u8],
+) -> SerialComResult<usize> {
+    let trailer = checksum.trailer(&[header, &[command], data]);
+    framer.encode_into(
+        &[header, &[command], data, &trailer[..checksum.trailer_len()]],
+        dst,
+    )
+}
+
+/// Encode a message into dst as one frame, framed and checked as link says
+///
+/// Returns final message length
+pub fn send_message_link(
+    link: &LinkConfig,
+    command: u8,
+    data: &[u8],
+    dst: &mut [u8],
+) -> SerialComResult<usize> {
+    let trailer = link.checksum.trailer(&[&[command], data]);
+    link.framing.encode_into(
+        &[&[command], data, &trailer[..link.checksum.trailer_len()]],
+        dst,
+    )
+}
+
+/// Encode a message into dst, as BinaryCom::send_message does, in one contiguous slice
+///
+/// For DMA and other writers that can't take a wrapped ArrayDeque.
+///
+/// Returns final message length
+pub fn send_message_into(command: u8, data: &[u8], dst: &mut [u8]) -> SerialComResult<usize> {
+    let crc_num = compute_crc_slices(&[&[command], data])?;
+    let crc_bytes = [(crc_num >> 8) as u8, (crc_num & 0xFF) as u8];
+    let message = core::iter::once(command)
+        .chain(data.iter().copied())
+        .chain(crc_bytes.iter().copied());
+    COBSVariant::Standard.encode_iter_into(message, dst)
+}
+
+/// Read a message from a frame already decoded by a cobs::COBSDecoder
+///
+/// The frame is [command][data][crc high byte][crc low byte]. Checks the CRC-16/DNP and copies the
+/// data part into data.
+///
+/// Returns the received data length
+pub fn unpack_frame(frame: &[u8], command: &mut u8, data: &mut [u8]) -> SerialComResult<usize> {
+    unpack_frame_with(frame, Checksum::Crc16Dnp, command, data)
+}
+
+/// Read a message from a decoded frame, as unpack_frame does, with a checksum trailer of any
+/// kind
+///
+/// Returns the received data length
+pub fn unpack_frame_with(
+    frame: &[u8],
+    checksum: Checksum,
+    command: &mut u8,
+    data: &mut [u8],
+) -> SerialComResult<usize> {
+    if frame.len() < 1 + checksum.trailer_len() {
+        return Err(SerialComError::COBSTooLittleData);
+    }
+    let data_size = frame.len() - 1 - checksum.trailer_len();
+    if data.len() < data_size {
+        return Err(SerialComError::SliceTooSmall);
+    }
+    let message = checksum.check(frame)?;
+    *command = message[0];
+    data[..data_size].copy_from_slice(&message[1..]);
+    Ok(data_size)
+}
+
+/// Read a message with header.len() header bytes before the command from a decoded frame,
+/// checking its checksum
+///
+/// The header bytes are copied into header. Returns the received data length
+pub fn unpack_frame_headed(
+    frame: &[u8],
+    checksum: Checksum,
+    header: &mut [u8],
+    command: &mut u8,
+    data: &mut [u8],
+) -> SerialComResult<usize> {
+    if frame.len() < header.len() + 1 + checksum.trailer_len() {
+        return Err(SerialComError::COBSTooLittleData);
+    }
+    let message = checksum.check(frame)?;
+    let data_size = message.len() - header.len() - 1;
+    if data.len() < data_size {
+        return Err(SerialComError::SliceTooSmall);
+    }
+    header.copy_from_slice(&message[..header.len()]);
+    *command = message[header.len()];
+    data[..data_size].copy_from_slice(&message[header.len() + 1..]);
+    Ok(data_size)
+}
+
 #[test]
 fn test_send() {
-    let mut buf: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> =
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
         arraydeque::ArrayDeque::new();
     let com: u8 = 0x8F;
     let data: [u8; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
@@ -190,14 +427,13 @@ fn test_send() {
 
 #[test]
 fn test_send_rand() {
-    let mut buf: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> =
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
         arraydeque::ArrayDeque::new();
     let mut rng = rand::thread_rng();
     for _trial in 0..1000 {
         let com: u8 = rand::random::<u8>();
         let data_size: usize = rng.gen_range(0, 12);
-        let mut data: Vec<u8> = Vec::new();
-        data.resize(data_size, 0);
+        let mut data: Vec<u8> = vec![0; data_size];
         rng.fill(&mut data[..]);
         buf.send_message(&com, &data)
             .expect("Couldn't send_message");
@@ -223,7 +459,7 @@ fn test_send_rand() {
 
 #[test]
 fn test_receive() {
-    let mut buf: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> =
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
         arraydeque::ArrayDeque::new();
     buf.push_back(8);
     buf.push_back(0);
@@ -246,20 +482,19 @@ fn test_receive() {
         .expect("Couldn't receive_message");
     assert_eq!(n_data, 6);
     assert_eq!(com, 8);
-    for i in 0..6 {
-        assert_eq!(data[i], 0);
+    for el in data.iter().take(6) {
+        assert_eq!(*el, 0);
     }
 }
 
 #[test]
 fn test_receive_rand() {
-    let mut buf: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> =
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
         arraydeque::ArrayDeque::new();
     let mut rng = rand::thread_rng();
     for _trial in 0..1000 {
         let message_size: usize = rng.gen_range(1, 13);
-        let mut message: Vec<u8> = Vec::new();
-        message.resize(message_size, 0);
+        let mut message: Vec<u8> = vec![0; message_size];
         rng.fill(&mut message[..]);
         let corr_com = message[0];
         let corr_data: Vec<u8> = message[1..].to_vec();
@@ -294,10 +529,161 @@ fn test_receive_rand() {
 
 #[test]
 fn test_receive_nothing() {
-    let mut buf: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> =
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
         arraydeque::ArrayDeque::new();
     let mut com: u8 = 3;
     let mut data: [u8; 11] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     buf.receive_message(&mut com, &mut data)
         .expect_err("Should be COBSTooLittleData error!");
 }
+
+#[test]
+fn test_receive_too_short() {
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    // A frame of two bytes, too short for a command and a CRC
+    for byte in [3u8, 5, 6, 0].iter() {
+        buf.push_back(*byte);
+    }
+    let mut com: u8 = 0;
+    let mut data: [u8; 11] = [0; 11];
+    buf.receive_message(&mut com, &mut data)
+        .expect_err("Should be COBSTooLittleData error!");
+}
+
+#[test]
+fn test_unpack_frame() {
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    buf.send_message(&0x8A, &[0, 1, 2, 3]).unwrap();
+    let encoded: Vec<u8> = buf.iter().copied().collect();
+    let mut decoder = crate::cobs::COBSDecoder::new();
+    let mut frame: Vec<u8> = Vec::new();
+    decoder.push_slice(&encoded, |result| frame = result.unwrap().to_vec());
+    let mut command: u8 = 0;
+    let mut data: [u8; 11] = [0; 11];
+    let data_len = unpack_frame(&frame, &mut command, &mut data).unwrap();
+    assert_eq!(command, 0x8A);
+    assert_eq!(data[..data_len], [0, 1, 2, 3]);
+    unpack_frame(&frame, &mut command, &mut data[..2]).expect_err("Should be SliceTooSmall error");
+    frame[2] ^= 0x10;
+    match unpack_frame(&frame, &mut command, &mut data) {
+        Err(SerialComError::CRCMismatch) => {}
+        _ => panic!("Expected CRCMismatch"),
+    }
+}
+
+#[cfg(test)]
+fn check_max_data_len<const N: usize>() {
+    let mut buf: arraydeque::ArrayDeque<u8, N, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let data: Vec<u8> =
+        vec![0xFF; arraydeque::ArrayDeque::<u8, N, arraydeque::Wrapping>::MAX_DATA_LEN];
+    buf.send_message(&0x8F, &data)
+        .expect("Couldn't send_message");
+    let mut command: u8 = 0;
+    let mut data_rec: Vec<u8> = vec![0; data.len()];
+    let data_len = buf
+        .receive_message(&mut command, &mut data_rec)
+        .expect("Couldn't receive_message");
+    assert_eq!(command, 0x8F);
+    assert_eq!(data_rec[..data_len], data[..]);
+    let mut too_big = data.clone();
+    too_big.push(0xFF);
+    buf.send_message(&0x8F, &too_big)
+        .expect_err("Should be SliceTooBig error");
+}
+
+#[test]
+fn test_max_data_len() {
+    assert_eq!(
+        arraydeque::ArrayDeque::<u8, 16, arraydeque::Wrapping>::MAX_DATA_LEN,
+        11
+    );
+    assert_eq!(
+        arraydeque::ArrayDeque::<u8, 64, arraydeque::Wrapping>::MAX_DATA_LEN,
+        59
+    );
+    check_max_data_len::<16>();
+    check_max_data_len::<64>();
+    check_max_data_len::<256>();
+    check_max_data_len::<258>();
+    check_max_data_len::<259>();
+    check_max_data_len::<1024>();
+}
+
+#[test]
+fn test_send_message_into() {
+    let mut buf: arraydeque::ArrayDeque<u8, 64, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let mut dst: [u8; 64] = [0; 64];
+    let data: [u8; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+    let frame_len = send_message_into(0x8F, &data, &mut dst).expect("Couldn't send_message_into");
+    assert_eq!(
+        dst[..frame_len],
+        [2, 0x8F, 12, 1, 2, 3, 4, 5, 6, 7, 8, 9, 68, 78, 0]
+    );
+    for data_len in 0..59 {
+        let data: Vec<u8> = (0..data_len).map(|i| (i % 7) as u8).collect();
+        let frame_len = send_message_into(3, &data, &mut dst).expect("Couldn't send_message_into");
+        buf.send_message(&3, &data).expect("Couldn't send_message");
+        assert!(buf.iter().eq(dst[..frame_len].iter()));
+    }
+    send_message_into(3, &data, &mut dst[..14]).expect_err("Should be error, dst too small");
+}
+
+#[cfg(test)]
+use crate::framer::HdlcFramer;
+
+#[test]
+fn test_send_receive_with_framer() {
+    let mut buf: arraydeque::ArrayDeque<u8, 32, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let mut framer = HdlcFramer::<32>::default();
+    let data: [u8; 4] = [0x7E, 0x7D, 0, 1];
+    buf.send_message_with(&framer, Checksum::Crc32, &0x8F, &data)
+        .expect("Couldn't send_message_with");
+    assert_eq!(buf.front(), Some(&0x7E));
+    assert_eq!(buf.back(), Some(&0x7E));
+    let mut command: u8 = 0;
+    let mut data_rec: [u8; 32] = [0; 32];
+    let data_len = buf
+        .receive_message_with(&mut framer, Checksum::Crc32, &mut command, &mut data_rec)
+        .expect("Couldn't receive_message_with");
+    assert_eq!(command, 0x8F);
+    assert_eq!(data_rec[..data_len], data);
+    buf.receive_message_with(&mut framer, Checksum::Crc32, &mut command, &mut data_rec)
+        .expect_err("Should be error, no frame left");
+}
+
+#[test]
+fn test_send_message_link() {
+    let mut dst: [u8; 64] = [0; 64];
+    let data: [u8; 6] = [0, 0x7E, 0xC0, 0xDB, 0x7D, 0xFF];
+    for framing in ["cobs", "cobsr", "slip", "hdlc"].iter() {
+        for checksum in ["crc16-dnp", "crc16-ccitt-false", "crc32c", "crc8", "none"].iter() {
+            let link = LinkConfig {
+                framing: framing.parse().unwrap(),
+                checksum: checksum.parse().unwrap(),
+                ..LinkConfig::default()
+            };
+            let frame_len = send_message_link(&link, 0x81, &data, &mut dst)
+                .expect("Couldn't send_message_link");
+            let mut framer = link.framing.new_framer::<64>();
+            let mut command: u8 = 0;
+            let mut data_rec: [u8; 64] = [0; 64];
+            let mut data_len: Option<usize> = None;
+            framer.push_slice(&dst[..frame_len], |frame| {
+                data_len = Some(
+                    unpack_frame_with(frame.unwrap(), link.checksum, &mut command, &mut data_rec)
+                        .expect("Couldn't unpack frame"),
+                )
+            });
+            assert_eq!(command, 0x81);
+            assert_eq!(data_rec[..data_len.unwrap()], data);
+        }
+    }
+    "crc64"
+        .parse::<Checksum>()
+        .expect_err("Should be error, unknown checksum");
+}
@@ -1,6 +1,6 @@
 use crate::error::{SerialComError, SerialComResult};
 
-use std::convert::TryFrom;
+use core::convert::TryFrom;
 
 /// Unpack register read message
 ///
@@ -9,7 +9,7 @@ pub fn dev_read_reg_unpack(data: &[u8]) -> SerialComResult<u16> {
     if data.len() < 2 {
         return Err(SerialComError::SliceTooSmall);
     }
-    let reg_num = (u16::from(data[0]) << (8 * 1) & 0xFF) & (u16::from(data[1]) << (8 * 0) & 0xFF);
+    let reg_num = u16::from(data[0]) << 8 | u16::from(data[1]);
     Ok(reg_num)
 }
 
@@ -20,7 +20,7 @@ pub fn dev_write_reg8_unpack(data: &[u8]) -> SerialComResult<(u16, u8)> {
     if data.len() < 3 {
         return Err(SerialComError::SliceTooSmall);
     }
-    let reg_num = (u16::from(data[0]) << (8 * 1) & 0xFF) & (u16::from(data[1]) << (8 * 0) & 0xFF);
+    let reg_num = u16::from(data[0]) << 8 | u16::from(data[1]);
     let reg_val = data[2];
     Ok((reg_num, reg_val))
 }
@@ -31,12 +31,11 @@ pub fn dev_write_reg32_unpack(data: &[u8]) -> SerialComResult<(u16, u32)> {
     if data.len() < 6 {
         return Err(SerialComError::SliceTooSmall);
     }
-    let reg_num: u16 =
-        (u16::from(data[0]) << (8 * 1) & 0xFF) & (u16::from(data[1]) << (8 * 0) & 0xFF);
-    let reg_val: u32 = (u32::from(data[2]) << (8 * 3) & 0xFF)
-        & (u32::from(data[3]) << (8 * 2) & 0xFF)
-        & (u32::from(data[4]) << (8 * 1) & 0xFF)
-        & (u32::from(data[5]) << (8 * 0) & 0xFF);
+    let reg_num: u16 = u16::from(data[0]) << 8 | u16::from(data[1]);
+    let reg_val: u32 = u32::from(data[2]) << (8 * 3)
+        | u32::from(data[3]) << (8 * 2)
+        | u32::from(data[4]) << 8
+        | u32::from(data[5]);
     Ok((reg_num, reg_val))
 }
 
@@ -68,8 +67,8 @@ pub fn dev_read_reg32_pack(reg_num: u16, reg_val: u32, data: &mut [u8]) -> Seria
     data[1] = u8::try_from(reg_num & 0xFF)?;
     data[2] = u8::try_from(reg_val >> (8 * 3) & 0xFF)?;
     data[3] = u8::try_from(reg_val >> (8 * 2) & 0xFF)?;
-    data[4] = u8::try_from(reg_val >> (8 * 1) & 0xFF)?;
-    data[5] = u8::try_from(reg_val >> (8 * 0) & 0xFF)?;
+    data[4] = u8::try_from(reg_val >> 8 & 0xFF)?;
+    data[5] = u8::try_from(reg_val & 0xFF)?;
     Ok(6u8)
 }
 
@@ -87,6 +86,50 @@ pub fn dev_write_reg_pack(reg_num: u16, data: &mut [u8]) -> SerialComResult<u8>
     Ok(2u8)
 }
 
+/// Unpack block read message
+///
+/// Returns result holding (first register number, number of registers)
+pub fn dev_read_regs_unpack(data: &[u8]) -> SerialComResult<(u16, u8)> {
+    if data.len() < 3 {
+        return Err(SerialComError::SliceTooSmall);
+    }
+    Ok((dev_read_reg_unpack(data)?, data[2]))
+}
+
+/// Respond to block read message of 8-bit registers
+///
+/// packs data portion of message: first register number, number of registers, values
+///
+/// returns Result with length of data
+pub fn dev_read_regs8_pack(start: u16, reg_vals: &[u8], data: &mut [u8]) -> SerialComResult<u8> {
+    let data_len = 3 + reg_vals.len();
+    if data.len() < data_len {
+        return Err(SerialComError::SliceTooSmall);
+    }
+    dev_write_reg_pack(start, data)?;
+    data[2] = u8::try_from(reg_vals.len())?;
+    data[3..data_len].copy_from_slice(reg_vals);
+    Ok(u8::try_from(data_len)?)
+}
+
+/// Respond to block read message of 32-bit registers
+///
+/// packs data portion of message: first register number, number of registers, values
+///
+/// returns Result with length of data
+pub fn dev_read_regs32_pack(start: u16, reg_vals: &[u32], data: &mut [u8]) -> SerialComResult<u8> {
+    let data_len = 3 + 4 * reg_vals.len();
+    if data.len() < data_len {
+        return Err(SerialComError::SliceTooSmall);
+    }
+    dev_write_reg_pack(start, data)?;
+    data[2] = u8::try_from(reg_vals.len())?;
+    for (value, reg_val) in data[3..data_len].chunks_mut(4).zip(reg_vals) {
+        value.copy_from_slice(&reg_val.to_be_bytes());
+    }
+    Ok(u8::try_from(data_len)?)
+}
+
 /// Pack a read message
 ///
 /// returns Result with length of data
@@ -108,6 +151,18 @@ pub fn host_write_reg32_pack(reg_num: u16, reg_val: u32, data: &mut [u8]) -> Ser
     dev_read_reg32_pack(reg_num, reg_val, data)
 }
 
+/// Pack a block read message, for count registers from start
+///
+/// returns Result with length of data
+pub fn host_read_regs_pack(start: u16, count: u8, data: &mut [u8]) -> SerialComResult<u8> {
+    if data.len() < 3 {
+        return Err(SerialComError::SliceTooSmall);
+    }
+    dev_write_reg_pack(start, data)?;
+    data[2] = count;
+    Ok(3u8)
+}
+
 pub fn host_write_reg_unpack(data: &[u8]) -> SerialComResult<u16> {
     dev_read_reg_unpack(data)
 }
@@ -123,54 +178,77 @@ pub fn host_read_reg_unpack(data: &[u8]) -> SerialComResult<(u16, u32)> {
     }
 }
 
+/// Unpack a block read reply, of 8 or 32-bit registers
+///
+/// Returns result holding (first register number, register values)
+#[cfg(feature = "std")]
+pub fn host_read_regs_unpack(data: &[u8]) -> SerialComResult<(u16, Vec<u32>)> {
+    let (start, count) = dev_read_regs_unpack(data)?;
+    let values = &data[3..];
+    let reg_vals = if values.len() == usize::from(count) {
+        values.iter().map(|value| u32::from(*value)).collect()
+    } else if values.len() == 4 * usize::from(count) {
+        values
+            .chunks(4)
+            .map(|value| u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+            .collect()
+    } else {
+        return Err(SerialComError::SliceTooSmall);
+    };
+    Ok((start, reg_vals))
+}
+
 /// unpack tx messages
 ///
 /// command unpacking:
 ///
 /// Lowest 3 bits are the word size in bits / 4
 /// Next 3 bits are the number of words in a single sample (for simultaneous measurements)
-/// The top 2 bits are reserved and should be 0
+/// The next bit is reserved and should be 0, and the top bit is always 1 for stream messages
 ///
 /// command = 0x80 means the data is UTF-8 text
+///
+/// Only one word per sample, of 4, 8, 12, 16 or 32 bits, is unpacked; other commands fail with
+/// UnsupportedStreamCommand, and data that isn't a whole number of words with StreamDataLength.
+#[cfg(feature = "std")]
 pub fn unpack_stream(command: u8, data: Vec<u8>) -> SerialComResult<Vec<u32>> {
     let word_size_bits = (command & 0b111) * 4;
-    let n_per_sample_word = command >> 3 & 0x1F;
+    let n_per_sample_word = command >> 3 & 0b111;
     if n_per_sample_word != 1 {
-        unimplemented!("Haven't implemented multiple words per sample");
+        return Err(SerialComError::UnsupportedStreamCommand(command));
     }
     match word_size_bits {
         4 => Ok(data
             .iter()
-            .map(|x| vec![x >> 0xF, x & 0xF])
-            .flatten()
-            .map(|x| u32::from(x))
+            .flat_map(|x| vec![x >> 4, x & 0xF])
+            .map(u32::from)
             .collect()),
         8 => Ok(data.iter().map(|x| u32::from(*x)).collect()),
         12 => {
-            if data.len() % 3 != 0 {
-                panic!("data for 12 bit ints must be a multiple of 3 long!");
+            if !data.len().is_multiple_of(3) {
+                return Err(SerialComError::StreamDataLength);
             }
             let mut result: Vec<u32> = Vec::new();
             for i in (0..data.len()).step_by(3) {
-                let el1 = u32::from(data[i]) << 4 & (u32::from(data[i + 1]) >> 4);
+                let el1 = u32::from(data[i]) << 4 | (u32::from(data[i + 1]) >> 4);
                 result.push(el1);
-                let el2 = (u32::from(data[i + 1]) & 0xF) << 4 & u32::from(data[i + 2]);
+                let el2 = (u32::from(data[i + 1]) & 0xF) << 8 | u32::from(data[i + 2]);
                 result.push(el2);
             }
             Ok(result)
         }
         16 => {
-            if data.len() % 2 != 0 {
-                panic!("data for 16 bit ints must be a multiple of 2 long!");
+            if !data.len().is_multiple_of(2) {
+                return Err(SerialComError::StreamDataLength);
             }
             let datau32s = data.iter().map(|x| u32::from(*x));
             let evens = datau32s.clone().step_by(2);
             let odds = datau32s.skip(1).step_by(2);
-            Ok(evens.zip(odds).map(|(e, o)| e << 8 & o).collect()) // msb first--big-endian
+            Ok(evens.zip(odds).map(|(e, o)| e << 8 | o).collect()) // msb first--big-endian
         }
         32 => {
-            if data.len() % 4 != 0 {
-                panic!("data for 32 bit ints must be a multiple of 4 long!");
+            if !data.len().is_multiple_of(4) {
+                return Err(SerialComError::StreamDataLength);
             }
             let datau32s = data.iter().map(|x| u32::from(*x));
             let d0s = datau32s.clone().step_by(4);
@@ -178,11 +256,62 @@ pub fn unpack_stream(command: u8, data: Vec<u8>) -> SerialComResult<Vec<u32>> {
             let d2s = datau32s.clone().skip(2).step_by(4);
             let d3s = datau32s.clone().skip(3).step_by(4);
             // msb first--big-endian
-            let d01s = d0s.zip(d1s).map(|(d0, d1)| d0 << 8 & d1);
-            let d23s = d2s.zip(d3s).map(|(d0, d1)| d0 << 8 & d1);
-            let d0123s = d01s.zip(d23s).map(|(d01, d12)| d01 << 16 & d12);
+            let d01s = d0s.zip(d1s).map(|(d0, d1)| d0 << 8 | d1);
+            let d23s = d2s.zip(d3s).map(|(d0, d1)| d0 << 8 | d1);
+            let d0123s = d01s.zip(d23s).map(|(d01, d12)| d01 << 16 | d12);
             Ok(d0123s.collect())
         }
-        _ => unimplemented!("Only implemented 8, 16, 32 bit streaming!"),
+        _ => Err(SerialComError::UnsupportedStreamCommand(command)),
+    }
+}
+
+#[test]
+fn test_reg_round_trip() {
+    let mut data: [u8; 6] = [0; 6];
+    host_read_reg_pack(0x1234, &mut data).unwrap();
+    assert_eq!(dev_read_reg_unpack(&data[..2]).unwrap(), 0x1234);
+    host_write_reg8_pack(0xABCD, 0x5A, &mut data).unwrap();
+    assert_eq!(dev_write_reg8_unpack(&data[..3]).unwrap(), (0xABCD, 0x5A));
+    host_write_reg32_pack(0x0102, 0xDEADBEEF, &mut data).unwrap();
+    assert_eq!(dev_write_reg32_unpack(&data).unwrap(), (0x0102, 0xDEADBEEF));
+    dev_write_reg_pack(0xFEDC, &mut data).unwrap();
+    assert_eq!(host_write_reg_unpack(&data[..2]).unwrap(), 0xFEDC);
+    dev_read_reg32_pack(7, 0x89ABCDEF, &mut data).unwrap();
+    assert_eq!(host_read_reg_unpack(&data).unwrap(), (7, 0x89ABCDEF));
+    dev_read_reg8_pack(0x300, 0xC3, &mut data).unwrap();
+    assert_eq!(host_read_reg_unpack(&data[..3]).unwrap(), (0x300, 0xC3));
+}
+
+#[test]
+fn test_unpack_stream_words() {
+    let data: Vec<u8> = vec![0x12, 0x34, 0x56, 0x78];
+    assert_eq!(unpack_stream(0x0C, data.clone()).unwrap(), [0x1234, 0x5678]);
+    assert_eq!(
+        unpack_stream(0x09, data[..2].to_vec()).unwrap(),
+        [0x1, 0x2, 0x3, 0x4]
+    );
+    assert_eq!(
+        unpack_stream(0x0B, data[..3].to_vec()).unwrap(),
+        [0x123, 0x456]
+    );
+}
+
+#[test]
+fn test_unpack_stream_invalid() {
+    // Two words per sample
+    match unpack_stream(0x94, vec![1, 2, 3, 4]) {
+        Err(SerialComError::UnsupportedStreamCommand(0x94)) => {}
+        _ => panic!("Expected UnsupportedStreamCommand"),
+    }
+    // 24 bit words
+    match unpack_stream(0x8E, vec![1, 2, 3]) {
+        Err(SerialComError::UnsupportedStreamCommand(0x8E)) => {}
+        _ => panic!("Expected UnsupportedStreamCommand"),
+    }
+    for command in [0x8Bu8, 0x8C].iter() {
+        match unpack_stream(*command, vec![1, 2, 3, 4, 5]) {
+            Err(SerialComError::StreamDataLength) => {}
+            _ => panic!("Expected StreamDataLength"),
+        }
     }
 }
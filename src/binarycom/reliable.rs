@@ -0,0 +1,187 @@
+//! Reliable delivery: sequence numbers, ACK/NACK and retransmission
+//!
+//! On a link with LinkConfig::reliable set, each message starts with a sequence number:
+//! [seq][command][data][checksum]. The device answers each request with an ACK carrying the
+//! request's seq, and a frame it can't decode or check with a NACK. The host retransmits a request
+//! until its reply arrives, as its RetryPolicy says, and retransmits right away on a NACK. Pings
+//! are the exception, see supervisor.
+//!
+//! The device remembers the seq of the last request and the reply to it. A retransmitted request
+//! is ACKed and answered from that reply but not carried out again, so each request takes effect
+//! exactly once. Replies and stream messages from the device aren't retransmitted by the device;
+//! the host drops repeats of them by seq.
+//!
+//! Either end may start over from seq 0 when it restarts, so the seqs it has seen from the other
+//! end would be taken for repeats. A host starts each session with a CMD_SYNC: the device forgets
+//! the seqs it has seen and its last reply, and answers with a CMD_SYNC of its own, which tells
+//! the host that the device's seqs start over. A device also sends a CMD_SYNC before its first
+//! message after starting up.
+
+use crate::binarycom::{send_message_headed, unpack_frame_headed};
+use crate::crc::Checksum;
+use crate::error::SerialComResult;
+use crate::framer::Framer;
+
+use core::time::Duration;
+
+/// Start of a session: [seq][CMD_SYNC], from either end
+pub const CMD_SYNC: u8 = 0x7C;
+/// Positive acknowledgement: [seq of the request][CMD_ACK]
+pub const CMD_ACK: u8 = 0x7E;
+/// Negative acknowledgement of a bad frame: [next expected seq][CMD_NACK]
+pub const CMD_NACK: u8 = 0x7F;
+
+/// How the host retransmits requests that go unanswered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of retransmissions after the first try
+    pub retries: u32,
+    /// How long to wait for the reply to the first try
+    pub timeout: Duration,
+    /// Each retry waits this many times longer than the one before
+    pub backoff: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries: 3,
+            timeout: Duration::from_millis(100),
+            backoff: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait for a reply after try number attempt, counting from 0
+    pub fn timeout(&self, attempt: u32) -> Duration {
+        self.timeout
+            .saturating_mul(self.backoff.saturating_pow(attempt))
+    }
+}
+
+/// Tells new sequence numbers from repeats
+///
+/// Remembers which of the 128 sequence numbers up to the newest one have been seen. A sequence
+/// number further back than that is taken as the sender having started over, not as a repeat.
+#[derive(Clone, Debug, Default)]
+pub struct Deduplicator {
+    newest: Option<u8>,
+    /// Bit i is set if newest - i has been seen
+    seen: u128,
+}
+
+impl Deduplicator {
+    /// returns whether seq hasn't been seen before, and marks it seen
+    pub fn is_new(&mut self, seq: u8) -> bool {
+        let newest = match self.newest {
+            Some(newest) => newest,
+            None => {
+                self.newest = Some(seq);
+                self.seen = 1;
+                return true;
+            }
+        };
+        let ahead = seq.wrapping_sub(newest) as i8;
+        if ahead > 0 {
+            self.seen = self.seen.checked_shl(ahead as u32).unwrap_or(0) | 1;
+            self.newest = Some(seq);
+            return true;
+        }
+        let behind = u32::from(ahead.unsigned_abs());
+        if behind >= 128 {
+            self.newest = Some(seq);
+            self.seen = 1;
+            return true;
+        }
+        let bit = 1u128 << behind;
+        let is_new = self.seen & bit == 0;
+        self.seen |= bit;
+        is_new
+    }
+
+    /// Forget every seq seen, for a sender that starts a new session
+    pub fn reset(&mut self) {
+        *self = Deduplicator::default();
+    }
+}
+
+/// Encode a sequenced message with a checksum trailer into dst as one frame of framer
+///
+/// Returns final message length
+pub fn send_sequenced<F: Framer + ?Sized>(
+    framer: &F,
+    checksum: Checksum,
+    seq: u8,
+    command: u8,
+    data: &[u8],
+    dst: &mut [u8],
+) -> SerialComResult<usize> {
+    send_message_headed(framer, checksum, &[seq], command, data, dst)
+}
+
+/// Read a sequenced message from a decoded frame, checking its checksum
+///
+/// Returns the seq, command, and received data length
+pub fn unpack_sequenced(
+    frame: &[u8],
+    checksum: Checksum,
+    data: &mut [u8],
+) -> SerialComResult<(u8, u8, usize)> {
+    let mut seq: [u8; 1] = [0];
+    let mut command: u8 = 0;
+    let data_size = unpack_frame_headed(frame, checksum, &mut seq, &mut command, data)?;
+    Ok((seq[0], command, data_size))
+}
+
+#[test]
+fn test_deduplicator() {
+    let mut dedup = Deduplicator::default();
+    assert!(dedup.is_new(250));
+    assert!(!dedup.is_new(250));
+    // Out of order and wrapping around
+    assert!(dedup.is_new(3));
+    assert!(dedup.is_new(252));
+    assert!(!dedup.is_new(252));
+    assert!(!dedup.is_new(3));
+    assert!(dedup.is_new(251));
+    for seq in 4..=100u8 {
+        assert!(dedup.is_new(seq));
+    }
+    assert!(!dedup.is_new(252));
+    assert!(dedup.is_new(200));
+    assert!(!dedup.is_new(200));
+    // 128 back: the sender started over
+    assert!(dedup.is_new(72));
+    assert!(dedup.is_new(71));
+    assert!(!dedup.is_new(72));
+    dedup.reset();
+    assert!(dedup.is_new(72));
+}
+
+#[test]
+fn test_sequenced_round_trip() {
+    let framer = crate::framer::SlipFramer::<32>::default();
+    let mut frame: [u8; 64] = [0; 64];
+    let frame_len = send_sequenced(
+        &framer,
+        Checksum::Crc16CcittFalse,
+        9,
+        2,
+        &[1, 2, 3],
+        &mut frame,
+    )
+    .unwrap();
+    let mut decoder = crate::framer::SlipFramer::<32>::default();
+    let mut data: [u8; 8] = [0; 8];
+    let mut unpacked: Option<(u8, u8, usize)> = None;
+    decoder.push_slice(&frame[..frame_len], |frame| {
+        unpacked =
+            Some(unpack_sequenced(frame.unwrap(), Checksum::Crc16CcittFalse, &mut data).unwrap())
+    });
+    assert_eq!(unpacked, Some((9, 2, 3)));
+    assert_eq!(data[..3], [1, 2, 3]);
+    let policy = RetryPolicy::default();
+    assert_eq!(policy.timeout(0), Duration::from_millis(100));
+    assert_eq!(policy.timeout(3), Duration::from_millis(800));
+}
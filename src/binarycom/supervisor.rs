@@ -0,0 +1,302 @@
+//! Link supervision: keepalive pings and link state
+//!
+//! A LinkSupervisor pings the device every SupervisorConfig::interval with CMD_PING and a nonce,
+//! which the device echoes. From the pings that go unanswered and the rate of frames failing
+//! their checksum it judges the link Connected, Degraded or Lost, and sends a LinkEvent to each
+//! subscriber when that changes.
+//!
+//! On a reliable link pings are outside the seq space: each carries seq 0, and the device answers
+//! every ping it gets without an ACK, and without forgetting its last reply, so pings don't get in
+//! the way of retransmitting the requests sent between them.
+
+use crate::binarycom::app::write_shared;
+use crate::binarycom::hostreceiver::LinkStats;
+use crate::binarycom::{send_message_headed, LinkConfig, CMD_PING};
+use crate::transport::Transport;
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Health of the link to a device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkState {
+    /// Pings are answered and few frames are bad
+    Connected,
+    /// Some pings went unanswered, or too many frames are bad
+    Degraded,
+    /// Too many pings in a row went unanswered, or none has been answered yet
+    Lost,
+}
+
+/// Change of link state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkEvent {
+    pub from: LinkState,
+    pub to: LinkState,
+}
+
+/// When the LinkSupervisor pings and how it judges the link
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SupervisorConfig {
+    /// Time between pings
+    pub interval: Duration,
+    /// How long a ping's reply may take
+    pub timeout: Duration,
+    /// Unanswered pings in a row after which the link is Lost
+    pub lost_after: u32,
+    /// Fraction of bad frames between pings above which the link is Degraded
+    pub max_error_rate: f64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> SupervisorConfig {
+        SupervisorConfig {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_millis(200),
+            lost_after: 3,
+            max_error_rate: 0.1,
+        }
+    }
+}
+
+impl SupervisorConfig {
+    /// State of a link with missed unanswered pings in a row and error_rate bad frames
+    pub fn judge(&self, missed: u32, error_rate: f64) -> LinkState {
+        if missed >= self.lost_after {
+            LinkState::Lost
+        } else if missed > 0 || error_rate > self.max_error_rate {
+            LinkState::Degraded
+        } else {
+            LinkState::Connected
+        }
+    }
+}
+
+/// Pings a device from its own thread and tracks the link's state
+///
+/// The thread stops when the LinkSupervisor is dropped, or when the HostReceiver16 closes.
+pub struct LinkSupervisor {
+    pub thread_handle: thread::JoinHandle<()>,
+    state: Arc<Mutex<LinkState>>,
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<LinkEvent>>>>,
+    /// Dropping it stops the thread
+    _tx_stop: mpsc::Sender<()>,
+}
+
+impl LinkSupervisor {
+    /// Start pinging the device at address over transport
+    ///
+    /// rx_ping and stats are the HostReceiver16's for the link. Each ping is written to
+    /// transport under its lock, which whatever else sends requests should take too.
+    pub fn start(
+        transport: Arc<Mutex<Box<dyn Transport>>>,
+        link: LinkConfig,
+        address: Option<u8>,
+        rx_ping: mpsc::Receiver<u8>,
+        stats: Arc<LinkStats>,
+        config: SupervisorConfig,
+    ) -> LinkSupervisor {
+        let state = Arc::new(Mutex::new(LinkState::Lost));
+        let subscribers: Arc<Mutex<Vec<mpsc::Sender<LinkEvent>>>> = Default::default();
+        let (tx_stop, rx_stop) = mpsc::channel();
+        let thread_state = Arc::clone(&state);
+        let thread_subscribers = Arc::clone(&subscribers);
+        let thread_handle = thread::spawn(move || {
+            let framer = link.framing.new_framer::<16>();
+            let mut nonce: u8 = 0;
+            let mut missed = config.lost_after;
+            let mut frames = (stats.frames_ok(), stats.frames_bad());
+            loop {
+                let started = Instant::now();
+                while rx_ping.try_recv().is_ok() {}
+                nonce = nonce.wrapping_add(1);
+                let seq = if link.reliable { Some(0) } else { None };
+                let header: Vec<u8> = address.into_iter().chain(seq).collect();
+                let mut frame: [u8; 32] = [0; 32];
+                let sent = send_message_headed(
+                    &framer,
+                    link.checksum,
+                    &header,
+                    CMD_PING,
+                    &[nonce],
+                    &mut frame,
+                )
+                .and_then(|frame_len| write_shared(&transport, &frame[..frame_len]));
+                let answered = match sent {
+                    Ok(()) => match recv_nonce(&rx_ping, nonce, config.timeout) {
+                        Ok(()) => true,
+                        Err(mpsc::RecvTimeoutError::Timeout) => false,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            println!("rx_ping disconnected, closing supervisor thread");
+                            publish(&thread_state, &thread_subscribers, LinkState::Lost);
+                            return;
+                        }
+                    },
+                    Err(send_error) => {
+                        println!("Error while sending ping: {}", send_error);
+                        false
+                    }
+                };
+                missed = if answered {
+                    0
+                } else {
+                    missed.saturating_add(1)
+                };
+                let now_frames = (stats.frames_ok(), stats.frames_bad());
+                let n_ok = now_frames.0 - frames.0;
+                let n_bad = now_frames.1 - frames.1;
+                frames = now_frames;
+                let error_rate = if n_ok + n_bad == 0 {
+                    0.0
+                } else {
+                    n_bad as f64 / (n_ok + n_bad) as f64
+                };
+                publish(
+                    &thread_state,
+                    &thread_subscribers,
+                    config.judge(missed, error_rate),
+                );
+                let remaining = config.interval.saturating_sub(started.elapsed());
+                match rx_stop.recv_timeout(remaining) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+        LinkSupervisor {
+            thread_handle,
+            state,
+            subscribers,
+            _tx_stop: tx_stop,
+        }
+    }
+
+    /// Current state of the link
+    pub fn state(&self) -> LinkState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Get a LinkEvent for each change of link state from now on
+    pub fn subscribe(&self) -> mpsc::Receiver<LinkEvent> {
+        let (tx_event, rx_event) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx_event);
+        rx_event
+    }
+}
+
+/// Receive from rx_ping until nonce comes, for at most timeout
+fn recv_nonce(
+    rx_ping: &mpsc::Receiver<u8>,
+    nonce: u8,
+    timeout: Duration,
+) -> Result<(), mpsc::RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if rx_ping.recv_timeout(deadline.saturating_duration_since(Instant::now()))? == nonce {
+            return Ok(());
+        }
+    }
+}
+
+/// Move to state to, telling the subscribers if it's a change
+///
+/// Subscribers whose receiver is gone are dropped.
+fn publish(
+    state: &Mutex<LinkState>,
+    subscribers: &Mutex<Vec<mpsc::Sender<LinkEvent>>>,
+    to: LinkState,
+) {
+    let mut state = state.lock().unwrap();
+    if *state == to {
+        return;
+    }
+    let event = LinkEvent { from: *state, to };
+    *state = to;
+    subscribers
+        .lock()
+        .unwrap()
+        .retain(|tx_event| tx_event.send(event).is_ok());
+}
+
+#[cfg(test)]
+use crate::binarycom::app::{fake_device, BinaryComApp16, MutableTransport};
+#[cfg(test)]
+use crate::binarycom::device::DeviceResponder;
+#[cfg(test)]
+use crate::binarycom::RegisterBitWidth;
+#[cfg(test)]
+use crate::sim::RegisterMap;
+#[cfg(test)]
+use crate::transport::MemoryTransport;
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[test]
+fn test_supervisor_config_judge() {
+    let config = SupervisorConfig::default();
+    assert_eq!(config.judge(0, 0.0), LinkState::Connected);
+    assert_eq!(config.judge(0, 0.1), LinkState::Connected);
+    assert_eq!(config.judge(0, 0.5), LinkState::Degraded);
+    assert_eq!(config.judge(1, 0.0), LinkState::Degraded);
+    assert_eq!(config.judge(2, 0.0), LinkState::Degraded);
+    assert_eq!(config.judge(3, 0.0), LinkState::Lost);
+    assert_eq!(config.judge(u32::MAX, 1.0), LinkState::Lost);
+}
+
+#[test]
+fn test_link_supervisor_events() {
+    let (host, dev) = MemoryTransport::pair();
+    // Doesn't answer while muted
+    let muted = Arc::new(AtomicBool::new(false));
+    let dev = MutableTransport::new(Box::new(dev), Arc::clone(&muted));
+    let responder = DeviceResponder::new(RegisterMap::new(RegisterBitWidth::ThirtyTwo));
+    thread::spawn(move || fake_device(dev, responder));
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    let supervisor = app
+        .supervise(SupervisorConfig {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(10),
+            ..SupervisorConfig::default()
+        })
+        .expect("Couldn't supervise");
+    app.supervise(SupervisorConfig::default())
+        .err()
+        .expect("Should be error, already supervised");
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while supervisor.state() != LinkState::Connected {
+        assert!(Instant::now() < deadline, "Never connected");
+        thread::sleep(Duration::from_millis(5));
+    }
+    let rx_event = supervisor.subscribe();
+    let next_event = || {
+        rx_event
+            .recv_timeout(Duration::from_secs(1))
+            .expect("No event")
+    };
+    muted.store(true, Ordering::SeqCst);
+    assert_eq!(
+        next_event(),
+        LinkEvent {
+            from: LinkState::Connected,
+            to: LinkState::Degraded
+        }
+    );
+    assert_eq!(
+        next_event(),
+        LinkEvent {
+            from: LinkState::Degraded,
+            to: LinkState::Lost
+        }
+    );
+    muted.store(false, Ordering::SeqCst);
+    assert_eq!(
+        next_event(),
+        LinkEvent {
+            from: LinkState::Lost,
+            to: LinkState::Connected
+        }
+    );
+    assert_eq!(app.read_reg(0).expect("Couldn't read reg"), 0);
+}
@@ -0,0 +1,408 @@
+//! Port-sharing broker: lets several local processes use one device link at once
+//!
+//! The broker owns the link to the device and listens on a Unix domain socket. Clients speak the
+//! same framed protocol to the broker as they would to the device, so a client is just a
+//! BinaryComApp with a UnixTransport. Register reads and writes from all clients are forwarded to
+//! the device one at a time, and each reply goes back to the client that asked; other requests are
+//! rejected with an UnsupportedCommand error reply. Stream messages from the device are sent to
+//! every client.
+
+use crate::binarycom::app::RegisterBitWidth;
+use crate::binarycom::errorreply::{DeviceErrorCode, ErrorReply, CMD_ERROR};
+use crate::binarycom::hostreceiver::HostReceiver16;
+use crate::binarycom::packers;
+use crate::binarycom::{unpack_frame, BinaryCom, CMD_READ_REG_TAGGED, CMD_WRITE_REG_TAGGED};
+use crate::cobs::COBSDecoder;
+use crate::error::SerialComResult;
+use crate::transport::unix::UnixTransport;
+use crate::transport::Transport;
+
+use std::convert::TryFrom;
+use std::io;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+type Buf16 = arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping>;
+
+/// The device side of the broker, only used by one client request at a time
+struct DeviceLink {
+    transport: Box<dyn Transport>,
+    hostreceiver: HostReceiver16,
+    outbuf: Buf16,
+}
+
+struct Client {
+    id: u64,
+    writer: Box<dyn Transport>,
+}
+
+struct BrokerShared {
+    device: Mutex<DeviceLink>,
+    clients: Mutex<Vec<Client>>,
+    regbitwidth: RegisterBitWidth,
+    reply_timeout: Duration,
+}
+
+pub struct Broker {
+    pub stream_thread_handle: thread::JoinHandle<()>,
+    listener: UnixListener,
+    socket_path: PathBuf,
+    shared: Arc<BrokerShared>,
+}
+
+impl Broker {
+    /// Take ownership of the device link and listen for clients on socket_path
+    ///
+    /// A stale socket file left at socket_path is removed, but it is an error if another broker
+    /// is still listening there.
+    pub fn bind<P: AsRef<Path>>(
+        socket_path: P,
+        device: Box<dyn Transport>,
+        register_bit_width: RegisterBitWidth,
+    ) -> SerialComResult<Broker> {
+        let socket_path = socket_path.as_ref().to_path_buf();
+        if socket_path.exists() {
+            if UnixStream::connect(&socket_path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("A broker is already listening on {}", socket_path.display()),
+                )
+                .into());
+            }
+            std::fs::remove_file(&socket_path)?;
+        }
+        let listener = UnixListener::bind(&socket_path)?;
+        let (hostreceiver, rx_stream) = HostReceiver16::new(device.try_clone()?);
+        let shared = Arc::new(BrokerShared {
+            device: Mutex::new(DeviceLink {
+                transport: device,
+                hostreceiver,
+                outbuf: arraydeque::ArrayDeque::new(),
+            }),
+            clients: Mutex::new(Vec::new()),
+            regbitwidth: register_bit_width,
+            reply_timeout: Duration::from_millis(200),
+        });
+        let stream_shared = Arc::clone(&shared);
+        let stream_thread = thread::spawn(move || loop {
+            match rx_stream.recv() {
+                Ok((command, data)) => stream_shared.send_to_all(command, &data),
+                Err(mpsc::RecvError) => {
+                    println!("rx_stream disconnected, closing broker stream thread");
+                    return;
+                }
+            }
+        });
+        Ok(Broker {
+            stream_thread_handle: stream_thread,
+            listener,
+            socket_path,
+            shared,
+        })
+    }
+
+    /// Accept clients and serve them, one thread per client
+    ///
+    /// Only returns if accepting a client fails.
+    pub fn run(&self) -> SerialComResult<()> {
+        let mut next_id: u64 = 0;
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            let id = next_id;
+            next_id += 1;
+            let reader = UnixTransport::from_stream(stream)?;
+            let writer = reader.try_clone()?;
+            self.shared.lock_clients()?.push(Client { id, writer });
+            let shared = Arc::clone(&self.shared);
+            thread::spawn(move || serve_client(shared, id, reader));
+        }
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+impl BrokerShared {
+    fn lock_clients(&self) -> SerialComResult<MutexGuard<'_, Vec<Client>>> {
+        Ok(self
+            .clients
+            .lock()
+            .map_err(|_| io::Error::other("broker clients lock poisoned"))?)
+    }
+
+    /// Encode a message and send it to every client, dropping clients that can't be written to
+    fn send_to_all(&self, command: u8, data: &[u8]) {
+        let mut outbuf: Buf16 = arraydeque::ArrayDeque::new();
+        if let Err(send_error) = outbuf.send_message(&command, data) {
+            println!("Broker error while encoding stream message: {}", send_error);
+            return;
+        }
+        if let Ok(mut clients) = self.lock_clients() {
+            clients.retain_mut(|client| write_buf(&mut client.writer, &outbuf).is_ok());
+        }
+    }
+
+    /// Encode a message and send it to the client with id
+    fn send_to(&self, id: u64, command: u8, data: &[u8]) -> SerialComResult<()> {
+        let mut outbuf: Buf16 = arraydeque::ArrayDeque::new();
+        outbuf.send_message(&command, data)?;
+        let mut clients = self.lock_clients()?;
+        if let Some(client) = clients.iter_mut().find(|client| client.id == id) {
+            write_buf(&mut client.writer, &outbuf)?;
+        }
+        Ok(())
+    }
+
+    fn remove_client(&self, id: u64) {
+        if let Ok(mut clients) = self.lock_clients() {
+            clients.retain(|client| client.id != id);
+        }
+    }
+
+    /// Forward a register request to the device and wait for its reply
+    ///
+    /// Holds the device lock for the whole exchange, so requests from different clients are
+    /// serialized.
+    ///
+    /// returns the reply command and data length, or None if the device didn't reply in time.
+    /// The reply command is CMD_ERROR if the device rejected the request.
+    fn forward_request(
+        &self,
+        command: u8,
+        data: &[u8],
+        reply: &mut [u8],
+    ) -> SerialComResult<Option<(u8, usize)>> {
+        let mut device = self
+            .device
+            .lock()
+            .map_err(|_| io::Error::other("broker device lock poisoned"))?;
+        let device = &mut *device;
+        // Throw away late replies to earlier requests that timed out
+        while device.hostreceiver.rx_reg_read.try_recv().is_ok() {}
+        while device.hostreceiver.rx_reg_write.try_recv().is_ok() {}
+        device.outbuf.send_message(&command, data)?;
+        write_buf(&mut device.transport, &device.outbuf)?;
+        match command {
+            1u8 => {
+                let reg_num = packers::dev_read_reg_unpack(data)?;
+                loop {
+                    match device
+                        .hostreceiver
+                        .rx_reg_read
+                        .recv_timeout(self.reply_timeout)
+                    {
+                        Ok(Ok((reg_num_rec, reg_val_rec))) if reg_num_rec == reg_num => {
+                            let reply_len = match self.regbitwidth {
+                                RegisterBitWidth::Eight => packers::dev_read_reg8_pack(
+                                    reg_num,
+                                    u8::try_from(reg_val_rec)?,
+                                    reply,
+                                )?,
+                                RegisterBitWidth::ThirtyTwo => {
+                                    packers::dev_read_reg32_pack(reg_num, reg_val_rec, reply)?
+                                }
+                            };
+                            return Ok(Some((command, usize::from(reply_len))));
+                        }
+                        Ok(Err(error_reply)) if error_reply.reg_num == reg_num => {
+                            let reply_len = error_reply.pack(reply)?;
+                            return Ok(Some((CMD_ERROR, usize::from(reply_len))));
+                        }
+                        Ok(_) => {}
+                        Err(_) => return Ok(None),
+                    }
+                }
+            }
+            _ => {
+                let reg_num = packers::dev_read_reg_unpack(data)?;
+                loop {
+                    match device
+                        .hostreceiver
+                        .rx_reg_write
+                        .recv_timeout(self.reply_timeout)
+                    {
+                        Ok(Ok(reg_num_rec)) if reg_num_rec == reg_num => {
+                            let reply_len = packers::dev_write_reg_pack(reg_num, reply)?;
+                            return Ok(Some((command, usize::from(reply_len))));
+                        }
+                        Ok(Err(error_reply)) if error_reply.reg_num == reg_num => {
+                            let reply_len = error_reply.pack(reply)?;
+                            return Ok(Some((CMD_ERROR, usize::from(reply_len))));
+                        }
+                        Ok(_) => {}
+                        Err(_) => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Encode the error reply to a request the broker doesn't forward, like a device that doesn't know
+/// its command
+///
+/// returns the reply command and data length, like forward_request
+fn reject_request(
+    command: u8,
+    data: &[u8],
+    reply: &mut [u8],
+) -> SerialComResult<Option<(u8, usize)>> {
+    let (tag, reg_num) = match command {
+        CMD_READ_REG_TAGGED | CMD_WRITE_REG_TAGGED if data.len() > 2 => {
+            (data[0], packers::dev_read_reg_unpack(&data[1..])?)
+        }
+        _ => (0, 0),
+    };
+    let error_reply = ErrorReply {
+        command,
+        tag,
+        code: DeviceErrorCode::UnsupportedCommand,
+        reg_num,
+    };
+    Ok(Some((CMD_ERROR, usize::from(error_reply.pack(reply)?))))
+}
+
+fn write_buf(transport: &mut Box<dyn Transport>, buf: &Buf16) -> SerialComResult<()> {
+    let (slice1, slice2) = buf.as_slices();
+    transport.write(slice1)?;
+    transport.write(slice2)?;
+    transport.flush()
+}
+
+/// Read requests from one client until it disconnects
+fn serve_client(shared: Arc<BrokerShared>, id: u64, mut reader: UnixTransport) {
+    let mut decoder = COBSDecoder::new();
+    let mut readbuf: [u8; 64] = [0; 64];
+    let mut command: u8 = 0;
+    let mut data: [u8; 11] = [0; 11];
+    let mut reply: [u8; 11] = [0; 11];
+    while let Ok(n_read) = reader.read(&mut readbuf) {
+        for byte in readbuf.iter().take(n_read) {
+            let received = match decoder.push(*byte) {
+                None => continue,
+                Some(frame) => frame.and_then(|frame| unpack_frame(frame, &mut command, &mut data)),
+            };
+            let data_len = match received {
+                Ok(data_len) => data_len,
+                Err(recv_error) => {
+                    println!(
+                        "Broker error while receiving client message: {}",
+                        recv_error
+                    );
+                    continue;
+                }
+            };
+            let forwarded = if command == 1 || command == 2 {
+                shared.forward_request(command, &data[..data_len], &mut reply)
+            } else {
+                reject_request(command, &data[..data_len], &mut reply)
+            };
+            let result = forwarded.and_then(|replied| match replied {
+                Some((reply_command, reply_len)) => {
+                    shared.send_to(id, reply_command, &reply[..reply_len])
+                }
+                None => {
+                    println!("Broker: device didn't reply to client {}", id);
+                    Ok(())
+                }
+            });
+            if let Err(forward_error) = result {
+                println!("Broker error while forwarding request: {}", forward_error);
+            }
+        }
+    }
+    shared.remove_client(id);
+}
+
+#[cfg(test)]
+use crate::binarycom::app::{fake_device, BinaryComApp16, FakeRegisters};
+#[cfg(test)]
+use crate::binarycom::device::DeviceResponder;
+#[cfg(test)]
+use crate::error::SerialComError;
+#[cfg(test)]
+use crate::transport::MemoryTransport;
+
+#[test]
+fn test_broker_shared_by_clients() {
+    let socket_path = std::env::temp_dir().join(format!(
+        "serial_com_rust_test_broker_{}.sock",
+        std::process::id()
+    ));
+    let (host, dev) = MemoryTransport::pair();
+    let mut dev_writer = dev.try_clone().expect("Couldn't clone device transport");
+    thread::spawn(move || fake_device(dev, DeviceResponder::new(FakeRegisters)));
+    let broker = Broker::bind(&socket_path, Box::new(host), RegisterBitWidth::ThirtyTwo)
+        .expect("Couldn't bind broker");
+    Broker::bind(
+        &socket_path,
+        Box::new(MemoryTransport::pair().0),
+        RegisterBitWidth::ThirtyTwo,
+    )
+    .err()
+    .expect("Should be error, broker already listening");
+    thread::spawn(move || broker.run());
+
+    let mut client_threads = Vec::new();
+    let mut stream_receivers = Vec::new();
+    for i_client in 0..3u16 {
+        let (tx, rx) = mpsc::channel();
+        stream_receivers.push(rx);
+        let transport = UnixTransport::connect(&socket_path).expect("Couldn't connect");
+        let mut app = BinaryComApp16::with_stream_handler(
+            RegisterBitWidth::ThirtyTwo,
+            Box::new(transport),
+            move |command, data| tx.send((command, data)).unwrap(),
+        )
+        .expect("Couldn't make app");
+        client_threads.push(thread::spawn(move || {
+            for i in 0..20u16 {
+                let reg_num = i_client * 100 + i;
+                let reg_val = app.read_reg(reg_num).expect("Couldn't read reg");
+                assert_eq!(reg_val, u32::from(reg_num) + 0x1000);
+                app.write_reg(reg_num, 7).expect("Couldn't write reg");
+            }
+            // Without a handshake, reads aren't pipelined or block reads, which the broker
+            // wouldn't forward
+            let reg_nums = [i_client * 100, i_client * 100 + 1];
+            let reg_vals = app.read_many(&reg_nums).expect("Couldn't read regs");
+            assert_eq!(reg_vals, [u32::from(reg_nums[0]) + 0x1000, reg_vals[0] + 1]);
+            let reg_vals = app.read_regs(reg_nums[0], 3).expect("Couldn't read regs");
+            assert_eq!(reg_vals[2], u32::from(reg_nums[0]) + 0x1002);
+            let tag = app
+                .start_read_reg(reg_nums[0])
+                .expect("Couldn't start read");
+            match app.wait_tagged(tag) {
+                Err(SerialComError::DeviceError {
+                    code: DeviceErrorCode::UnsupportedCommand,
+                    reg_num,
+                }) => assert_eq!(reg_num, reg_nums[0]),
+                _ => panic!("Expected UnsupportedCommand"),
+            }
+            app
+        }));
+    }
+    let apps: Vec<BinaryComApp16> = client_threads
+        .into_iter()
+        .map(|handle| handle.join().expect("Client thread failed"))
+        .collect();
+
+    let mut outbuf: Buf16 = arraydeque::ArrayDeque::new();
+    outbuf.send_message(&0x8A, &[3, 2, 1]).unwrap();
+    let (slice1, slice2) = outbuf.as_slices();
+    dev_writer.write(slice1).unwrap();
+    dev_writer.write(slice2).unwrap();
+    for rx in stream_receivers.iter() {
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            (0x8A, vec![3, 2, 1])
+        );
+    }
+    drop(apps);
+}
@@ -2,435 +2,719 @@ extern crate arraydeque;
 
 #[cfg(test)]
 use rand::prelude::*;
-use std::convert::TryFrom;
 
 #[cfg(test)]
 use crate::circbuf::CircBufExt;
 use crate::error::SerialComError;
 use crate::error::SerialComResult;
 
+/// Flavor of COBS to encode and decode
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum COBSVariant {
+    /// COBS as in the original paper
+    #[default]
+    Standard,
+    /// COBS/R (reduced): when the last byte of a message is bigger than the code of the final
+    /// block, it takes the place of the code, saving a byte. The downside is that a frame cut
+    /// short in its final block can't be detected.
+    Reduced,
+}
+
 /// Trait to do consistent overhead byte stuffing (COBS)
 ///
-/// Implemented for:
-///
-///  arraydeque::ArrayDeque<[u8; 8], arraydeque::Wrapping>
-///  arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping>
-///  arraydeque::ArrayDeque<[u8; 32], arraydeque::Wrapping>
-///  arraydeque::ArrayDeque<[u8; 64], arraydeque::Wrapping>
-///  arraydeque::ArrayDeque<[u8; 128], arraydeque::Wrapping>
+/// Implemented for arraydeque::ArrayDeque<u8, N, arraydeque::Wrapping> of any capacity N, and
+/// Vec<u8>
 ///
+/// Runs of 254 or more non-zero bytes are split into blocks with the 0xFF code, as the COBS spec
+/// requires, so messages of any length can be encoded given room in the buffer.
 pub trait COBSExt {
     /// Encode data using consistent overhead byte stuffing (COBS)
     ///
-    /// Assumes everything in the buffer is a single unencoded message, and message is < 254 bytes long
+    /// Assumes everything in the buffer is a single unencoded message
     ///
     /// returns SerialComResult with queue size;
     ///
-    fn cobs_encode(&mut self) -> SerialComResult<usize>;
-    /// Encode data using consistent overhead byte stuffing (COBS)
+    fn cobs_encode(&mut self) -> SerialComResult<usize> {
+        self.cobs_encode_variant(COBSVariant::Standard)
+    }
+    /// Decode data using consistent overhead byte stuffing (COBS)
     ///
-    /// Assumes buffer starts with a message ending in a 0 as a comma character, and message is < 254 bytes long
+    /// Assumes buffer starts with a message ending in a 0 as a comma character
     ///
     /// Leaves the trailing 0 comma character.
     ///
     /// returns SerialComResult with size of front message on queue.
     ///
-    fn cobs_decode(&mut self) -> SerialComResult<usize>;
+    fn cobs_decode(&mut self) -> SerialComResult<usize> {
+        self.cobs_decode_variant(COBSVariant::Standard)
+    }
+    /// cobs_encode with the given variant of COBS
+    fn cobs_encode_variant(&mut self, variant: COBSVariant) -> SerialComResult<usize>;
+    /// cobs_decode with the given variant of COBS
+    fn cobs_decode_variant(&mut self, variant: COBSVariant) -> SerialComResult<usize>;
 }
 
-impl COBSExt for arraydeque::ArrayDeque<[u8; 8], arraydeque::Wrapping> {
-    fn cobs_encode(&mut self) -> SerialComResult<usize> {
-        if self.len() == 0 {
-            return Err(SerialComError::COBSTooLittleData);
+/// Longest run of non-zero bytes in one COBS block, which gets the code 0xFF
+const COBS_MAX_RUN: u8 = 254;
+
+/// Length of the standard COBS frame, including the comma, for message bytes
+#[cfg(feature = "std")]
+fn cobs_encoded_len<I: Iterator<Item = u8>>(message: I) -> usize {
+    let mut encoded_len: usize = 2;
+    let mut run: u8 = 0;
+    let mut split_pending = false;
+    for byte in message {
+        encoded_len += 1;
+        if split_pending {
+            // The last block was a full run and more bytes follow, so another code byte is needed
+            encoded_len += 1;
+            split_pending = false;
         }
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_front(0u8);
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_back(0u8);
-        let mut i_last_zero: usize = 0;
-        let qlen = self.len();
-        for i in 1..qlen {
-            if let Some(&0) = self.get(i) {
-                let last_zero_el = self
-                    .get_mut(i_last_zero)
-                    .ok_or(SerialComError::QueueIndexingError)?;
-                let last_zero_el_next = u8::try_from(i - i_last_zero)?;
-                *last_zero_el = last_zero_el_next;
-                i_last_zero = i;
+        if byte == 0 {
+            run = 0;
+        } else {
+            run += 1;
+            if run == COBS_MAX_RUN {
+                run = 0;
+                split_pending = true;
             }
         }
+    }
+    encoded_len
+}
+
+impl<const N: usize> COBSExt for arraydeque::ArrayDeque<u8, N, arraydeque::Wrapping> {
+    fn cobs_encode_variant(&mut self, variant: COBSVariant) -> SerialComResult<usize> {
+        let mut frame: [u8; N] = [0; N];
+        let frame_len = variant
+            .encode_iter_into(self.iter().copied(), &mut frame)
+            .map_err(|encode_error| match encode_error {
+                SerialComError::SliceTooSmall => SerialComError::QueueTooFull,
+                encode_error => encode_error,
+            })?;
+        self.clear();
+        self.extend(frame[..frame_len].iter().copied());
         Ok(self.len())
     }
-    fn cobs_decode(&mut self) -> SerialComResult<usize> {
-        let mut i_zero: usize = 0;
-        let mut comma_found = false;
-        let q_len = self.len();
-        if q_len < 3 {
+    fn cobs_decode_variant(&mut self, variant: COBSVariant) -> SerialComResult<usize> {
+        if self.len() < variant.min_frame_len() {
             return Err(SerialComError::COBSTooLittleData);
         }
-        while i_zero < q_len {
-            let i_zero_val = self
-                .get_mut(i_zero)
-                .ok_or(SerialComError::QueueIndexingError)?;
-            if *i_zero_val == 0u8 {
-                comma_found = true;
-                break;
-            }
-            i_zero += usize::from(*i_zero_val);
-            *i_zero_val = 0u8;
+        self.linearize();
+        let (msg_len, i_comma) = decode_frame_in_place(self.as_mut_slices().0, variant)?;
+        // Drop the overhead bytes left between the message and the comma
+        for _ in msg_len..i_comma {
+            self.remove(msg_len);
         }
-        if !comma_found {
-            return Err(SerialComError::COBSDecodeNoCommaFound);
-        };
-        self.pop_front();
-        Ok(i_zero - 1)
+        Ok(msg_len)
     }
 }
 
-impl COBSExt for arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> {
-    fn cobs_encode(&mut self) -> SerialComResult<usize> {
-        if self.len() == 0 {
-            return Err(SerialComError::COBSTooLittleData);
-        }
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_front(0u8);
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_back(0u8);
-        let mut i_last_zero: usize = 0;
-        let qlen = self.len();
-        for i in 1..qlen {
-            if let Some(&0) = self.get(i) {
-                let last_zero_el = self
-                    .get_mut(i_last_zero)
-                    .ok_or(SerialComError::QueueIndexingError)?;
-                let last_zero_el_next = u8::try_from(i - i_last_zero)?;
-                *last_zero_el = last_zero_el_next;
-                i_last_zero = i;
-            }
-        }
+#[cfg(feature = "std")]
+impl COBSExt for Vec<u8> {
+    fn cobs_encode_variant(&mut self, variant: COBSVariant) -> SerialComResult<usize> {
+        let mut encoded: Vec<u8> = vec![0; cobs_encoded_len(self.iter().copied())];
+        let encoded_len = variant.encode_iter_into(self.iter().copied(), &mut encoded)?;
+        encoded.truncate(encoded_len);
+        *self = encoded;
         Ok(self.len())
     }
-    fn cobs_decode(&mut self) -> SerialComResult<usize> {
-        let mut i_zero: usize = 0;
-        let mut comma_found = false;
-        let q_len = self.len();
-        if q_len < 3 {
+    fn cobs_decode_variant(&mut self, variant: COBSVariant) -> SerialComResult<usize> {
+        if self.len() < variant.min_frame_len() {
             return Err(SerialComError::COBSTooLittleData);
         }
-        while i_zero < q_len {
-            let i_zero_val = self
-                .get_mut(i_zero)
-                .ok_or(SerialComError::QueueIndexingError)?;
-            if *i_zero_val == 0u8 {
-                comma_found = true;
-                break;
-            }
-            i_zero += usize::from(*i_zero_val);
-            *i_zero_val = 0u8;
-        }
-        if !comma_found {
-            return Err(SerialComError::COBSDecodeNoCommaFound);
-        };
-        self.pop_front();
-        Ok(i_zero - 1)
+        let (msg_len, i_comma) = decode_frame_in_place(self, variant)?;
+        self.drain(msg_len..i_comma);
+        Ok(msg_len)
     }
 }
 
-impl COBSExt for arraydeque::ArrayDeque<[u8; 32], arraydeque::Wrapping> {
-    fn cobs_encode(&mut self) -> SerialComResult<usize> {
-        if self.len() == 0 {
-            return Err(SerialComError::COBSTooLittleData);
-        }
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_front(0u8);
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_back(0u8);
-        let mut i_last_zero: usize = 0;
-        let qlen = self.len();
-        for i in 1..qlen {
-            if let Some(&0) = self.get(i) {
-                let last_zero_el = self
-                    .get_mut(i_last_zero)
-                    .ok_or(SerialComError::QueueIndexingError)?;
-                let last_zero_el_next = u8::try_from(i - i_last_zero)?;
-                *last_zero_el = last_zero_el_next;
-                i_last_zero = i;
-            }
+/// Encode src with standard COBS into dst, followed by the 0 comma byte
+///
+/// For DMA and other writers that need the whole frame in one contiguous slice.
+///
+/// returns the encoded length, including the comma
+pub fn encode_into(src: &[u8], dst: &mut [u8]) -> SerialComResult<usize> {
+    COBSVariant::Standard.encode_into(src, dst)
+}
+
+/// Decode the standard COBS frame at the start of buf in place
+///
+/// The frame must end with a 0 comma byte. The decoded message is left at the start of buf;
+/// anything after it is garbage.
+///
+/// returns the decoded message length
+pub fn decode_in_place(buf: &mut [u8]) -> SerialComResult<usize> {
+    COBSVariant::Standard.decode_in_place(buf)
+}
+
+impl COBSVariant {
+    /// Length of the shortest frame, holding a one byte message, including the comma
+    fn min_frame_len(self) -> usize {
+        match self {
+            COBSVariant::Standard => 3,
+            COBSVariant::Reduced => 2,
         }
-        Ok(self.len())
     }
-    fn cobs_decode(&mut self) -> SerialComResult<usize> {
-        let mut i_zero: usize = 0;
-        let mut comma_found = false;
-        let q_len = self.len();
-        if q_len < 3 {
+
+    /// Encode src into dst, followed by the 0 comma byte
+    ///
+    /// returns the encoded length, including the comma
+    pub fn encode_into(self, src: &[u8], dst: &mut [u8]) -> SerialComResult<usize> {
+        self.encode_iter_into(src.iter().copied(), dst)
+    }
+
+    /// Decode the frame at the start of buf in place
+    ///
+    /// returns the decoded message length
+    pub fn decode_in_place(self, buf: &mut [u8]) -> SerialComResult<usize> {
+        let (msg_len, _) = decode_frame_in_place(buf, self)?;
+        Ok(msg_len)
+    }
+
+    /// Encode the bytes from src into dst, followed by the 0 comma byte
+    ///
+    /// returns the encoded length, including the comma
+    pub(crate) fn encode_iter_into<I>(self, src: I, dst: &mut [u8]) -> SerialComResult<usize>
+    where
+        I: Iterator<Item = u8>,
+    {
+        let mut src = src.peekable();
+        if src.peek().is_none() {
             return Err(SerialComError::COBSTooLittleData);
         }
-        while i_zero < q_len {
-            let i_zero_val = self
-                .get_mut(i_zero)
-                .ok_or(SerialComError::QueueIndexingError)?;
-            if *i_zero_val == 0u8 {
-                comma_found = true;
-                break;
+        let mut i_code: usize = 0;
+        let mut i_dst: usize = 1;
+        let mut code: u8 = 1;
+        while let Some(byte) = src.next() {
+            if byte != 0 {
+                *dst.get_mut(i_dst).ok_or(SerialComError::SliceTooSmall)? = byte;
+                i_dst += 1;
+                code += 1;
+            }
+            if byte == 0 || (code == COBS_MAX_RUN + 1 && src.peek().is_some()) {
+                *dst.get_mut(i_code).ok_or(SerialComError::SliceTooSmall)? = code;
+                i_code = i_dst;
+                i_dst += 1;
+                code = 1;
             }
-            i_zero += usize::from(*i_zero_val);
-            *i_zero_val = 0u8;
         }
-        if !comma_found {
-            return Err(SerialComError::COBSDecodeNoCommaFound);
-        };
-        self.pop_front();
-        Ok(i_zero - 1)
+        *dst.get_mut(i_code).ok_or(SerialComError::SliceTooSmall)? = code;
+        if self == COBSVariant::Reduced && code > 1 && dst[i_dst - 1] > code {
+            dst[i_code] = dst[i_dst - 1];
+            i_dst -= 1;
+        }
+        *dst.get_mut(i_dst).ok_or(SerialComError::SliceTooSmall)? = 0;
+        Ok(i_dst + 1)
     }
 }
 
-impl COBSExt for arraydeque::ArrayDeque<[u8; 64], arraydeque::Wrapping> {
-    fn cobs_encode(&mut self) -> SerialComResult<usize> {
-        if self.len() == 0 {
-            return Err(SerialComError::COBSTooLittleData);
+/// Decode the frame at the start of buf in place
+///
+/// returns the decoded message length and the index of the frame's comma
+fn decode_frame_in_place(buf: &mut [u8], variant: COBSVariant) -> SerialComResult<(usize, usize)> {
+    let mut i_read: usize = 0;
+    let mut i_write: usize = 0;
+    let mut zero_pending = false;
+    loop {
+        let code = *buf
+            .get(i_read)
+            .ok_or(SerialComError::COBSDecodeNoCommaFound)?;
+        if code == 0 {
+            break;
         }
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_front(0u8);
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_back(0u8);
-        let mut i_last_zero: usize = 0;
-        let qlen = self.len();
-        for i in 1..qlen {
-            if let Some(&0) = self.get(i) {
-                let last_zero_el = self
-                    .get_mut(i_last_zero)
-                    .ok_or(SerialComError::QueueIndexingError)?;
-                let last_zero_el_next = u8::try_from(i - i_last_zero)?;
-                *last_zero_el = last_zero_el_next;
-                i_last_zero = i;
-            }
+        if zero_pending {
+            buf[i_write] = 0;
+            i_write += 1;
         }
-        Ok(self.len())
-    }
-    fn cobs_decode(&mut self) -> SerialComResult<usize> {
-        let mut i_zero: usize = 0;
-        let mut comma_found = false;
-        let q_len = self.len();
-        if q_len < 3 {
-            return Err(SerialComError::COBSTooLittleData);
-        }
-        while i_zero < q_len {
-            let i_zero_val = self
-                .get_mut(i_zero)
-                .ok_or(SerialComError::QueueIndexingError)?;
-            if *i_zero_val == 0u8 {
-                comma_found = true;
-                break;
+        let block_end = i_read + usize::from(code);
+        for i_block in i_read + 1..block_end {
+            let byte = *buf
+                .get(i_block)
+                .ok_or(SerialComError::COBSDecodeNoCommaFound)?;
+            if byte == 0 {
+                if variant == COBSVariant::Reduced {
+                    // The code of the final block was its last byte
+                    buf[i_write] = code;
+                    return Ok((i_write + 1, i_block));
+                }
+                return Err(SerialComError::FrameMalformed);
             }
-            i_zero += usize::from(*i_zero_val);
-            *i_zero_val = 0u8;
+            buf[i_write] = byte;
+            i_write += 1;
         }
-        if !comma_found {
-            return Err(SerialComError::COBSDecodeNoCommaFound);
-        };
-        self.pop_front();
-        Ok(i_zero - 1)
+        zero_pending = code != 0xFF;
+        i_read = block_end;
+    }
+    if i_read == 0 {
+        return Err(SerialComError::COBSTooLittleData);
     }
+    Ok((i_write, i_read))
 }
 
-impl COBSExt for arraydeque::ArrayDeque<[u8; 128], arraydeque::Wrapping> {
-    fn cobs_encode(&mut self) -> SerialComResult<usize> {
-        if self.len() == 0 {
-            return Err(SerialComError::COBSTooLittleData);
-        }
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_front(0u8);
-        if self.is_full() {
-            return Err(SerialComError::QueueTooFull);
-        };
-        self.push_back(0u8);
-        let mut i_last_zero: usize = 0;
-        let qlen = self.len();
-        for i in 1..qlen {
-            if let Some(&0) = self.get(i) {
-                let last_zero_el = self
-                    .get_mut(i_last_zero)
-                    .ok_or(SerialComError::QueueIndexingError)?;
-                let last_zero_el_next = u8::try_from(i - i_last_zero)?;
-                *last_zero_el = last_zero_el_next;
-                i_last_zero = i;
-            }
+/// Max decoded frame size of a COBSDecoder made with new
+pub const COBS_DECODER_MAX_FRAME: usize = 256;
+
+/// Stateful COBS decoder for a byte stream
+///
+/// Bytes can be pushed in arbitrary chunks: partial frames, several frames at once, or garbage
+/// before the first frame. Frames are decoded as their bytes arrive, so nothing is buffered but
+/// the decoded frame. A frame that is truncated or too long is reported when its 0 comma byte
+/// arrives, and decoding resynchronizes on the next frame.
+///
+/// N is the max decoded frame size.
+pub struct COBSDecoder<const N: usize = COBS_DECODER_MAX_FRAME> {
+    frame: [u8; N],
+    frame_len: usize,
+    /// Data bytes left in the current block; the next byte is a code byte when 0
+    block_remaining: u8,
+    /// Set once the first code byte of a frame is seen
+    in_frame: bool,
+    /// Set while in a full run block (code 0xFF), which isn't followed by a zero
+    in_run_block: bool,
+    /// Code byte of the current block
+    code: u8,
+    variant: COBSVariant,
+    /// Set when the current frame is bad; its remaining bytes are dropped
+    error: Option<SerialComError>,
+}
+
+impl COBSDecoder {
+    pub fn new() -> COBSDecoder {
+        COBSDecoder::default()
+    }
+}
+
+impl<const N: usize> Default for COBSDecoder<N> {
+    fn default() -> COBSDecoder<N> {
+        COBSDecoder::with_variant(COBSVariant::Standard)
+    }
+}
+
+impl<const N: usize> COBSDecoder<N> {
+    pub fn with_variant(variant: COBSVariant) -> COBSDecoder<N> {
+        COBSDecoder {
+            frame: [0; N],
+            frame_len: 0,
+            block_remaining: 0,
+            in_frame: false,
+            in_run_block: false,
+            code: 0,
+            variant,
+            error: None,
         }
-        Ok(self.len())
     }
-    fn cobs_decode(&mut self) -> SerialComResult<usize> {
-        let mut i_zero: usize = 0;
-        let mut comma_found = false;
-        let q_len = self.len();
-        if q_len < 3 {
-            return Err(SerialComError::COBSTooLittleData);
+
+    /// Drop any partially decoded frame
+    pub fn reset(&mut self) {
+        self.frame_len = 0;
+        self.block_remaining = 0;
+        self.in_frame = false;
+        self.in_run_block = false;
+        self.error = None;
+    }
+
+    /// Push one encoded byte
+    ///
+    /// returns None while a frame is in progress. When byte is the 0 comma byte that ends a
+    /// frame, returns the decoded frame, or the error that made it bad. Empty frames (repeated
+    /// commas) are skipped.
+    pub fn push(&mut self, byte: u8) -> Option<SerialComResult<&[u8]>> {
+        if byte == 0 {
+            let result = match self.error.take() {
+                Some(frame_error) => Some(Err(frame_error)),
+                None if !self.in_frame => None,
+                None if self.block_remaining == 0 => Some(Ok(())),
+                None if self.variant == COBSVariant::Reduced => {
+                    // The code of the final block was its last byte
+                    self.push_decoded(self.code);
+                    Some(self.error.take().map_or(Ok(()), Err))
+                }
+                None => Some(Err(SerialComError::FrameMalformed)),
+            };
+            let frame_len = self.frame_len;
+            self.reset();
+            return result.map(|result| result.map(move |_| &self.frame[..frame_len]));
+        }
+        if self.error.is_some() {
+            return None;
         }
-        while i_zero < q_len {
-            let i_zero_val = self
-                .get_mut(i_zero)
-                .ok_or(SerialComError::QueueIndexingError)?;
-            if *i_zero_val == 0u8 {
-                comma_found = true;
-                break;
+        if self.block_remaining == 0 {
+            // Code byte: the zero it stands for ends the previous block
+            if self.in_frame && !self.in_run_block {
+                self.push_decoded(0);
             }
-            i_zero += usize::from(*i_zero_val);
-            *i_zero_val = 0u8;
+            self.in_frame = true;
+            self.in_run_block = byte == 0xFF;
+            self.code = byte;
+            self.block_remaining = byte - 1;
+        } else {
+            self.push_decoded(byte);
+            self.block_remaining -= 1;
         }
-        if !comma_found {
-            return Err(SerialComError::COBSDecodeNoCommaFound);
-        };
-        self.pop_front();
-        Ok(i_zero - 1)
+        None
     }
-}
 
-impl COBSExt for Vec<u8> {
-    fn cobs_encode(&mut self) -> SerialComResult<usize> {
-        if self.len() == 0 {
-            return Err(SerialComError::COBSTooLittleData);
-        }
-        self.insert(0, 0u8);
-        self.push(0u8);
-        let mut i_last_zero: usize = 0;
-        let qlen = self.len();
-        for i in 1..qlen {
-            if let Some(&0) = self.get(i) {
-                let last_zero_el = self
-                    .get_mut(i_last_zero)
-                    .ok_or(SerialComError::QueueIndexingError)?;
-                let last_zero_el_next = u8::try_from(i - i_last_zero)?;
-                *last_zero_el = last_zero_el_next;
-                i_last_zero = i;
+    /// Push encoded bytes, calling on_frame with each frame that ends in them
+    pub fn push_slice<F>(&mut self, bytes: &[u8], mut on_frame: F)
+    where
+        F: FnMut(SerialComResult<&[u8]>),
+    {
+        for byte in bytes {
+            if let Some(result) = self.push(*byte) {
+                on_frame(result);
             }
         }
-        Ok(self.len())
     }
-    fn cobs_decode(&mut self) -> SerialComResult<usize> {
-        let mut i_zero: usize = 0;
-        let mut comma_found = false;
-        let q_len = self.len();
-        if q_len < 3 {
-            return Err(SerialComError::COBSTooLittleData);
-        }
-        while i_zero < q_len {
-            let i_zero_val = self
-                .get_mut(i_zero)
-                .ok_or(SerialComError::QueueIndexingError)?;
-            if *i_zero_val == 0u8 {
-                comma_found = true;
-                break;
-            }
-            i_zero += usize::from(*i_zero_val);
-            *i_zero_val = 0u8;
+
+    fn push_decoded(&mut self, byte: u8) {
+        if self.frame_len == self.frame.len() {
+            self.error = Some(SerialComError::FrameTooLong);
+            return;
         }
-        if !comma_found {
-            return Err(SerialComError::COBSDecodeNoCommaFound);
-        };
-        self.remove(0);
-        Ok(i_zero - 1)
+        self.frame[self.frame_len] = byte;
+        self.frame_len += 1;
     }
 }
 
 #[test]
 fn test_cobs_encode_decode_back_8() {
     let mut rng = thread_rng();
-    let mut q: arraydeque::ArrayDeque<[u8; 8], arraydeque::Wrapping> =
-        arraydeque::ArrayDeque::new();
-    for _i_trial in 0..10000 {
-        q.clear();
-        let size = rng.gen_range(1, 6);
-        q.push_back_rand(&size, &20);
-        let q_orig = q.clone();
-        q.cobs_encode().unwrap();
-        q.cobs_decode().unwrap();
-        q.pop_back(); // remove comma char
-        assert_eq!(q, q_orig);
+    let mut q: arraydeque::ArrayDeque<u8, 8, arraydeque::Wrapping> = arraydeque::ArrayDeque::new();
+    for variant in [COBSVariant::Standard, COBSVariant::Reduced].iter() {
+        for _i_trial in 0..10000 {
+            q.clear();
+            let size = rng.gen_range(1, 6);
+            q.push_back_rand(&size, &20);
+            let q_orig = q.clone();
+            q.cobs_encode_variant(*variant).unwrap();
+            q.cobs_decode_variant(*variant).unwrap();
+            q.pop_back(); // remove comma char
+            assert_eq!(q, q_orig);
+        }
     }
 }
 
 #[test]
 fn test_cobs_encode_decode_back_16() {
     let mut rng = thread_rng();
-    let mut q: arraydeque::ArrayDeque<[u8; 16], arraydeque::Wrapping> =
-        arraydeque::ArrayDeque::new();
-    for _i_trial in 0..10000 {
-        q.clear();
-        let size = rng.gen_range(1, 14);
-        q.push_back_rand(&size, &20);
-        let q_orig = q.clone();
-        q.cobs_encode().unwrap();
-        q.cobs_decode().unwrap();
-        q.pop_back(); // remove comma char
-        assert_eq!(q, q_orig);
+    let mut q: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> = arraydeque::ArrayDeque::new();
+    for variant in [COBSVariant::Standard, COBSVariant::Reduced].iter() {
+        for _i_trial in 0..10000 {
+            q.clear();
+            let size = rng.gen_range(1, 14);
+            q.push_back_rand(&size, &20);
+            let q_orig = q.clone();
+            q.cobs_encode_variant(*variant).unwrap();
+            q.cobs_decode_variant(*variant).unwrap();
+            q.pop_back(); // remove comma char
+            assert_eq!(q, q_orig);
+        }
     }
 }
 
 #[test]
 fn test_cobs_encode_decode_back_32() {
     let mut rng = thread_rng();
-    let mut q: arraydeque::ArrayDeque<[u8; 32], arraydeque::Wrapping> =
-        arraydeque::ArrayDeque::new();
-    for _i_trial in 0..1000 {
-        q.clear();
-        let size = rng.gen_range(1, 30);
-        q.push_back_rand(&size, &20);
-        let q_orig = q.clone();
-        q.cobs_encode().unwrap();
-        q.cobs_decode().unwrap();
-        q.pop_back(); // remove comma char
-        assert_eq!(q, q_orig);
+    let mut q: arraydeque::ArrayDeque<u8, 32, arraydeque::Wrapping> = arraydeque::ArrayDeque::new();
+    for variant in [COBSVariant::Standard, COBSVariant::Reduced].iter() {
+        for _i_trial in 0..1000 {
+            q.clear();
+            let size = rng.gen_range(1, 30);
+            q.push_back_rand(&size, &20);
+            let q_orig = q.clone();
+            q.cobs_encode_variant(*variant).unwrap();
+            q.cobs_decode_variant(*variant).unwrap();
+            q.pop_back(); // remove comma char
+            assert_eq!(q, q_orig);
+        }
     }
 }
 
 #[test]
 fn test_cobs_encode_decode_back_64() {
     let mut rng = thread_rng();
-    let mut q: arraydeque::ArrayDeque<[u8; 64], arraydeque::Wrapping> =
-        arraydeque::ArrayDeque::new();
-    for _i_trial in 0..1000 {
-        q.clear();
-        let size = rng.gen_range(1, 62);
-        q.push_back_rand(&size, &20);
-        let q_orig = q.clone();
-        q.cobs_encode().unwrap();
-        q.cobs_decode().unwrap();
-        q.pop_back(); // remove comma char
-        assert_eq!(q, q_orig);
+    let mut q: arraydeque::ArrayDeque<u8, 64, arraydeque::Wrapping> = arraydeque::ArrayDeque::new();
+    for variant in [COBSVariant::Standard, COBSVariant::Reduced].iter() {
+        for _i_trial in 0..1000 {
+            q.clear();
+            let size = rng.gen_range(1, 62);
+            q.push_back_rand(&size, &20);
+            let q_orig = q.clone();
+            q.cobs_encode_variant(*variant).unwrap();
+            q.cobs_decode_variant(*variant).unwrap();
+            q.pop_back(); // remove comma char
+            assert_eq!(q, q_orig);
+        }
     }
 }
 
 #[test]
 fn test_cobs_encode_decode_back_128() {
     let mut rng = thread_rng();
-    let mut q: arraydeque::ArrayDeque<[u8; 128], arraydeque::Wrapping> =
+    let mut q: arraydeque::ArrayDeque<u8, 128, arraydeque::Wrapping> =
         arraydeque::ArrayDeque::new();
-    for _i_trial in 0..1000 {
+    for variant in [COBSVariant::Standard, COBSVariant::Reduced].iter() {
+        for _i_trial in 0..1000 {
+            q.clear();
+            let size = rng.gen_range(1, 126);
+            q.push_back_rand(&size, &20);
+            let q_orig = q.clone();
+            q.cobs_encode_variant(*variant).unwrap();
+            q.cobs_decode_variant(*variant).unwrap();
+            q.pop_back(); // remove comma char
+            assert_eq!(q, q_orig);
+        }
+    }
+}
+
+#[cfg(test)]
+fn encode_frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = data.to_vec();
+    frame.cobs_encode().unwrap();
+    frame
+}
+
+#[test]
+fn test_cobs_decoder_chunks() {
+    let mut rng = thread_rng();
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+    let mut stream: Vec<u8> = Vec::new();
+    for _i_msg in 0..200 {
+        let size = rng.gen_range(1, 120);
+        let message: Vec<u8> = (0..size)
+            .map(|_| {
+                if rng.gen_range(0, 4) == 0 {
+                    0
+                } else {
+                    rng.gen()
+                }
+            })
+            .collect();
+        stream.extend(encode_frame(&message));
+        messages.push(message);
+    }
+    let mut decoder = COBSDecoder::new();
+    let mut decoded: Vec<Vec<u8>> = Vec::new();
+    let mut i_byte = 0;
+    while i_byte < stream.len() {
+        let chunk_len = rng.gen_range(1, 40).min(stream.len() - i_byte);
+        decoder.push_slice(&stream[i_byte..i_byte + chunk_len], |frame| {
+            decoded.push(frame.expect("Couldn't decode frame").to_vec())
+        });
+        i_byte += chunk_len;
+    }
+    assert_eq!(decoded, messages);
+}
+
+#[test]
+fn test_cobs_decoder_resync() {
+    let mut decoder = COBSDecoder::new();
+    let mut results: Vec<SerialComResult<Vec<u8>>> = Vec::new();
+    // Garbage before the first comma is reported as a bad frame
+    let mut stream: Vec<u8> = vec![0x37, 0x01, 0];
+    stream.extend(encode_frame(&[1, 0, 2]));
+    // A frame cut short by a comma is reported, then the next one decodes
+    let mut truncated = encode_frame(&[5, 6, 7, 8]);
+    truncated.truncate(3);
+    stream.extend(truncated);
+    stream.push(0);
+    stream.extend(encode_frame(&[9]));
+    // Decodes to more than the decoder can hold
+    stream.extend([2u8; 600].iter());
+    stream.push(0);
+    // Repeated commas are skipped
+    stream.extend([0, 0].iter());
+    stream.extend(encode_frame(&[0, 0]));
+    decoder.push_slice(&stream, |frame| {
+        results.push(frame.map(|frame| frame.to_vec()))
+    });
+    assert_eq!(results.len(), 6);
+    match results[0] {
+        Err(SerialComError::FrameMalformed) => {}
+        _ => panic!("Expected FrameMalformed"),
+    }
+    assert_eq!(results[1].as_ref().unwrap(), &vec![1, 0, 2]);
+    match results[2] {
+        Err(SerialComError::FrameMalformed) => {}
+        _ => panic!("Expected FrameMalformed"),
+    }
+    assert_eq!(results[3].as_ref().unwrap(), &vec![9]);
+    match results[4] {
+        Err(SerialComError::FrameTooLong) => {}
+        _ => panic!("Expected FrameTooLong"),
+    }
+    assert_eq!(results[5].as_ref().unwrap(), &vec![0, 0]);
+}
+
+#[test]
+fn test_cobs_long_runs() {
+    // Examples from the COBS spec
+    let run_254: Vec<u8> = (1..=254).collect();
+    let mut encoded = run_254.clone();
+    encoded.cobs_encode().unwrap();
+    assert_eq!(encoded[0], 0xFF);
+    assert_eq!(encoded[1..255], run_254[..]);
+    assert_eq!(encoded[255..], [0]);
+    let run_255: Vec<u8> = (1..=255).collect();
+    let mut encoded = run_255.clone();
+    encoded.cobs_encode().unwrap();
+    assert_eq!(encoded[0], 0xFF);
+    assert_eq!(encoded[255..], [2, 0xFF, 0]);
+    let mut leading_zero: Vec<u8> = vec![0];
+    leading_zero.extend(1..=255);
+    let mut encoded = leading_zero.clone();
+    encoded.cobs_encode().unwrap();
+    assert_eq!(encoded[..2], [1, 0xFF]);
+    assert_eq!(encoded[256..], [2, 0xFF, 0]);
+
+    let mut rng = thread_rng();
+    let mut q: arraydeque::ArrayDeque<u8, 1024, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    for message in [run_254, run_255, leading_zero].iter() {
+        let mut vec_decoded = message.clone();
+        vec_decoded.cobs_encode().unwrap();
+        let encoded = vec_decoded.clone();
+        assert_eq!(vec_decoded.cobs_decode().unwrap(), message.len());
+        assert_eq!(vec_decoded[..message.len()], message[..]);
         q.clear();
-        let size = rng.gen_range(1, 126);
-        q.push_back_rand(&size, &20);
-        let q_orig = q.clone();
+        q.extend(message.iter().copied());
         q.cobs_encode().unwrap();
-        q.cobs_decode().unwrap();
-        q.pop_back(); // remove comma char
-        assert_eq!(q, q_orig);
+        assert!(q.iter().eq(encoded.iter()));
+        assert_eq!(q.cobs_decode().unwrap(), message.len());
+        assert!(q.iter().take(message.len()).eq(message.iter()));
+        let mut decoder = COBSDecoder::new();
+        let mut frames: Vec<Vec<u8>> = Vec::new();
+        decoder.push_slice(&encoded, |frame| frames.push(frame.unwrap().to_vec()));
+        assert_eq!(frames, vec![message.clone()]);
+    }
+    for _i_trial in 0..200 {
+        let size = rng.gen_range(1, 1000);
+        let message: Vec<u8> = (0..size)
+            .map(|_| {
+                if rng.gen_range(0, 200) == 0 {
+                    0
+                } else {
+                    rng.gen()
+                }
+            })
+            .collect();
+        let mut encoded = message.clone();
+        encoded.cobs_encode().unwrap();
+        assert!(encoded[..encoded.len() - 1].iter().all(|byte| *byte != 0));
+        q.clear();
+        q.extend(message.iter().copied());
+        q.cobs_encode().unwrap();
+        assert!(q.iter().eq(encoded.iter()));
+        assert_eq!(q.cobs_decode().unwrap(), message.len());
+        assert!(q.iter().take(message.len()).eq(message.iter()));
+        encoded.cobs_decode().unwrap();
+        assert_eq!(encoded[..message.len()], message[..]);
+    }
+    q.clear();
+    q.extend((0..1023).map(|_| 1u8));
+    match q.cobs_encode() {
+        Err(SerialComError::QueueTooFull) => {}
+        _ => panic!("Expected QueueTooFull"),
+    }
+}
+
+#[test]
+fn test_encode_into_decode_in_place() {
+    let mut rng = thread_rng();
+    let mut dst: [u8; 1200] = [0; 1200];
+    for _i_trial in 0..500 {
+        let size = rng.gen_range(1, 1000);
+        let message: Vec<u8> = (0..size)
+            .map(|_| {
+                if rng.gen_range(0, 50) == 0 {
+                    0
+                } else {
+                    rng.gen()
+                }
+            })
+            .collect();
+        let mut encoded = message.clone();
+        encoded.cobs_encode().unwrap();
+        let encoded_len = encode_into(&message, &mut dst).expect("Couldn't encode_into");
+        assert_eq!(dst[..encoded_len], encoded[..]);
+        encode_into(&message, &mut dst[..encoded_len - 1])
+            .expect_err("Should be error, dst too small");
+        let decoded_len = decode_in_place(&mut dst).expect("Couldn't decode_in_place");
+        assert_eq!(dst[..decoded_len], message[..]);
+    }
+    encode_into(&[], &mut dst).expect_err("Should be error, nothing to encode");
+    match decode_in_place(&mut [3, 1, 0, 2, 0]) {
+        Err(SerialComError::FrameMalformed) => {}
+        _ => panic!("Expected FrameMalformed"),
+    }
+    match decode_in_place(&mut [3, 1, 2]) {
+        Err(SerialComError::COBSDecodeNoCommaFound) => {}
+        _ => panic!("Expected COBSDecodeNoCommaFound"),
+    }
+}
+
+#[test]
+fn test_cobs_reduced() {
+    let cases: [(&[u8], &[u8]); 5] = [
+        (&[5], &[5, 0]),
+        (&[1, 0, 5], &[2, 1, 5, 0]),
+        (&[1, 2], &[3, 1, 2, 0]),
+        (&[1, 0], &[2, 1, 1, 0]),
+        (&[0x10, 0x20, 0x30], &[0x30, 0x10, 0x20, 0]),
+    ];
+    let mut dst: [u8; 16] = [0; 16];
+    for (message, encoded) in cases.iter() {
+        let encoded_len = COBSVariant::Reduced
+            .encode_into(message, &mut dst)
+            .expect("Couldn't encode_into");
+        assert_eq!(dst[..encoded_len], encoded[..]);
+        let decoded_len = COBSVariant::Reduced
+            .decode_in_place(&mut dst)
+            .expect("Couldn't decode_in_place");
+        assert_eq!(dst[..decoded_len], message[..]);
+        let mut vec_encoded = message.to_vec();
+        vec_encoded
+            .cobs_encode_variant(COBSVariant::Reduced)
+            .unwrap();
+        assert_eq!(vec_encoded, encoded.to_vec());
+        let decoded_len = vec_encoded
+            .cobs_decode_variant(COBSVariant::Reduced)
+            .unwrap();
+        assert_eq!(vec_encoded[..decoded_len], message[..]);
+        let mut decoder: COBSDecoder = COBSDecoder::with_variant(COBSVariant::Reduced);
+        let mut frames: Vec<Vec<u8>> = Vec::new();
+        decoder.push_slice(encoded, |frame| frames.push(frame.unwrap().to_vec()));
+        assert_eq!(frames, vec![message.to_vec()]);
+    }
+
+    let mut rng = thread_rng();
+    let mut stream: Vec<u8> = Vec::new();
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+    let mut saved: usize = 0;
+    for _i_msg in 0..500 {
+        let size = rng.gen_range(1, 600);
+        let message: Vec<u8> = (0..size)
+            .map(|_| {
+                if rng.gen_range(0, 8) == 0 {
+                    0
+                } else {
+                    rng.gen()
+                }
+            })
+            .collect();
+        let mut standard = message.clone();
+        standard.cobs_encode().unwrap();
+        let mut reduced = message.clone();
+        reduced.cobs_encode_variant(COBSVariant::Reduced).unwrap();
+        assert!(reduced.len() == standard.len() || reduced.len() + 1 == standard.len());
+        saved += standard.len() - reduced.len();
+        stream.extend(reduced.iter());
+        messages.push(message);
     }
+    assert!(saved > 0);
+    let mut decoder: COBSDecoder<600> = COBSDecoder::with_variant(COBSVariant::Reduced);
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    decoder.push_slice(&stream, |frame| frames.push(frame.unwrap().to_vec()));
+    assert_eq!(frames, messages);
 }
@@ -0,0 +1,230 @@
+pub mod table;
+
+use core::convert::TryFrom;
+
+use crate::error::{SerialComError, SerialComResult};
+use table::{CrcTable, CHECKSUM_SLICES};
+
+/// Checksum appended to each message
+///
+/// The trailer is the checksum in big-endian order, trailer_len bytes long. Crc16Dnp is the
+/// default and what send_message and receive_message use.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Checksum {
+    #[default]
+    Crc16Dnp,
+    Crc16CcittFalse,
+    Crc16Modbus,
+    /// CRC-32/IEEE, as used by Ethernet and zlib
+    Crc32,
+    /// CRC-32/Castagnoli
+    Crc32c,
+    Crc8,
+    /// No trailer, for links that check integrity some other way
+    None,
+}
+
+impl Checksum {
+    /// Longest trailer of any checksum
+    pub const MAX_TRAILER_LEN: usize = 4;
+
+    /// Number of checksum bytes at the end of each message
+    pub fn trailer_len(self) -> usize {
+        match self {
+            Checksum::Crc16Dnp | Checksum::Crc16CcittFalse | Checksum::Crc16Modbus => 2,
+            Checksum::Crc32 | Checksum::Crc32c => 4,
+            Checksum::Crc8 => 1,
+            Checksum::None => 0,
+        }
+    }
+
+    /// Number identifying the checksum on the wire, e.g. in the hello reply
+    pub fn id(self) -> u8 {
+        match self {
+            Checksum::Crc16Dnp => 0,
+            Checksum::Crc16CcittFalse => 1,
+            Checksum::Crc16Modbus => 2,
+            Checksum::Crc32 => 3,
+            Checksum::Crc32c => 4,
+            Checksum::Crc8 => 5,
+            Checksum::None => 6,
+        }
+    }
+
+    /// The checksum identified by id, if any
+    pub fn from_id(id: u8) -> Option<Checksum> {
+        match id {
+            0 => Some(Checksum::Crc16Dnp),
+            1 => Some(Checksum::Crc16CcittFalse),
+            2 => Some(Checksum::Crc16Modbus),
+            3 => Some(Checksum::Crc32),
+            4 => Some(Checksum::Crc32c),
+            5 => Some(Checksum::Crc8),
+            6 => Some(Checksum::None),
+            _ => None,
+        }
+    }
+
+    /// Compute the checksum of slices, as if they were one slice
+    pub fn compute(self, slices: &[&[u8]]) -> u32 {
+        let table: &CrcTable<CHECKSUM_SLICES> = match self {
+            Checksum::Crc16Dnp => &table::CRC16_DNP_TABLE,
+            Checksum::Crc16CcittFalse => &table::CRC16_CCITT_FALSE_TABLE,
+            Checksum::Crc16Modbus => &table::CRC16_MODBUS_TABLE,
+            Checksum::Crc32 => &table::CRC32_TABLE,
+            Checksum::Crc32c => &table::CRC32C_TABLE,
+            Checksum::Crc8 => &table::CRC8_TABLE,
+            Checksum::None => return 0,
+        };
+        let mut digest = table.digest();
+        for slice in slices {
+            digest.update(slice);
+        }
+        digest.finalize()
+    }
+
+    /// Compute the trailer for slices
+    ///
+    /// returns a buffer with the trailer in its first trailer_len bytes
+    pub fn trailer(self, slices: &[&[u8]]) -> [u8; Checksum::MAX_TRAILER_LEN] {
+        let crc_bytes = self.compute(slices).to_be_bytes();
+        let mut trailer: [u8; Checksum::MAX_TRAILER_LEN] = [0; Checksum::MAX_TRAILER_LEN];
+        trailer[..self.trailer_len()]
+            .copy_from_slice(&crc_bytes[Checksum::MAX_TRAILER_LEN - self.trailer_len()..]);
+        trailer
+    }
+
+    /// Check the trailer at the end of message
+    ///
+    /// returns message without the trailer
+    pub fn check(self, message: &[u8]) -> SerialComResult<&[u8]> {
+        let msg_len = message
+            .len()
+            .checked_sub(self.trailer_len())
+            .ok_or(SerialComError::COBSTooLittleData)?;
+        let (message, received) = message.split_at(msg_len);
+        if received != &self.trailer(&[message])[..self.trailer_len()] {
+            return Err(SerialComError::CRCMismatch);
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::str::FromStr for Checksum {
+    type Err = std::io::Error;
+    fn from_str(text: &str) -> Result<Checksum, std::io::Error> {
+        match text {
+            "crc16-dnp" => Ok(Checksum::Crc16Dnp),
+            "crc16-ccitt-false" => Ok(Checksum::Crc16CcittFalse),
+            "crc16-modbus" => Ok(Checksum::Crc16Modbus),
+            "crc32" => Ok(Checksum::Crc32),
+            "crc32c" => Ok(Checksum::Crc32c),
+            "crc8" => Ok(Checksum::Crc8),
+            "none" => Ok(Checksum::None),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown checksum: {}", text),
+            )),
+        }
+    }
+}
+
+/// Trait to calculate CRCs
+///
+/// Implemented for arraydeque::ArrayDeque<u8, N, arraydeque::Wrapping> of any capacity N, and
+/// Vec<u8>
+pub trait CRCExt {
+    /// Compute the CRC
+    ///
+    /// Assumes self is some kind of buffer containing the message
+    ///
+    /// msg_len is the number of elements or size of buffer to compute the CRC on
+    ///
+    /// returns SerialComResult containing CRC
+    ///
+    fn compute_crc(&mut self, msg_len: usize) -> SerialComResult<u16>;
+    /// Compute the CRC, return as high and low bytes
+    ///
+    /// Assumes self is some kind of buffer containing the message
+    ///
+    /// msg_len is the number of elements or size of buffer to compute the CRC on
+    ///
+    /// returns SerialComResult containing a tuple of two bytes. The .0 byte is the upper 8 bits of
+    /// the CRC. The .1 byte is the lower 8 bits of the CRC.
+    ///
+    fn compute_crc_bytes(&mut self, msg_len: usize) -> SerialComResult<(u8, u8)> {
+        let crc_num: u16 = self.compute_crc(msg_len)?;
+        let crc_high_byte = u8::try_from(crc_num >> 8 & 0xFF)?;
+        let crc_low_byte = u8::try_from(crc_num & 0xFF)?;
+        Ok((crc_high_byte, crc_low_byte))
+    }
+}
+
+/// Compute the CRC of a whole slice
+pub fn compute_crc_slice(data: &[u8]) -> SerialComResult<u16> {
+    compute_crc_slices(&[data])
+}
+
+/// Compute the CRC of slices, as if they were one slice
+pub fn compute_crc_slices(slices: &[&[u8]]) -> SerialComResult<u16> {
+    Ok(u16::try_from(Checksum::Crc16Dnp.compute(slices))?)
+}
+
+impl<const N: usize> CRCExt for arraydeque::ArrayDeque<u8, N, arraydeque::Wrapping> {
+    fn compute_crc(&mut self, msg_len: usize) -> SerialComResult<u16> {
+        let mut crc16 = table::CRC16_DNP_TABLE.digest();
+        let (slice1, slice2) = self.as_slices();
+        let slice1_len = slice1.len();
+        if msg_len <= slice1_len {
+            crc16.update(&slice1[0..msg_len]);
+        } else {
+            crc16.update(slice1);
+            crc16.update(&slice2[0..(msg_len - slice1_len)]);
+        }
+        let crc_num: u16 = u16::try_from(crc16.finalize())?;
+        Ok(crc_num)
+    }
+}
+
+#[cfg(feature = "std")]
+impl CRCExt for Vec<u8> {
+    fn compute_crc(&mut self, msg_len: usize) -> SerialComResult<u16> {
+        let crc_num: u16 = u16::try_from(table::CRC16_DNP_TABLE.checksum(&self[0..msg_len]))?;
+        Ok(crc_num)
+    }
+}
+
+#[test]
+fn test_checksum_check_values() {
+    // The standard check value of each CRC is its CRC of "123456789"
+    let check_input: &[u8] = b"123456789";
+    let expected: [(Checksum, u32); 7] = [
+        (Checksum::Crc16Dnp, 0xEA82),
+        (Checksum::Crc16CcittFalse, 0x29B1),
+        (Checksum::Crc16Modbus, 0x4B37),
+        (Checksum::Crc32, 0xCBF43926),
+        (Checksum::Crc32c, 0xE3069283),
+        (Checksum::Crc8, 0xF4),
+        (Checksum::None, 0),
+    ];
+    for (checksum, check_value) in expected.iter() {
+        assert_eq!(
+            checksum.compute(&[&check_input[..4], &check_input[4..]]),
+            *check_value
+        );
+        let mut message: Vec<u8> = check_input.to_vec();
+        message.extend(checksum.trailer(&[check_input])[..checksum.trailer_len()].iter());
+        assert_eq!(message.len(), check_input.len() + checksum.trailer_len());
+        assert_eq!(checksum.check(&message).unwrap(), check_input);
+        if *checksum != Checksum::None {
+            message[0] ^= 1;
+            checksum
+                .check(&message)
+                .expect_err("Should be error, corrupted message");
+        }
+    }
+    Checksum::Crc32
+        .check(&[1, 2, 3])
+        .expect_err("Should be error, shorter than trailer");
+}
@@ -0,0 +1,283 @@
+//! Table-driven CRC engine
+//!
+//! The tables are generated at compile time by const fns, so they end up in flash on devices.
+//! CrcTable<1> is computed a byte at a time with a 1 KiB table. CrcTable<8> also uses
+//! slice-by-8, taking 8 bytes per step with an 8 KiB table, which is several times faster on long
+//! messages.
+
+use core::convert::TryFrom;
+
+/// Parameters of a CRC, as given in the usual CRC catalogues
+///
+/// Only CRCs that reflect both their input and output, or neither, are supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrcParams {
+    /// Number of bits, 8 to 32
+    pub width: u32,
+    /// Polynomial, without the top bit, in normal (not reversed) form
+    pub poly: u32,
+    /// Register value before the first byte, not reflected
+    pub init: u32,
+    /// Whether bytes go in, and the CRC comes out, least significant bit first
+    pub reflected: bool,
+    /// Value XORed with the register to get the CRC
+    pub xorout: u32,
+}
+
+/// Lookup tables for one CRC
+///
+/// SLICES is 1 for byte-wise tables or 8 for slice-by-8 tables.
+pub struct CrcTable<const SLICES: usize> {
+    params: CrcParams,
+    table: [[u32; 256]; SLICES],
+}
+
+/// Reverse the low width bits of value
+const fn reflect(value: u32, width: u32) -> u32 {
+    value.reverse_bits() >> (32 - width)
+}
+
+impl<const SLICES: usize> CrcTable<SLICES> {
+    pub const fn new(params: CrcParams) -> CrcTable<SLICES> {
+        let mut table = [[0u32; 256]; SLICES];
+        let mut i_byte: usize = 0;
+        while i_byte < 256 {
+            let mut crc: u32;
+            let mut i_bit = 0;
+            if params.reflected {
+                let poly = reflect(params.poly, params.width);
+                crc = i_byte as u32;
+                while i_bit < 8 {
+                    crc = if crc & 1 == 1 {
+                        crc >> 1 ^ poly
+                    } else {
+                        crc >> 1
+                    };
+                    i_bit += 1;
+                }
+            } else {
+                // Unreflected CRCs are computed in the top width bits of the register
+                let poly = params.poly << (32 - params.width);
+                crc = (i_byte as u32) << 24;
+                while i_bit < 8 {
+                    crc = if crc & 0x8000_0000 != 0 {
+                        crc << 1 ^ poly
+                    } else {
+                        crc << 1
+                    };
+                    i_bit += 1;
+                }
+            }
+            table[0][i_byte] = crc;
+            i_byte += 1;
+        }
+        // table[i_slice][i_byte] is the register after i_byte then i_slice 0 bytes
+        let mut i_slice: usize = 1;
+        while i_slice < SLICES {
+            let mut i_byte: usize = 0;
+            while i_byte < 256 {
+                let prev = table[i_slice - 1][i_byte];
+                table[i_slice][i_byte] = if params.reflected {
+                    prev >> 8 ^ table[0][(prev & 0xFF) as usize]
+                } else {
+                    prev << 8 ^ table[0][(prev >> 24) as usize]
+                };
+                i_byte += 1;
+            }
+            i_slice += 1;
+        }
+        CrcTable { params, table }
+    }
+
+    pub fn params(&self) -> &CrcParams {
+        &self.params
+    }
+
+    /// Start computing a CRC, to be fed with CrcDigest::update
+    pub fn digest(&self) -> CrcDigest<'_, SLICES> {
+        let state = if self.params.reflected {
+            reflect(self.params.init, self.params.width)
+        } else {
+            self.params.init << (32 - self.params.width)
+        };
+        CrcDigest { table: self, state }
+    }
+
+    /// Compute the CRC of bytes in one go
+    pub fn checksum(&self, bytes: &[u8]) -> u32 {
+        let mut digest = self.digest();
+        digest.update(bytes);
+        digest.finalize()
+    }
+}
+
+/// A CRC being computed
+///
+/// update can be called any number of times, e.g. once for each of the two slices of a wrapped
+/// ArrayDeque, and gives the same CRC as one call with the bytes joined.
+#[derive(Clone)]
+pub struct CrcDigest<'a, const SLICES: usize> {
+    table: &'a CrcTable<SLICES>,
+    state: u32,
+}
+
+impl<'a, const SLICES: usize> CrcDigest<'a, SLICES> {
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        let table = &self.table.table;
+        let reflected = self.table.params.reflected;
+        if let Ok(table8) = <&[[u32; 256]; 8]>::try_from(&table[..]) {
+            let mut chunks = bytes.chunks_exact(8);
+            for chunk in &mut chunks {
+                self.state = update_slice_by_8(table8, reflected, self.state, chunk);
+            }
+            bytes = chunks.remainder();
+        }
+        let table = &table[0];
+        for byte in bytes {
+            self.state = if reflected {
+                table[((self.state ^ u32::from(*byte)) & 0xFF) as usize] ^ self.state >> 8
+            } else {
+                table[((self.state >> 24) ^ u32::from(*byte)) as usize] ^ self.state << 8
+            };
+        }
+    }
+
+    /// The CRC of all the bytes given to update so far
+    pub fn finalize(&self) -> u32 {
+        let params = &self.table.params;
+        let crc = if params.reflected {
+            self.state
+        } else {
+            self.state >> (32 - params.width)
+        };
+        crc ^ params.xorout
+    }
+}
+
+/// Take 8 bytes into the register
+fn update_slice_by_8(table: &[[u32; 256]; 8], reflected: bool, state: u32, chunk: &[u8]) -> u32 {
+    let lookup =
+        |i_slice: usize, word: u32, shift: u32| table[i_slice][(word >> shift & 0xFF) as usize];
+    if reflected {
+        let first = state ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        let second = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        lookup(7, first, 0)
+            ^ lookup(6, first, 8)
+            ^ lookup(5, first, 16)
+            ^ lookup(4, first, 24)
+            ^ lookup(3, second, 0)
+            ^ lookup(2, second, 8)
+            ^ lookup(1, second, 16)
+            ^ lookup(0, second, 24)
+    } else {
+        let first = state ^ u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        let second = u32::from_be_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        lookup(7, first, 24)
+            ^ lookup(6, first, 16)
+            ^ lookup(5, first, 8)
+            ^ lookup(4, first, 0)
+            ^ lookup(3, second, 24)
+            ^ lookup(2, second, 16)
+            ^ lookup(1, second, 8)
+            ^ lookup(0, second, 0)
+    }
+}
+
+/// Slices of the tables behind Checksum: 8 with the crc-slice-by-8 feature, else 1
+#[cfg(feature = "crc-slice-by-8")]
+pub const CHECKSUM_SLICES: usize = 8;
+#[cfg(not(feature = "crc-slice-by-8"))]
+pub const CHECKSUM_SLICES: usize = 1;
+
+pub const CRC16_DNP: CrcParams = CrcParams {
+    width: 16,
+    poly: 0x3D65,
+    init: 0,
+    reflected: true,
+    xorout: 0xFFFF,
+};
+
+pub const CRC16_CCITT_FALSE: CrcParams = CrcParams {
+    width: 16,
+    poly: 0x1021,
+    init: 0xFFFF,
+    reflected: false,
+    xorout: 0,
+};
+
+pub const CRC16_MODBUS: CrcParams = CrcParams {
+    width: 16,
+    poly: 0x8005,
+    init: 0xFFFF,
+    reflected: true,
+    xorout: 0,
+};
+
+pub const CRC32: CrcParams = CrcParams {
+    width: 32,
+    poly: 0x04C1_1DB7,
+    init: 0xFFFF_FFFF,
+    reflected: true,
+    xorout: 0xFFFF_FFFF,
+};
+
+pub const CRC32C: CrcParams = CrcParams {
+    width: 32,
+    poly: 0x1EDC_6F41,
+    init: 0xFFFF_FFFF,
+    reflected: true,
+    xorout: 0xFFFF_FFFF,
+};
+
+pub const CRC8: CrcParams = CrcParams {
+    width: 8,
+    poly: 0x07,
+    init: 0,
+    reflected: false,
+    xorout: 0,
+};
+
+pub static CRC16_DNP_TABLE: CrcTable<CHECKSUM_SLICES> = CrcTable::new(CRC16_DNP);
+pub static CRC16_CCITT_FALSE_TABLE: CrcTable<CHECKSUM_SLICES> = CrcTable::new(CRC16_CCITT_FALSE);
+pub static CRC16_MODBUS_TABLE: CrcTable<CHECKSUM_SLICES> = CrcTable::new(CRC16_MODBUS);
+pub static CRC32_TABLE: CrcTable<CHECKSUM_SLICES> = CrcTable::new(CRC32);
+pub static CRC32C_TABLE: CrcTable<CHECKSUM_SLICES> = CrcTable::new(CRC32C);
+pub static CRC8_TABLE: CrcTable<CHECKSUM_SLICES> = CrcTable::new(CRC8);
+
+#[cfg(test)]
+use crc_any::CRC;
+#[cfg(test)]
+use rand::prelude::*;
+
+#[test]
+fn test_crc_table_matches_crc_any() {
+    let mut rng = thread_rng();
+    let cases: [(CrcParams, fn() -> CRC); 6] = [
+        (CRC16_DNP, CRC::crc16dnp),
+        (CRC16_CCITT_FALSE, CRC::crc16ccitt_false),
+        (CRC16_MODBUS, CRC::crc16modbus),
+        (CRC32, CRC::crc32),
+        (CRC32C, CRC::crc32c),
+        (CRC8, CRC::crc8),
+    ];
+    for (params, reference) in cases.iter() {
+        let bytewise: CrcTable<1> = CrcTable::new(*params);
+        let slice_by_8: CrcTable<8> = CrcTable::new(*params);
+        for _trial in 0..200 {
+            let len = rng.gen_range(0, 100);
+            let mut data: Vec<u8> = vec![0; len];
+            rng.fill(&mut data[..]);
+            let mut crc = reference();
+            crc.digest(&data);
+            let expected = crc.get_crc() as u32;
+            assert_eq!(bytewise.checksum(&data), expected);
+            assert_eq!(slice_by_8.checksum(&data), expected);
+            // Split in two, as ArrayDeque::as_slices does
+            let split = rng.gen_range(0, len + 1);
+            let mut digest = slice_by_8.digest();
+            digest.update(&data[..split]);
+            digest.update(&data[split..]);
+            assert_eq!(digest.finalize(), expected);
+        }
+    }
+}
@@ -1,8 +1,19 @@
-use std::num::TryFromIntError;
+use crate::binarycom::errorreply::DeviceErrorCode;
+#[cfg(feature = "std")]
+use crate::binarycom::errorreply::ErrorReply;
+#[cfg(feature = "std")]
+use crate::binarycom::hello::DeviceInfo;
+use crate::binarycom::hello::{PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR};
+#[cfg(feature = "std")]
+use crate::binarycom::hostreceiver::TaggedReply;
+use core::num::TryFromIntError;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
 use std::sync::mpsc;
 
 // See https://doc.rust-lang.org/stable/rust-by-example/error/multiple_error_types/wrap_error.html
-pub type SerialComResult<T> = std::result::Result<T, SerialComError>;
+pub type SerialComResult<T> = core::result::Result<T, SerialComError>;
 
 #[derive(Debug)]
 pub enum SerialComError {
@@ -10,17 +21,53 @@ pub enum SerialComError {
     QueueIndexingError,
     COBSDecodeNoCommaFound,
     COBSTooLittleData,
+    /// A frame too long for the decoder's buffer, from any Framer
+    FrameTooLong,
+    /// A frame that ended mid-block, was aborted or had an invalid escape, from any Framer
+    FrameMalformed,
     SliceTooSmall,
     SliceTooBig,
     CRCMismatch,
+    RetriesExhausted,
+    /// No reply from the device, after as many tries as allowed
+    Timeout,
+    /// The receive thread has closed, so no more replies can arrive
+    Disconnected,
+    FragmentOutOfOrder,
+    FragmentTimeout,
+    /// Stream command whose word size or words per sample unpack_stream doesn't take
+    UnsupportedStreamCommand(u8),
+    /// Stream data that isn't a whole number of words
+    StreamDataLength,
+    /// Device protocol version, major and minor
+    IncompatibleVersion(u8, u8),
+    InvalidDeviceInfo,
+    /// The device rejected a request, see binarycom::errorreply
+    DeviceError {
+        code: DeviceErrorCode,
+        reg_num: u16,
+    },
     TryFromInt(TryFromIntError),
-    MPSCSendErrorRegNum(mpsc::SendError<u16>),
-    MPSCSendErrorRegNumVal(mpsc::SendError<(u16, u32)>),
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    #[cfg(feature = "std")]
+    MPSCSendErrorRegNum(mpsc::SendError<Result<u16, ErrorReply>>),
+    #[cfg(feature = "std")]
+    MPSCSendErrorRegNumVal(mpsc::SendError<Result<(u16, u32), ErrorReply>>),
+    #[cfg(feature = "std")]
     MPSCSendErrorStream(mpsc::SendError<(u8, Vec<u8>)>),
+    #[cfg(feature = "std")]
+    MPSCSendErrorTagged(mpsc::SendError<(u8, Result<TaggedReply, ErrorReply>)>),
+    #[cfg(feature = "std")]
+    MPSCSendErrorRegs(mpsc::SendError<Result<(u16, Vec<u32>), ErrorReply>>),
+    #[cfg(feature = "std")]
+    MPSCSendErrorDeviceInfo(mpsc::SendError<DeviceInfo>),
+    #[cfg(feature = "std")]
+    MPSCSendErrorPing(mpsc::SendError<u8>),
 }
 
-impl std::fmt::Display for SerialComError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for SerialComError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match *self {
             SerialComError::QueueTooFull => {
                 write!(f, "Queue too full, need room for overhead and comma bytes.")
@@ -35,19 +82,76 @@ impl std::fmt::Display for SerialComError {
                 f,
                 "Couldn't encode/decode message because message too short."
             ),
+            SerialComError::FrameTooLong => write!(f, "Frame too long for the decoder buffer."),
+            SerialComError::FrameMalformed => {
+                write!(f, "Frame truncated, aborted or with an invalid escape.")
+            }
             SerialComError::SliceTooSmall => {
                 write!(f, "Slice too small to hold data part of message")
             }
             SerialComError::SliceTooBig => write!(f, "Data slice too big to fit into message"),
             SerialComError::CRCMismatch => write!(f, "Received and computed CRCs don't match"),
+            SerialComError::RetriesExhausted => {
+                write!(f, "No reply after retransmitting as many times as allowed")
+            }
+            SerialComError::Timeout => write!(f, "No reply from the device in time"),
+            SerialComError::Disconnected => {
+                write!(f, "Receive thread closed, no more replies can arrive")
+            }
+            SerialComError::FragmentOutOfOrder => {
+                write!(f, "Fragment missing or out of order, message dropped")
+            }
+            SerialComError::FragmentTimeout => {
+                write!(
+                    f,
+                    "Fragmented message not completed in time, message dropped"
+                )
+            }
+            SerialComError::UnsupportedStreamCommand(command) => {
+                write!(
+                    f,
+                    "Can't unpack stream messages with command {:#04x}",
+                    command
+                )
+            }
+            SerialComError::StreamDataLength => {
+                write!(f, "Stream data length isn't a whole number of words")
+            }
+            SerialComError::IncompatibleVersion(major, minor) => write!(
+                f,
+                "Device speaks protocol version {}.{}, incompatible with the host's {}.{}",
+                major, minor, PROTOCOL_VERSION_MAJOR, PROTOCOL_VERSION_MINOR
+            ),
+            SerialComError::InvalidDeviceInfo => {
+                write!(f, "Unknown register width or checksum in device info")
+            }
+            SerialComError::DeviceError { code, reg_num } => write!(
+                f,
+                "Device rejected request for register {}: {}",
+                reg_num, code
+            ),
             SerialComError::TryFromInt(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
+            SerialComError::Io(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
             SerialComError::MPSCSendErrorRegNum(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
             SerialComError::MPSCSendErrorRegNumVal(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
             SerialComError::MPSCSendErrorStream(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorTagged(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorRegs(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorDeviceInfo(ref e) => e.fmt(f),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorPing(ref e) => e.fmt(f),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for SerialComError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
@@ -55,13 +159,38 @@ impl std::error::Error for SerialComError {
             SerialComError::QueueIndexingError => None,
             SerialComError::COBSDecodeNoCommaFound => None,
             SerialComError::COBSTooLittleData => None,
+            SerialComError::FrameTooLong => None,
+            SerialComError::FrameMalformed => None,
             SerialComError::SliceTooSmall => None,
             SerialComError::SliceTooBig => None,
             SerialComError::CRCMismatch => None,
+            SerialComError::RetriesExhausted => None,
+            SerialComError::Timeout => None,
+            SerialComError::Disconnected => None,
+            SerialComError::FragmentOutOfOrder => None,
+            SerialComError::FragmentTimeout => None,
+            SerialComError::UnsupportedStreamCommand(_) => None,
+            SerialComError::StreamDataLength => None,
+            SerialComError::IncompatibleVersion(_, _) => None,
+            SerialComError::InvalidDeviceInfo => None,
+            SerialComError::DeviceError { .. } => None,
             SerialComError::TryFromInt(ref e) => Some(e),
+            #[cfg(feature = "std")]
+            SerialComError::Io(ref e) => Some(e),
+            #[cfg(feature = "std")]
             SerialComError::MPSCSendErrorRegNum(ref e) => Some(e),
+            #[cfg(feature = "std")]
             SerialComError::MPSCSendErrorRegNumVal(ref e) => Some(e),
+            #[cfg(feature = "std")]
             SerialComError::MPSCSendErrorStream(ref e) => Some(e),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorTagged(ref e) => Some(e),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorRegs(ref e) => Some(e),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorDeviceInfo(ref e) => Some(e),
+            #[cfg(feature = "std")]
+            SerialComError::MPSCSendErrorPing(ref e) => Some(e),
         }
     }
 }
@@ -72,20 +201,58 @@ impl From<TryFromIntError> for SerialComError {
     }
 }
 
-impl From<mpsc::SendError<(u16, u32)>> for SerialComError {
-    fn from(err: mpsc::SendError<(u16, u32)>) -> SerialComError {
+#[cfg(feature = "std")]
+impl From<io::Error> for SerialComError {
+    fn from(err: io::Error) -> SerialComError {
+        SerialComError::Io(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<mpsc::SendError<Result<(u16, u32), ErrorReply>>> for SerialComError {
+    fn from(err: mpsc::SendError<Result<(u16, u32), ErrorReply>>) -> SerialComError {
         SerialComError::MPSCSendErrorRegNumVal(err)
     }
 }
 
-impl From<mpsc::SendError<u16>> for SerialComError {
-    fn from(err: mpsc::SendError<u16>) -> SerialComError {
+#[cfg(feature = "std")]
+impl From<mpsc::SendError<Result<u16, ErrorReply>>> for SerialComError {
+    fn from(err: mpsc::SendError<Result<u16, ErrorReply>>) -> SerialComError {
         SerialComError::MPSCSendErrorRegNum(err)
     }
 }
 
+#[cfg(feature = "std")]
 impl From<mpsc::SendError<(u8, Vec<u8>)>> for SerialComError {
     fn from(err: mpsc::SendError<(u8, Vec<u8>)>) -> SerialComError {
         SerialComError::MPSCSendErrorStream(err)
     }
 }
+
+#[cfg(feature = "std")]
+impl From<mpsc::SendError<(u8, Result<TaggedReply, ErrorReply>)>> for SerialComError {
+    fn from(err: mpsc::SendError<(u8, Result<TaggedReply, ErrorReply>)>) -> SerialComError {
+        SerialComError::MPSCSendErrorTagged(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<mpsc::SendError<Result<(u16, Vec<u32>), ErrorReply>>> for SerialComError {
+    fn from(err: mpsc::SendError<Result<(u16, Vec<u32>), ErrorReply>>) -> SerialComError {
+        SerialComError::MPSCSendErrorRegs(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<mpsc::SendError<DeviceInfo>> for SerialComError {
+    fn from(err: mpsc::SendError<DeviceInfo>) -> SerialComError {
+        SerialComError::MPSCSendErrorDeviceInfo(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<mpsc::SendError<u8>> for SerialComError {
+    fn from(err: mpsc::SendError<u8>) -> SerialComError {
+        SerialComError::MPSCSendErrorPing(err)
+    }
+}
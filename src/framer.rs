@@ -0,0 +1,457 @@
+//! Framers: how messages are delimited on the wire
+//!
+//! COBS is the default framing. SLIP (RFC 1055) and an HDLC-like 0x7E flag / 0x7D escape framing
+//! are there for devices that speak those. The message inside the frame ([command][data][crc]),
+//! and everything above it, is the same whichever framer is used.
+
+use crate::cobs::{COBSDecoder, COBSVariant, COBS_DECODER_MAX_FRAME};
+use crate::error::{SerialComError, SerialComResult};
+
+/// Encodes messages into frames and decodes frames from a byte stream
+///
+/// The decoding side is stateful: bytes are pushed one at a time in whatever chunks they arrive
+/// in, and each complete frame is returned when its last byte is pushed. A bad frame is reported
+/// as an error once it ends, and decoding picks up again with the next frame.
+pub trait Framer {
+    /// Encode the message made of parts, in order, into dst as one frame
+    ///
+    /// returns the frame length
+    fn encode_into(&self, parts: &[&[u8]], dst: &mut [u8]) -> SerialComResult<usize>;
+
+    /// Push one received byte
+    ///
+    /// returns None while a frame is in progress. When byte ends a frame, returns the decoded
+    /// message, or the error that made the frame bad. Empty frames are skipped.
+    fn push(&mut self, byte: u8) -> Option<SerialComResult<&[u8]>>;
+
+    /// Drop any partially received frame
+    fn reset(&mut self);
+
+    /// Push received bytes, calling on_frame with each frame that ends in them
+    fn push_slice<F>(&mut self, bytes: &[u8], mut on_frame: F)
+    where
+        F: FnMut(SerialComResult<&[u8]>),
+        Self: Sized,
+    {
+        for byte in bytes {
+            if let Some(result) = self.push(*byte) {
+                on_frame(result);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<F: Framer + ?Sized> Framer for Box<F> {
+    fn encode_into(&self, parts: &[&[u8]], dst: &mut [u8]) -> SerialComResult<usize> {
+        (**self).encode_into(parts, dst)
+    }
+    fn push(&mut self, byte: u8) -> Option<SerialComResult<&[u8]>> {
+        (**self).push(byte)
+    }
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+}
+
+/// Which framer a link uses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    Cobs(COBSVariant),
+    Slip,
+    Hdlc,
+}
+
+impl Default for Framing {
+    fn default() -> Framing {
+        Framing::Cobs(COBSVariant::Standard)
+    }
+}
+
+impl Framing {
+    /// Encode the message made of parts into dst, as this framing's framer would
+    ///
+    /// returns the frame length
+    pub fn encode_into(self, parts: &[&[u8]], dst: &mut [u8]) -> SerialComResult<usize> {
+        match self {
+            Framing::Cobs(variant) => {
+                variant.encode_iter_into(parts.iter().flat_map(|part| part.iter().copied()), dst)
+            }
+            Framing::Slip => stuffed_encode_into(&SLIP_STUFFING, parts, dst),
+            Framing::Hdlc => stuffed_encode_into(&HDLC_STUFFING, parts, dst),
+        }
+    }
+
+    /// A framer for this framing, decoding messages of up to N bytes
+    #[cfg(feature = "std")]
+    pub fn new_framer<const N: usize>(self) -> Box<dyn Framer + Send> {
+        match self {
+            Framing::Cobs(variant) => Box::new(CobsFramer::<N>::with_variant(variant)),
+            Framing::Slip => Box::new(SlipFramer::<N>::default()),
+            Framing::Hdlc => Box::new(HdlcFramer::<N>::default()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::str::FromStr for Framing {
+    type Err = std::io::Error;
+    fn from_str(text: &str) -> Result<Framing, std::io::Error> {
+        match text {
+            "cobs" => Ok(Framing::Cobs(COBSVariant::Standard)),
+            "cobsr" => Ok(Framing::Cobs(COBSVariant::Reduced)),
+            "slip" => Ok(Framing::Slip),
+            "hdlc" => Ok(Framing::Hdlc),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown framing: {}", text),
+            )),
+        }
+    }
+}
+
+/// COBS framing, with the 0 byte as the frame delimiter
+///
+/// N is the max decoded message size.
+pub struct CobsFramer<const N: usize = COBS_DECODER_MAX_FRAME> {
+    variant: COBSVariant,
+    decoder: COBSDecoder<N>,
+}
+
+impl<const N: usize> Default for CobsFramer<N> {
+    fn default() -> CobsFramer<N> {
+        CobsFramer::with_variant(COBSVariant::Standard)
+    }
+}
+
+impl<const N: usize> CobsFramer<N> {
+    pub fn with_variant(variant: COBSVariant) -> CobsFramer<N> {
+        CobsFramer {
+            variant,
+            decoder: COBSDecoder::with_variant(variant),
+        }
+    }
+}
+
+impl<const N: usize> Framer for CobsFramer<N> {
+    fn encode_into(&self, parts: &[&[u8]], dst: &mut [u8]) -> SerialComResult<usize> {
+        Framing::Cobs(self.variant).encode_into(parts, dst)
+    }
+    fn push(&mut self, byte: u8) -> Option<SerialComResult<&[u8]>> {
+        self.decoder.push(byte)
+    }
+    fn reset(&mut self) {
+        self.decoder.reset();
+    }
+}
+
+/// Delimiter and escape bytes of a byte stuffing framing
+struct Stuffing {
+    flag: u8,
+    escape: u8,
+    /// The byte sent after the escape byte in place of byte
+    escaped: fn(u8) -> u8,
+    /// The byte an escaped byte stands for, or None if it isn't a valid escape
+    unescaped: fn(u8) -> Option<u8>,
+}
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+const SLIP_STUFFING: Stuffing = Stuffing {
+    flag: SLIP_END,
+    escape: SLIP_ESC,
+    escaped: |byte| {
+        if byte == SLIP_END {
+            SLIP_ESC_END
+        } else {
+            SLIP_ESC_ESC
+        }
+    },
+    unescaped: |byte| match byte {
+        SLIP_ESC_END => Some(SLIP_END),
+        SLIP_ESC_ESC => Some(SLIP_ESC),
+        _ => None,
+    },
+};
+
+const HDLC_FLAG: u8 = 0x7E;
+const HDLC_ESCAPE: u8 = 0x7D;
+
+const HDLC_STUFFING: Stuffing = Stuffing {
+    flag: HDLC_FLAG,
+    escape: HDLC_ESCAPE,
+    escaped: |byte| byte ^ 0x20,
+    unescaped: |byte| Some(byte ^ 0x20),
+};
+
+/// Decoder shared by the byte stuffing framers
+struct StuffedDecoder<const N: usize> {
+    frame: [u8; N],
+    frame_len: usize,
+    /// Set after an escape byte
+    escaped: bool,
+    /// Set when the current frame is bad; its remaining bytes are dropped
+    error: Option<SerialComError>,
+}
+
+impl<const N: usize> StuffedDecoder<N> {
+    fn new() -> StuffedDecoder<N> {
+        StuffedDecoder {
+            frame: [0; N],
+            frame_len: 0,
+            escaped: false,
+            error: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.frame_len = 0;
+        self.escaped = false;
+        self.error = None;
+    }
+
+    fn push(&mut self, stuffing: &Stuffing, byte: u8) -> Option<SerialComResult<&[u8]>> {
+        if byte == stuffing.flag {
+            let result = if self.escaped {
+                // An escape right before the flag aborts the frame
+                Some(Err(SerialComError::FrameMalformed))
+            } else if let Some(frame_error) = self.error.take() {
+                Some(Err(frame_error))
+            } else if self.frame_len == 0 {
+                None
+            } else {
+                Some(Ok(()))
+            };
+            let frame_len = self.frame_len;
+            self.reset();
+            return result.map(|result| result.map(move |_| &self.frame[..frame_len]));
+        }
+        if self.error.is_some() {
+            return None;
+        }
+        if self.escaped {
+            self.escaped = false;
+            match (stuffing.unescaped)(byte) {
+                Some(unescaped) => self.push_decoded(unescaped),
+                None => self.error = Some(SerialComError::FrameMalformed),
+            }
+        } else if byte == stuffing.escape {
+            self.escaped = true;
+        } else {
+            self.push_decoded(byte);
+        }
+        None
+    }
+
+    fn push_decoded(&mut self, byte: u8) {
+        if self.frame_len == N {
+            self.error = Some(SerialComError::FrameTooLong);
+            return;
+        }
+        self.frame[self.frame_len] = byte;
+        self.frame_len += 1;
+    }
+}
+
+/// Encode parts between two flag bytes, escaping the flag and escape bytes
+fn stuffed_encode_into(
+    stuffing: &Stuffing,
+    parts: &[&[u8]],
+    dst: &mut [u8],
+) -> SerialComResult<usize> {
+    let mut i_dst: usize = 0;
+    let mut put = |byte: u8| -> SerialComResult<()> {
+        *dst.get_mut(i_dst).ok_or(SerialComError::SliceTooSmall)? = byte;
+        i_dst += 1;
+        Ok(())
+    };
+    put(stuffing.flag)?;
+    for byte in parts.iter().flat_map(|part| part.iter().copied()) {
+        if byte == stuffing.flag || byte == stuffing.escape {
+            put(stuffing.escape)?;
+            put((stuffing.escaped)(byte))?;
+        } else {
+            put(byte)?;
+        }
+    }
+    put(stuffing.flag)?;
+    Ok(i_dst)
+}
+
+/// SLIP framing (RFC 1055)
+///
+/// Frames are sent with an END byte before and after, so line noise before a frame ends up in an
+/// empty or bad frame of its own. N is the max decoded message size.
+pub struct SlipFramer<const N: usize = COBS_DECODER_MAX_FRAME> {
+    decoder: StuffedDecoder<N>,
+}
+
+impl<const N: usize> Default for SlipFramer<N> {
+    fn default() -> SlipFramer<N> {
+        SlipFramer {
+            decoder: StuffedDecoder::new(),
+        }
+    }
+}
+
+impl<const N: usize> Framer for SlipFramer<N> {
+    fn encode_into(&self, parts: &[&[u8]], dst: &mut [u8]) -> SerialComResult<usize> {
+        Framing::Slip.encode_into(parts, dst)
+    }
+    fn push(&mut self, byte: u8) -> Option<SerialComResult<&[u8]>> {
+        self.decoder.push(&SLIP_STUFFING, byte)
+    }
+    fn reset(&mut self) {
+        self.decoder.reset();
+    }
+}
+
+/// HDLC-like framing: 0x7E flags around each frame, with 0x7E and 0x7D sent as 0x7D followed by
+/// the byte XOR 0x20
+///
+/// 0x7D right before a flag aborts the frame. N is the max decoded message size.
+pub struct HdlcFramer<const N: usize = COBS_DECODER_MAX_FRAME> {
+    decoder: StuffedDecoder<N>,
+}
+
+impl<const N: usize> Default for HdlcFramer<N> {
+    fn default() -> HdlcFramer<N> {
+        HdlcFramer {
+            decoder: StuffedDecoder::new(),
+        }
+    }
+}
+
+impl<const N: usize> Framer for HdlcFramer<N> {
+    fn encode_into(&self, parts: &[&[u8]], dst: &mut [u8]) -> SerialComResult<usize> {
+        Framing::Hdlc.encode_into(parts, dst)
+    }
+    fn push(&mut self, byte: u8) -> Option<SerialComResult<&[u8]>> {
+        self.decoder.push(&HDLC_STUFFING, byte)
+    }
+    fn reset(&mut self) {
+        self.decoder.reset();
+    }
+}
+
+#[cfg(test)]
+use rand::prelude::*;
+
+#[cfg(test)]
+fn check_round_trip<F: Framer>(framer: &mut F) {
+    let mut rng = thread_rng();
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+    let mut stream: Vec<u8> = Vec::new();
+    let mut dst: [u8; 512] = [0; 512];
+    for _i_msg in 0..300 {
+        let size = rng.gen_range(1, 200);
+        let message: Vec<u8> = (0..size)
+            .map(|_| match rng.gen_range(0, 8) {
+                0 => [0, SLIP_END, SLIP_ESC, HDLC_FLAG, HDLC_ESCAPE][rng.gen_range(0, 5)],
+                _ => rng.gen(),
+            })
+            .collect();
+        let split = rng.gen_range(0, size);
+        let frame_len = framer
+            .encode_into(&[&message[..split], &message[split..]], &mut dst)
+            .expect("Couldn't encode");
+        stream.extend(dst[..frame_len].iter());
+        messages.push(message);
+    }
+    let mut decoded: Vec<Vec<u8>> = Vec::new();
+    let mut i_byte = 0;
+    while i_byte < stream.len() {
+        let chunk_len = rng.gen_range(1, 64).min(stream.len() - i_byte);
+        framer.push_slice(&stream[i_byte..i_byte + chunk_len], |frame| {
+            decoded.push(frame.expect("Couldn't decode frame").to_vec())
+        });
+        i_byte += chunk_len;
+    }
+    assert_eq!(decoded, messages);
+    framer
+        .encode_into(&[&[1; 100]], &mut dst[..50])
+        .expect_err("Should be error, dst too small");
+}
+
+#[test]
+fn test_framers_round_trip() {
+    check_round_trip(&mut CobsFramer::<256>::default());
+    check_round_trip(&mut CobsFramer::<256>::with_variant(COBSVariant::Reduced));
+    check_round_trip(&mut SlipFramer::<256>::default());
+    check_round_trip(&mut HdlcFramer::<256>::default());
+    for framing in ["cobs", "cobsr", "slip", "hdlc"].iter() {
+        check_round_trip(&mut framing.parse::<Framing>().unwrap().new_framer::<256>());
+    }
+    "ppp"
+        .parse::<Framing>()
+        .expect_err("Should be error, unknown framing");
+}
+
+#[test]
+fn test_slip_hdlc_encoding() {
+    let mut dst: [u8; 16] = [0; 16];
+    let slip = SlipFramer::<16>::default();
+    let frame_len = slip
+        .encode_into(&[&[SLIP_END, 1], &[SLIP_ESC]], &mut dst)
+        .unwrap();
+    assert_eq!(
+        dst[..frame_len],
+        [
+            SLIP_END,
+            SLIP_ESC,
+            SLIP_ESC_END,
+            1,
+            SLIP_ESC,
+            SLIP_ESC_ESC,
+            SLIP_END
+        ]
+    );
+    let hdlc = HdlcFramer::<16>::default();
+    let frame_len = hdlc
+        .encode_into(&[&[HDLC_FLAG, 1, HDLC_ESCAPE]], &mut dst)
+        .unwrap();
+    assert_eq!(dst[..frame_len], [0x7E, 0x7D, 0x5E, 1, 0x7D, 0x5D, 0x7E]);
+}
+
+#[test]
+fn test_stuffed_framers_resync() {
+    let mut slip = SlipFramer::<4>::default();
+    let mut results: Vec<SerialComResult<Vec<u8>>> = Vec::new();
+    let stream = [
+        // Invalid escape
+        SLIP_END, 1, SLIP_ESC, 2, 3, SLIP_END, // Good frame
+        4, 5, SLIP_END, // Too long
+        1, 2, 3, 4, 5, SLIP_END, // Good frame after repeated ENDs
+        SLIP_END, SLIP_END, 6, SLIP_END,
+    ];
+    slip.push_slice(&stream, |frame| {
+        results.push(frame.map(|frame| frame.to_vec()))
+    });
+    assert_eq!(results.len(), 4);
+    match results[0] {
+        Err(SerialComError::FrameMalformed) => {}
+        _ => panic!("Expected FrameMalformed"),
+    }
+    assert_eq!(results[1].as_ref().unwrap(), &vec![4, 5]);
+    match results[2] {
+        Err(SerialComError::FrameTooLong) => {}
+        _ => panic!("Expected FrameTooLong"),
+    }
+    assert_eq!(results[3].as_ref().unwrap(), &vec![6]);
+
+    let mut hdlc = HdlcFramer::<16>::default();
+    results.clear();
+    // Aborted by an escape right before the flag
+    hdlc.push_slice(&[0x7E, 1, 2, 0x7D, 0x7E, 3, 0x7E], |frame| {
+        results.push(frame.map(|frame| frame.to_vec()))
+    });
+    assert_eq!(results.len(), 2);
+    match results[0] {
+        Err(SerialComError::FrameMalformed) => {}
+        _ => panic!("Expected FrameMalformed"),
+    }
+    assert_eq!(results[1].as_ref().unwrap(), &vec![3]);
+}
@@ -1,5 +1,22 @@
+//! Framed binary protocol for talking to devices over serial links
+//!
+//! The framing (cobs, framer), checksum (crc), message (binarycom) and device side
+//! (binarycom::device) code is no_std and allocation-free, so device firmware can share it with
+//! the host. Everything else needs the std feature, which is on by default.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
 pub mod binarycom;
+#[cfg(all(feature = "std", unix))]
+pub mod broker;
 pub mod circbuf;
 pub mod cobs;
 pub mod crc;
 pub mod error;
+pub mod framer;
+#[cfg(feature = "std")]
+pub mod sim;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod transport;
@@ -0,0 +1,411 @@
+//! Device simulator: a configurable register map and stream generators
+//!
+//! Used by the serialcom-sim binary so host-side code can be developed without hardware.
+
+use crate::binarycom::device::{DeviceResponder, RegisterFile};
+use crate::binarycom::hello::CAP_STREAM;
+use crate::binarycom::{LinkConfig, RegisterBitWidth};
+use crate::error::{SerialComError, SerialComResult};
+use crate::transport::Transport;
+
+use rand::prelude::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Register file backed by a map
+///
+/// Registers that haven't been set read as 0, and writing any register creates it.
+pub struct RegisterMap {
+    pub values: HashMap<u16, u32>,
+    width: RegisterBitWidth,
+}
+
+impl RegisterMap {
+    pub fn new(width: RegisterBitWidth) -> RegisterMap {
+        RegisterMap {
+            values: HashMap::new(),
+            width,
+        }
+    }
+
+    /// Set a register from an assignment like "12=34" or "0x10=0xFF"
+    pub fn set_from_str(&mut self, assignment: &str) -> SerialComResult<()> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid register assignment: {}", assignment),
+            )
+        };
+        let mut parts = assignment.splitn(2, '=');
+        let reg_num = parts.next().and_then(parse_int).ok_or_else(invalid)?;
+        let reg_val = parts.next().and_then(parse_int).ok_or_else(invalid)?;
+        self.values.insert(u16::try_from(reg_num)?, reg_val);
+        Ok(())
+    }
+}
+
+/// Parse a decimal or 0x-prefixed hexadecimal integer
+fn parse_int(text: &str) -> Option<u32> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+impl RegisterFile for RegisterMap {
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        self.width
+    }
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
+        Ok(self.values.get(&reg_num).copied().unwrap_or(0))
+    }
+    fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
+        self.values.insert(reg_num, reg_val);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamKind {
+    /// Full scale sine wave
+    Sine,
+    /// Rises from 0 to full scale, then starts over
+    Ramp,
+    /// Uniform random samples
+    Noise,
+    /// Counts up by 1, wrapping at full scale
+    Counter,
+}
+
+impl std::str::FromStr for StreamKind {
+    type Err = io::Error;
+    fn from_str(text: &str) -> Result<StreamKind, io::Error> {
+        match text {
+            "sine" => Ok(StreamKind::Sine),
+            "ramp" => Ok(StreamKind::Ramp),
+            "noise" => Ok(StreamKind::Noise),
+            "counter" => Ok(StreamKind::Counter),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown stream kind: {}", text),
+            )),
+        }
+    }
+}
+
+/// Generates samples for stream messages
+///
+/// Samples are 8 or 16 bit words, one word per sample, packed big-endian as unpack_stream
+/// expects.
+pub struct StreamGenerator {
+    kind: StreamKind,
+    word_bits: u8,
+    /// Number of samples in one sine or ramp period
+    period: u32,
+    i_sample: u32,
+}
+
+impl StreamGenerator {
+    /// word_bits must be 8 or 16
+    pub fn new(kind: StreamKind, word_bits: u8) -> SerialComResult<StreamGenerator> {
+        if word_bits != 8 && word_bits != 16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Stream word size must be 8 or 16 bits, not {}", word_bits),
+            )
+            .into());
+        }
+        Ok(StreamGenerator {
+            kind,
+            word_bits,
+            period: 64,
+            i_sample: 0,
+        })
+    }
+
+    /// The stream message command: one word per sample of word_bits bits
+    pub fn command(&self) -> u8 {
+        0x80 | 1 << 3 | (self.word_bits / 4)
+    }
+
+    pub fn next_sample(&mut self) -> u32 {
+        let full_scale = (1u32 << self.word_bits) - 1;
+        let i_period = self.i_sample % self.period;
+        let sample = match self.kind {
+            StreamKind::Sine => {
+                let phase = 2. * PI * f64::from(i_period) / f64::from(self.period);
+                let half_scale = f64::from(full_scale) / 2.;
+                (half_scale + half_scale * phase.sin()).round() as u32
+            }
+            StreamKind::Ramp => i_period * full_scale / (self.period - 1),
+            StreamKind::Noise => thread_rng().gen_range(0, full_scale + 1),
+            StreamKind::Counter => self.i_sample & full_scale,
+        };
+        self.i_sample = self.i_sample.wrapping_add(1);
+        sample
+    }
+
+    /// Fill data with as many whole samples as fit
+    ///
+    /// returns the number of bytes used
+    pub fn fill(&mut self, data: &mut [u8]) -> usize {
+        let word_bytes = usize::from(self.word_bits / 8);
+        let n_samples = data.len() / word_bytes;
+        for i in 0..n_samples {
+            let sample = self.next_sample();
+            for i_byte in 0..word_bytes {
+                let shift = 8 * (word_bytes - 1 - i_byte);
+                data[i * word_bytes + i_byte] = (sample >> shift & 0xFF) as u8;
+            }
+        }
+        n_samples * word_bytes
+    }
+}
+
+/// Act as a device on transport, framing and checking messages as link says, until reading from
+/// it fails
+///
+/// Answers register requests from registers and, if stream is given, sends a stream message
+/// from the generator every interval.
+pub fn run_simulator(
+    transport: Box<dyn Transport>,
+    link: LinkConfig,
+    registers: RegisterMap,
+    stream: Option<(StreamGenerator, Duration)>,
+) -> SerialComResult<()> {
+    run_simulator_at(transport, link, None, registers, stream)
+}
+
+/// Like run_simulator, as the device at address on a multi-drop bus if address is given
+pub fn run_simulator_at(
+    mut transport: Box<dyn Transport>,
+    link: LinkConfig,
+    address: Option<u8>,
+    registers: RegisterMap,
+    mut stream: Option<(StreamGenerator, Duration)>,
+) -> SerialComResult<()> {
+    let mut responder: DeviceResponder<_, _> =
+        DeviceResponder::with_framer(registers, link.framing.new_framer::<16>(), link.checksum);
+    responder.set_reliable(link.reliable);
+    responder.set_address(address);
+    if stream.is_some() {
+        let capabilities = responder.device_info().capabilities;
+        responder.set_capabilities(capabilities | CAP_STREAM);
+    }
+    let mut readbuf: [u8; 64] = [0; 64];
+    let mut next_stream_time = Instant::now();
+    let mut host_gone = false;
+    loop {
+        let n_read = transport.read(&mut readbuf)?;
+        responder.process(&readbuf[..n_read], |bytes| {
+            write_to_host(&mut *transport, bytes, &mut host_gone)
+        })?;
+        if let Some((ref mut generator, interval)) = stream {
+            if Instant::now() >= next_stream_time {
+                let mut data: [u8; 10] = [0; 10];
+                let data_len = generator.fill(&mut data);
+                responder.stream(generator.command(), &data[..data_len])?;
+                let (slice1, slice2) = responder.reply();
+                write_to_host(&mut *transport, slice1, &mut host_gone)?;
+                write_to_host(&mut *transport, slice2, &mut host_gone)?;
+                next_stream_time += interval;
+            }
+        }
+        transport.flush()?;
+    }
+}
+
+/// Write bytes to the host through transport
+///
+/// If no host is connected, or it went away, the bytes are dropped so the simulator keeps serving
+/// until one connects; host_gone tracks that so it is only reported once per disconnection.
+fn write_to_host(
+    transport: &mut dyn Transport,
+    bytes: &[u8],
+    host_gone: &mut bool,
+) -> SerialComResult<()> {
+    match transport.write(bytes) {
+        Ok(()) => {
+            *host_gone = false;
+            Ok(())
+        }
+        Err(SerialComError::Io(ref e))
+            if e.kind() == io::ErrorKind::NotConnected
+                || e.kind() == io::ErrorKind::BrokenPipe
+                || e.kind() == io::ErrorKind::ConnectionReset =>
+        {
+            if !*host_gone {
+                println!("Simulator: no host connected, dropping output: {}", e);
+                *host_gone = true;
+            }
+            Ok(())
+        }
+        Err(write_error) => Err(write_error),
+    }
+}
+
+#[cfg(test)]
+use crate::binarycom::app::BinaryComApp16;
+#[cfg(test)]
+use crate::binarycom::hello::{CAP_BLOCK_READ, CAP_FRAGMENTS, CAP_TAGGED};
+#[cfg(test)]
+use crate::binarycom::packers;
+#[cfg(test)]
+use crate::transport::tcp::TcpTransport;
+#[cfg(test)]
+use crate::transport::MemoryTransport;
+#[cfg(test)]
+use std::sync::mpsc;
+#[cfg(test)]
+use std::thread;
+
+#[test]
+fn test_register_map_set_from_str() {
+    let mut registers = RegisterMap::new(RegisterBitWidth::ThirtyTwo);
+    registers.set_from_str("12=34").unwrap();
+    registers.set_from_str("0x10=0xFFFFFFFF").unwrap();
+    assert_eq!(registers.read_reg(12).unwrap(), 34);
+    assert_eq!(registers.read_reg(16).unwrap(), 0xFFFFFFFF);
+    assert_eq!(registers.read_reg(17).unwrap(), 0);
+    registers.set_from_str("12").expect_err("Should be error");
+    registers.set_from_str("x=1").expect_err("Should be error");
+    registers
+        .set_from_str("0x10000=1")
+        .expect_err("Should be error, register number too big");
+}
+
+#[test]
+fn test_stream_generators_unpack() {
+    for word_bits in [8u8, 16].iter() {
+        let full_scale = (1u32 << word_bits) - 1;
+        for kind in [
+            StreamKind::Sine,
+            StreamKind::Ramp,
+            StreamKind::Noise,
+            StreamKind::Counter,
+        ]
+        .iter()
+        {
+            let mut generator = StreamGenerator::new(*kind, *word_bits).unwrap();
+            let mut reference = StreamGenerator::new(*kind, *word_bits).unwrap();
+            let mut data: [u8; 11] = [0; 11];
+            let data_len = generator.fill(&mut data);
+            assert_eq!(data_len, if *word_bits == 8 { 11 } else { 10 });
+            let samples = packers::unpack_stream(generator.command(), data[..data_len].to_vec())
+                .expect("Couldn't unpack stream");
+            assert_eq!(samples.len(), data_len * 8 / usize::from(*word_bits));
+            for sample in samples.iter() {
+                assert!(*sample <= full_scale);
+                if *kind != StreamKind::Noise {
+                    assert_eq!(*sample, reference.next_sample());
+                }
+            }
+        }
+    }
+    let mut ramp = StreamGenerator::new(StreamKind::Ramp, 8).unwrap();
+    let ramp_samples: Vec<u32> = (0..64).map(|_| ramp.next_sample()).collect();
+    assert_eq!(ramp_samples[0], 0);
+    assert_eq!(ramp_samples[63], 255);
+    StreamGenerator::new(StreamKind::Sine, 12)
+        .err()
+        .expect("Should be error");
+}
+
+#[test]
+fn test_run_simulator() {
+    let (host, dev) = MemoryTransport::pair();
+    let mut registers = RegisterMap::new(RegisterBitWidth::ThirtyTwo);
+    registers.set_from_str("3=0x12345678").unwrap();
+    let generator = StreamGenerator::new(StreamKind::Counter, 16).unwrap();
+    thread::spawn(move || {
+        run_simulator(
+            Box::new(dev),
+            LinkConfig::default(),
+            registers,
+            Some((generator, Duration::from_millis(5))),
+        )
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut app = BinaryComApp16::with_stream_handler(
+        RegisterBitWidth::ThirtyTwo,
+        Box::new(host),
+        move |command, data| {
+            let _ = tx.send((command, data));
+        },
+    )
+    .expect("Couldn't make app");
+    assert_eq!(app.read_reg(3).expect("Couldn't read reg"), 0x12345678);
+    app.write_reg(4, 99).expect("Couldn't write reg");
+    assert_eq!(app.read_reg(4).expect("Couldn't read reg"), 99);
+    let device_info = app.handshake().expect("Couldn't handshake");
+    // Streaming adds CAP_STREAM to the default capabilities, and RegisterMap takes no messages
+    assert!(device_info.supports(CAP_TAGGED | CAP_STREAM | CAP_BLOCK_READ));
+    assert!(!device_info.supports(CAP_FRAGMENTS));
+    assert_eq!(app.read_regs(3, 2).unwrap(), [0x12345678, 99]);
+    let (command, samples) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(command, 0x8C);
+    assert_eq!(samples.len(), 5);
+    for pair in samples.windows(2) {
+        assert_eq!(pair[1], pair[0] + 1);
+    }
+}
+
+#[test]
+fn test_run_simulator_link() {
+    let (host, dev) = MemoryTransport::pair();
+    let link = LinkConfig {
+        framing: "hdlc".parse().unwrap(),
+        checksum: "crc16-ccitt-false".parse().unwrap(),
+        reliable: true,
+    };
+    let mut registers = RegisterMap::new(RegisterBitWidth::Eight);
+    registers.set_from_str("0x7E=0x7D").unwrap();
+    thread::spawn(move || run_simulator(Box::new(dev), link, registers, None));
+    let mut app = BinaryComApp16::with_link(
+        RegisterBitWidth::Eight,
+        Box::new(host),
+        link,
+        |_command, _data| {},
+    )
+    .expect("Couldn't make app");
+    assert_eq!(app.read_reg(0x7E).expect("Couldn't read reg"), 0x7D);
+    app.write_reg(0x7D, 0xC0).expect("Couldn't write reg");
+    assert_eq!(app.read_reg(0x7D).expect("Couldn't read reg"), 0xC0);
+}
+
+#[test]
+fn test_run_simulator_waits_for_host() {
+    let device = TcpTransport::listen("127.0.0.1:0").expect("Couldn't listen");
+    let addr = device.local_addr().expect("Couldn't get address");
+    let mut registers = RegisterMap::new(RegisterBitWidth::ThirtyTwo);
+    registers.set_from_str("3=7").unwrap();
+    let generator = StreamGenerator::new(StreamKind::Sine, 16).unwrap();
+    let simulator = thread::spawn(move || {
+        run_simulator(
+            Box::new(device),
+            LinkConfig::default(),
+            registers,
+            Some((generator, Duration::from_millis(5))),
+        )
+    });
+    // Stream messages are due before any host connects
+    thread::sleep(Duration::from_millis(100));
+    assert!(!simulator.is_finished());
+    let (tx, rx) = mpsc::channel();
+    let host = TcpTransport::connect(addr).expect("Couldn't connect");
+    let mut app = BinaryComApp16::with_stream_handler(
+        RegisterBitWidth::ThirtyTwo,
+        Box::new(host),
+        move |command, data| {
+            let _ = tx.send((command, data));
+        },
+    )
+    .expect("Couldn't make app");
+    assert_eq!(app.read_reg(3).expect("Couldn't read reg"), 7);
+    let (command, _samples) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(command, 0x8C);
+}
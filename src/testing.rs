@@ -0,0 +1,256 @@
+//! Pseudo-terminal loopback harness for exercising BinaryComApp end to end
+//!
+//! PtyLoopback opens a PTY pair, runs a simulated device on the slave side, and hands the master
+//! side to a BinaryComApp, so requests and replies go through a real tty file descriptor and the
+//! HostReceiver16 and stream threads.
+
+use crate::binarycom::app::{BinaryComApp16, RegisterBitWidth};
+use crate::binarycom::device::{DeviceResponder, RegisterFile};
+use crate::binarycom::BinaryCom;
+use crate::error::SerialComResult;
+use crate::transport::pty::{PtyMaster, PtyPair};
+use crate::transport::serial::{SerialPort, SerialSettings};
+use crate::transport::Transport;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A BinaryComApp connected to a simulated device through a PTY pair
+///
+/// The simulated device answers register reads and writes from registers. Registers that haven't
+/// been set read as 0. Dropping the PtyLoopback stops the device thread.
+pub struct PtyLoopback {
+    pub app: BinaryComApp16,
+    pub registers: Arc<Mutex<HashMap<u16, u32>>>,
+    pub slave_path: PathBuf,
+    pub device_thread_handle: thread::JoinHandle<()>,
+    device_writer: Mutex<Box<dyn Transport>>,
+    muted: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+}
+
+impl PtyLoopback {
+    /// Setup the PTY pair, simulated device, and app; the app prints stream data
+    pub fn new(register_bit_width: RegisterBitWidth) -> SerialComResult<PtyLoopback> {
+        PtyLoopback::with_stream_handler(register_bit_width, |_command, data| {
+            println!("The data is: {:?}", data)
+        })
+    }
+
+    /// Setup the PTY pair, simulated device, and app, with stream_handler as the app's stream
+    /// handler
+    pub fn with_stream_handler<F>(
+        register_bit_width: RegisterBitWidth,
+        stream_handler: F,
+    ) -> SerialComResult<PtyLoopback>
+    where
+        F: FnMut(u8, Vec<u32>) + Send + 'static,
+    {
+        let pty = PtyPair::open()?;
+        // Open the slave before the app starts reading the master, otherwise the master read fails
+        let device_port = SerialPort::open(&pty.slave_path, &SerialSettings::default())?;
+        let device_writer = Mutex::new(device_port.try_clone()?);
+        let registers = Arc::new(Mutex::new(HashMap::new()));
+        let muted = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder = DeviceResponder::new(SharedRegisters {
+            width: register_bit_width,
+            values: Arc::clone(&registers),
+        });
+        let device_thread_handle = {
+            let muted = Arc::clone(&muted);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || run_simulated_device(device_port, responder, muted, stop))
+        };
+        let master = PtyMaster::new(pty.master, Duration::from_millis(50));
+        let app = BinaryComApp16::with_stream_handler(
+            register_bit_width,
+            Box::new(master),
+            stream_handler,
+        )?;
+        Ok(PtyLoopback {
+            app,
+            registers,
+            slave_path: pty.slave_path,
+            device_thread_handle,
+            device_writer,
+            muted,
+            stop,
+        })
+    }
+
+    /// While muted, the simulated device receives requests but doesn't reply to them
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::SeqCst);
+    }
+
+    /// Send a stream message from the simulated device to the app
+    pub fn send_stream(&self, command: u8, data: &[u8]) -> SerialComResult<()> {
+        let mut outbuf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+            arraydeque::ArrayDeque::new();
+        outbuf.send_message(&command, data)?;
+        write_slices(&self.device_writer, outbuf.as_slices())
+    }
+}
+
+impl Drop for PtyLoopback {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Write both slices of an encoded message to writer
+fn write_slices(
+    writer: &Mutex<Box<dyn Transport>>,
+    (slice1, slice2): (&[u8], &[u8]),
+) -> SerialComResult<()> {
+    let mut writer = writer
+        .lock()
+        .map_err(|_| std::io::Error::other("device writer lock poisoned"))?;
+    writer.write(slice1)?;
+    writer.write(slice2)?;
+    writer.flush()
+}
+
+/// Registers of the simulated device, shared with the PtyLoopback
+///
+/// Registers that haven't been set read as 0.
+struct SharedRegisters {
+    width: RegisterBitWidth,
+    values: Arc<Mutex<HashMap<u16, u32>>>,
+}
+
+impl RegisterFile for SharedRegisters {
+    fn register_bit_width(&self) -> RegisterBitWidth {
+        self.width
+    }
+    fn read_reg(&mut self, reg_num: u16) -> SerialComResult<u32> {
+        let values = self
+            .values
+            .lock()
+            .map_err(|_| std::io::Error::other("registers lock poisoned"))?;
+        Ok(values.get(&reg_num).copied().unwrap_or(0))
+    }
+    fn write_reg(&mut self, reg_num: u16, reg_val: u32) -> SerialComResult<()> {
+        let mut values = self
+            .values
+            .lock()
+            .map_err(|_| std::io::Error::other("registers lock poisoned"))?;
+        values.insert(reg_num, reg_val);
+        Ok(())
+    }
+}
+
+fn run_simulated_device(
+    mut port: SerialPort,
+    mut responder: DeviceResponder<SharedRegisters>,
+    muted: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+) {
+    let writer: Mutex<Box<dyn Transport>> = match port.try_clone() {
+        Ok(writer) => Mutex::new(writer),
+        Err(clone_error) => {
+            println!("Simulated device couldn't clone port: {}", clone_error);
+            return;
+        }
+    };
+    let mut readbuf: [u8; 64] = [0; 64];
+    while !stop.load(Ordering::SeqCst) {
+        let n_read = match port.read(&mut readbuf) {
+            Ok(n_read) => n_read,
+            Err(read_error) => {
+                println!("Simulated device read error, stopping: {}", read_error);
+                return;
+            }
+        };
+        for byte in readbuf.iter().take(n_read) {
+            match responder.receive_byte(*byte) {
+                Ok(true) if !muted.load(Ordering::SeqCst) => {
+                    if let Err(reply_error) = write_slices(&writer, responder.reply()) {
+                        println!("Simulated device reply error: {}", reply_error);
+                    }
+                }
+                Ok(_) => {}
+                Err(recv_error) => {
+                    println!("Simulated device receive error: {}", recv_error)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::error::SerialComError;
+#[cfg(test)]
+use std::sync::mpsc;
+
+#[test]
+fn test_pty_loopback_read_write_reg32() {
+    let mut lb = PtyLoopback::new(RegisterBitWidth::ThirtyTwo).expect("Couldn't setup loopback");
+    lb.registers.lock().unwrap().insert(7, 0x01020304);
+    assert_eq!(lb.app.read_reg(7).expect("Couldn't read reg"), 0x01020304);
+    assert_eq!(lb.app.read_reg(8).expect("Couldn't read reg"), 0);
+    lb.app
+        .write_reg(0x1234, 0xFFFF0000)
+        .expect("Couldn't write reg");
+    assert_eq!(lb.registers.lock().unwrap()[&0x1234], 0xFFFF0000);
+    assert_eq!(
+        lb.app.read_reg(0x1234).expect("Couldn't read reg"),
+        0xFFFF0000
+    );
+}
+
+#[test]
+fn test_pty_loopback_read_write_reg8() {
+    let mut lb = PtyLoopback::new(RegisterBitWidth::Eight).expect("Couldn't setup loopback");
+    for reg_num in 0..20u16 {
+        lb.app
+            .write_reg(reg_num, u32::from(reg_num) * 3)
+            .expect("Couldn't write reg");
+    }
+    for reg_num in 0..20u16 {
+        assert_eq!(
+            lb.app.read_reg(reg_num).expect("Couldn't read reg"),
+            u32::from(reg_num) * 3
+        );
+    }
+    lb.app
+        .write_reg(0, 0x100)
+        .expect_err("Should be error, value too big for 8 bits");
+}
+
+#[test]
+fn test_pty_loopback_stream() {
+    let (tx, rx) = mpsc::channel();
+    let lb = PtyLoopback::with_stream_handler(RegisterBitWidth::ThirtyTwo, move |command, data| {
+        tx.send((command, data)).unwrap()
+    })
+    .expect("Couldn't setup loopback");
+    lb.send_stream(0x8A, &[0, 1, 2, 0xFF])
+        .expect("Couldn't send stream");
+    lb.send_stream(0x8C, &[0x12, 0x34, 0, 0x56])
+        .expect("Couldn't send stream");
+    let timeout = Duration::from_secs(1);
+    assert_eq!(
+        rx.recv_timeout(timeout).unwrap(),
+        (0x8A, vec![0, 1, 2, 0xFF])
+    );
+    assert_eq!(
+        rx.recv_timeout(timeout).unwrap(),
+        (0x8C, vec![0x1234, 0x56])
+    );
+}
+
+#[test]
+fn test_pty_loopback_read_reg_timeout() {
+    let mut lb = PtyLoopback::new(RegisterBitWidth::ThirtyTwo).expect("Couldn't setup loopback");
+    lb.set_muted(true);
+    match lb.app.read_reg(3) {
+        Err(SerialComError::Timeout) => {}
+        _ => panic!("Expected Timeout"),
+    }
+}
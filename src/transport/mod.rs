@@ -0,0 +1,165 @@
+#[cfg(target_os = "linux")]
+pub mod pty;
+#[cfg(target_os = "linux")]
+pub mod serial;
+pub mod tcp;
+#[cfg(unix)]
+pub mod unix;
+
+use crate::error::SerialComResult;
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Trait for byte streams that carry encoded frames between host and device
+///
+/// HostReceiver16 reads from one handle to the transport in its own thread while BinaryComApp
+/// writes requests to another handle, obtained with try_clone.
+pub trait Transport: Send {
+    /// Read whatever bytes are available into buf
+    ///
+    /// Blocks for at most the transport's read timeout.
+    ///
+    /// Returns Ok(number of bytes read), which is 0 if nothing arrived before the timeout. Returns
+    /// Err if the link is closed and can't be used anymore.
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize>;
+    /// Write all of buf to the link
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()>;
+    /// Make sure all written bytes have been handed off to the link
+    fn flush(&mut self) -> SerialComResult<()>;
+    /// Get another handle to the same link
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>>;
+}
+
+/// How long MemoryTransport::read waits for data before returning Ok(0)
+const MEMORY_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// In-process transport, mostly useful to connect a host and a device thread in tests
+///
+/// Create the two connected ends with MemoryTransport::pair. Bytes written to one end can be read
+/// from the other.
+pub struct MemoryTransport {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+    pending: Vec<u8>,
+}
+
+impl MemoryTransport {
+    /// returns the two ends of an in-memory link
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (tx_a, rx_b) = mpsc::channel();
+        let (tx_b, rx_a) = mpsc::channel();
+        (
+            MemoryTransport {
+                tx: tx_a,
+                rx: Arc::new(Mutex::new(rx_a)),
+                pending: Vec::new(),
+            },
+            MemoryTransport {
+                tx: tx_b,
+                rx: Arc::new(Mutex::new(rx_b)),
+                pending: Vec::new(),
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        if self.pending.is_empty() {
+            let rx = self
+                .rx
+                .lock()
+                .map_err(|_| io::Error::other("MemoryTransport receiver lock poisoned"))?;
+            match rx.recv_timeout(MEMORY_READ_TIMEOUT) {
+                Ok(chunk) => self.pending = chunk,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(0),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::from(io::ErrorKind::BrokenPipe).into())
+                }
+            }
+        }
+        let n_read = buf.len().min(self.pending.len());
+        buf[..n_read].copy_from_slice(&self.pending[..n_read]);
+        self.pending.drain(..n_read);
+        Ok(n_read)
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(())
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        Ok(())
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(MemoryTransport {
+            tx: self.tx.clone(),
+            rx: Arc::clone(&self.rx),
+            pending: Vec::new(),
+        }))
+    }
+}
+
+/// Transport on this process's stdin and stdout
+///
+/// Spawns threads that copy stdin into, and written bytes out to stdout. Reads fail once stdin is
+/// closed. Nothing else should print to stdout while this is in use.
+pub fn stdio_transport() -> MemoryTransport {
+    let (tx_in, rx_in) = mpsc::channel::<Vec<u8>>();
+    let (tx_out, rx_out) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf: [u8; 256] = [0; 256];
+        while let Ok(n_read) = stdin.read(&mut buf) {
+            if n_read == 0 || tx_in.send(buf[..n_read].to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+    thread::spawn(move || {
+        let mut stdout = io::stdout();
+        for chunk in rx_out.iter() {
+            if stdout
+                .write_all(&chunk)
+                .and_then(|_| stdout.flush())
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    MemoryTransport {
+        tx: tx_out,
+        rx: Arc::new(Mutex::new(rx_in)),
+        pending: Vec::new(),
+    }
+}
+
+#[test]
+fn test_memory_transport() {
+    let (mut host, mut dev) = MemoryTransport::pair();
+    let mut buf: [u8; 4] = [0; 4];
+    assert_eq!(dev.read(&mut buf).expect("Couldn't read"), 0);
+    host.write(&[1, 2, 3, 4, 5, 6]).expect("Couldn't write");
+    host.flush().expect("Couldn't flush");
+    assert_eq!(dev.read(&mut buf).expect("Couldn't read"), 4);
+    assert_eq!(buf, [1, 2, 3, 4]);
+    assert_eq!(dev.read(&mut buf).expect("Couldn't read"), 2);
+    assert_eq!(buf[..2], [5, 6]);
+    let mut dev_clone = dev.try_clone().expect("Couldn't clone");
+    dev_clone.write(&[7]).expect("Couldn't write");
+    assert_eq!(host.read(&mut buf).expect("Couldn't read"), 1);
+    assert_eq!(buf[0], 7);
+    drop(dev);
+    drop(dev_clone);
+    host.read(&mut buf)
+        .expect_err("Should be error after other end closed");
+}
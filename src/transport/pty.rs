@@ -0,0 +1,77 @@
+use crate::error::SerialComResult;
+use crate::transport::serial::read_with_timeout;
+use crate::transport::Transport;
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A pseudo-terminal pair
+///
+/// Whatever is written to master can be read from the tty at slave_path and vice versa, so a
+/// program that expects a serial port device can be pointed at slave_path.
+pub struct PtyPair {
+    pub master: File,
+    pub slave_path: PathBuf,
+}
+
+impl PtyPair {
+    /// Open a new pseudo-terminal pair
+    pub fn open() -> SerialComResult<PtyPair> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // Owns fd from here on, so it is closed on the error paths
+        let master = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::grantpt(fd) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if unsafe { libc::unlockpt(fd) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut name_buf: [libc::c_char; 128] = [0; 128];
+        if unsafe { libc::ptsname_r(fd, name_buf.as_mut_ptr(), name_buf.len()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let slave_name = unsafe { CStr::from_ptr(name_buf.as_ptr()) };
+        let slave_path = PathBuf::from(slave_name.to_string_lossy().into_owned());
+        Ok(PtyPair { master, slave_path })
+    }
+}
+
+/// Transport for the master side of a PtyPair
+///
+/// Reads fail once the slave side has been closed, so the slave should be opened before reading.
+pub struct PtyMaster {
+    file: File,
+    read_timeout: Duration,
+}
+
+impl PtyMaster {
+    pub fn new(file: File, read_timeout: Duration) -> PtyMaster {
+        PtyMaster { file, read_timeout }
+    }
+}
+
+impl Transport for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        read_with_timeout(&mut self.file, self.read_timeout, buf)
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        Ok(self.file.write_all(buf)?)
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        Ok(self.file.flush()?)
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(PtyMaster {
+            file: self.file.try_clone()?,
+            read_timeout: self.read_timeout,
+        }))
+    }
+}
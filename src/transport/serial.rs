@@ -0,0 +1,385 @@
+use crate::error::SerialComResult;
+use crate::transport::Transport;
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+pub enum StopBits {
+    One,
+    Two,
+}
+
+pub enum FlowControl {
+    None,
+    /// RTS/CTS
+    Hardware,
+    /// XON/XOFF
+    Software,
+}
+
+/// Line settings for a SerialPort
+///
+/// The default is 115200 baud, 8 data bits, no parity, 1 stop bit (8N1), and no flow control.
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    /// Max time SerialPort::read waits for data
+    pub read_timeout: Duration,
+}
+
+impl Default for SerialSettings {
+    fn default() -> SerialSettings {
+        SerialSettings {
+            baud_rate: 115200,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            read_timeout: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Serial port transport for /dev/tty* devices, configured in raw mode with termios
+pub struct SerialPort {
+    file: File,
+    read_timeout: Duration,
+}
+
+impl SerialPort {
+    /// Open and configure the serial port device at path
+    pub fn open<P: AsRef<Path>>(path: P, settings: &SerialSettings) -> SerialComResult<SerialPort> {
+        // O_NONBLOCK so that open doesn't wait for carrier detect
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(path)?;
+        let fd = file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if unsafe { libc::ioctl(fd, libc::TIOCEXCL) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        SerialPort::from_file(file, settings)
+    }
+
+    /// Use an already open tty file as a serial port, configuring it with settings
+    pub fn from_file(file: File, settings: &SerialSettings) -> SerialComResult<SerialPort> {
+        let port = SerialPort {
+            file,
+            read_timeout: settings.read_timeout,
+        };
+        port.configure(settings)?;
+        Ok(port)
+    }
+
+    /// Apply settings to the port
+    ///
+    /// Puts the port in raw mode: no echo, no line editing, and no special handling of any bytes.
+    pub fn configure(&self, settings: &SerialSettings) -> SerialComResult<()> {
+        let fd = self.file.as_raw_fd();
+        let mut tio: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut tio) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        apply_settings(&mut tio, settings)?;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &tio) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Discard any received bytes not yet read and any written bytes not yet transmitted
+    pub fn discard_buffers(&self) -> SerialComResult<()> {
+        if unsafe { libc::tcflush(self.file.as_raw_fd(), libc::TCIOFLUSH) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+impl Transport for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        read_with_timeout(&mut self.file, self.read_timeout, buf)
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        Ok(self.file.write_all(buf)?)
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        self.file.flush()?;
+        if unsafe { libc::tcdrain(self.file.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(SerialPort {
+            file: self.file.try_clone()?,
+            read_timeout: self.read_timeout,
+        }))
+    }
+}
+
+/// Read from file, waiting up to timeout for bytes to arrive
+///
+/// returns Ok(0) on timeout, and an UnexpectedEof error if the other end hung up
+pub(crate) fn read_with_timeout(
+    file: &mut File,
+    timeout: Duration,
+    buf: &mut [u8],
+) -> SerialComResult<usize> {
+    if !poll_readable(file, timeout)? {
+        return Ok(0);
+    }
+    match file.read(buf)? {
+        // Readable with nothing to read: the other end hung up
+        0 if !buf.is_empty() => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        n_read => Ok(n_read),
+    }
+}
+
+/// Wait up to timeout for file to have bytes to read
+///
+/// returns Ok(true) if there is something to read (or the other end hung up, so read will report
+/// that), and Ok(false) on timeout
+pub(crate) fn poll_readable(file: &File, timeout: Duration) -> SerialComResult<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+    loop {
+        let n_ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if n_ready >= 0 {
+            return Ok(n_ready > 0);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err.into());
+        }
+    }
+}
+
+/// Modify tio to be raw mode with settings
+fn apply_settings(tio: &mut libc::termios, settings: &SerialSettings) -> SerialComResult<()> {
+    let speed = baud_rate_to_speed(settings.baud_rate)?;
+    unsafe { libc::cfmakeraw(tio) };
+    tio.c_cflag |= libc::CREAD | libc::CLOCAL;
+    tio.c_cflag &= !libc::CSIZE;
+    tio.c_cflag |= match settings.data_bits {
+        DataBits::Five => libc::CS5,
+        DataBits::Six => libc::CS6,
+        DataBits::Seven => libc::CS7,
+        DataBits::Eight => libc::CS8,
+    };
+    match settings.parity {
+        Parity::None => {
+            tio.c_cflag &= !(libc::PARENB | libc::PARODD);
+            tio.c_iflag &= !libc::INPCK;
+        }
+        Parity::Odd => {
+            tio.c_cflag |= libc::PARENB | libc::PARODD;
+            tio.c_iflag |= libc::INPCK;
+        }
+        Parity::Even => {
+            tio.c_cflag |= libc::PARENB;
+            tio.c_cflag &= !libc::PARODD;
+            tio.c_iflag |= libc::INPCK;
+        }
+    }
+    match settings.stop_bits {
+        StopBits::One => tio.c_cflag &= !libc::CSTOPB,
+        StopBits::Two => tio.c_cflag |= libc::CSTOPB,
+    }
+    tio.c_cflag &= !libc::CRTSCTS;
+    tio.c_iflag &= !(libc::IXON | libc::IXOFF | libc::IXANY);
+    match settings.flow_control {
+        FlowControl::None => {}
+        FlowControl::Hardware => tio.c_cflag |= libc::CRTSCTS,
+        FlowControl::Software => tio.c_iflag |= libc::IXON | libc::IXOFF,
+    }
+    // read returns whatever is available immediately; read timeouts are done with poll
+    tio.c_cc[libc::VMIN] = 0;
+    tio.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::cfsetispeed(tio, speed) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if unsafe { libc::cfsetospeed(tio, speed) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn baud_rate_to_speed(baud_rate: u32) -> SerialComResult<libc::speed_t> {
+    let speed = match baud_rate {
+        50 => libc::B50,
+        75 => libc::B75,
+        110 => libc::B110,
+        134 => libc::B134,
+        150 => libc::B150,
+        200 => libc::B200,
+        300 => libc::B300,
+        600 => libc::B600,
+        1200 => libc::B1200,
+        1800 => libc::B1800,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        500000 => libc::B500000,
+        576000 => libc::B576000,
+        921600 => libc::B921600,
+        1000000 => libc::B1000000,
+        1152000 => libc::B1152000,
+        1500000 => libc::B1500000,
+        2000000 => libc::B2000000,
+        2500000 => libc::B2500000,
+        3000000 => libc::B3000000,
+        3500000 => libc::B3500000,
+        4000000 => libc::B4000000,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported baud rate: {}", baud_rate),
+            )
+            .into())
+        }
+    };
+    Ok(speed)
+}
+
+#[cfg(test)]
+use crate::error::SerialComError;
+#[cfg(test)]
+use crate::transport::pty::PtyPair;
+#[cfg(test)]
+use std::os::unix::io::FromRawFd;
+
+#[test]
+fn test_serial_settings() {
+    let settings = SerialSettings {
+        baud_rate: 9600,
+        data_bits: DataBits::Seven,
+        parity: Parity::Even,
+        stop_bits: StopBits::Two,
+        flow_control: FlowControl::Software,
+        ..SerialSettings::default()
+    };
+    let mut tio: libc::termios = unsafe { std::mem::zeroed() };
+    tio.c_lflag = libc::ICANON | libc::ECHO;
+    apply_settings(&mut tio, &settings).expect("Couldn't apply settings");
+    assert_eq!(unsafe { libc::cfgetospeed(&tio) }, libc::B9600);
+    assert_eq!(unsafe { libc::cfgetispeed(&tio) }, libc::B9600);
+    assert_eq!(tio.c_cflag & libc::CSIZE, libc::CS7);
+    assert_eq!(tio.c_cflag & (libc::PARENB | libc::PARODD), libc::PARENB);
+    assert_eq!(tio.c_cflag & libc::CSTOPB, libc::CSTOPB);
+    assert_eq!(tio.c_cflag & libc::CRTSCTS, 0);
+    assert_eq!(tio.c_iflag & libc::IXON, libc::IXON);
+    assert_eq!(tio.c_lflag & (libc::ICANON | libc::ECHO), 0);
+    let settings = SerialSettings {
+        parity: Parity::Odd,
+        flow_control: FlowControl::Hardware,
+        ..SerialSettings::default()
+    };
+    apply_settings(&mut tio, &settings).expect("Couldn't apply settings");
+    assert_eq!(unsafe { libc::cfgetospeed(&tio) }, libc::B115200);
+    assert_eq!(tio.c_cflag & libc::CSIZE, libc::CS8);
+    assert_eq!(
+        tio.c_cflag & (libc::PARENB | libc::PARODD),
+        libc::PARENB | libc::PARODD
+    );
+    assert_eq!(tio.c_cflag & libc::CSTOPB, 0);
+    assert_eq!(tio.c_cflag & libc::CRTSCTS, libc::CRTSCTS);
+    assert_eq!(tio.c_iflag & libc::IXON, 0);
+    let settings = SerialSettings {
+        baud_rate: 12345,
+        ..SerialSettings::default()
+    };
+    apply_settings(&mut tio, &settings).expect_err("Should be unsupported baud rate error");
+}
+
+#[test]
+fn test_serial_read_write() {
+    let mut pty = PtyPair::open().expect("Couldn't open PTY");
+    let mut port =
+        SerialPort::open(&pty.slave_path, &SerialSettings::default()).expect("Couldn't open port");
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(port.read(&mut buf).expect("Couldn't read"), 0);
+    // Bytes the line discipline would interpret in cooked mode
+    let message: [u8; 6] = [0x0, 0x3, 0xA, 0xD, 0x11, 0xFF];
+    port.write(&message).expect("Couldn't write");
+    port.flush().expect("Couldn't flush");
+    let mut n_read = 0;
+    while n_read < message.len() {
+        n_read += pty
+            .master
+            .read(&mut buf[n_read..])
+            .expect("Couldn't read master");
+    }
+    assert_eq!(buf[..n_read], message);
+    pty.master
+        .write_all(&message)
+        .expect("Couldn't write master");
+    let mut n_read = 0;
+    while n_read < message.len() {
+        n_read += port.read(&mut buf[n_read..]).expect("Couldn't read");
+    }
+    assert_eq!(buf[..n_read], message);
+}
+
+#[test]
+fn test_read_with_timeout_hang_up() {
+    let mut fds: [libc::c_int; 2] = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let (mut reader, mut writer) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    let timeout = Duration::from_millis(10);
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(
+        read_with_timeout(&mut reader, timeout, &mut buf).unwrap(),
+        0
+    );
+    writer.write_all(&[1, 2]).unwrap();
+    drop(writer);
+    assert_eq!(
+        read_with_timeout(&mut reader, timeout, &mut buf).unwrap(),
+        2
+    );
+    let err = read_with_timeout(&mut reader, timeout, &mut buf)
+        .expect_err("Should be error, the other end hung up");
+    match err {
+        SerialComError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        _ => panic!("Expected UnexpectedEof, got {:?}", err),
+    }
+}
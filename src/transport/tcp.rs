@@ -0,0 +1,279 @@
+use crate::error::SerialComResult;
+use crate::transport::Transport;
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for a TCP connection to be established before giving up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Endpoint {
+    Client(Vec<SocketAddr>),
+    Server(TcpListener),
+}
+
+struct Connection {
+    stream: Option<TcpStream>,
+    /// Incremented every time a new stream is connected
+    generation: u64,
+}
+
+struct Shared {
+    endpoint: Endpoint,
+    connection: Mutex<Connection>,
+}
+
+/// TCP transport, for serial ports exposed over the network (e.g. with ser2net)
+///
+/// Works either as a client, connecting to a server, or as a server, accepting one client at a
+/// time. When the socket is closed, the transport reconnects (client) or accepts the next client
+/// (server) on the next read or write. While not connected, read waits for the read timeout and
+/// returns Ok(0), and write returns a NotConnected error.
+pub struct TcpTransport {
+    shared: Arc<Shared>,
+    read_timeout: Duration,
+}
+
+impl TcpTransport {
+    /// Connect to a TCP server at addr
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> SerialComResult<TcpTransport> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let stream = connect_any(&addrs)?;
+        TcpTransport::new(Endpoint::Client(addrs), Some(stream))
+    }
+
+    /// Listen for a TCP client on addr
+    ///
+    /// Doesn't wait for a client to connect; that happens on the first read or write.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> SerialComResult<TcpTransport> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        TcpTransport::new(Endpoint::Server(listener), None)
+    }
+
+    fn new(endpoint: Endpoint, stream: Option<TcpStream>) -> SerialComResult<TcpTransport> {
+        if let Some(ref stream) = stream {
+            stream.set_nodelay(true)?;
+        }
+        Ok(TcpTransport {
+            shared: Arc::new(Shared {
+                endpoint,
+                connection: Mutex::new(Connection {
+                    stream,
+                    generation: 0,
+                }),
+            }),
+            read_timeout: Duration::from_millis(50),
+        })
+    }
+
+    /// Set the max time read waits for data
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// The local address of the listening socket (server) or of the current connection (client)
+    pub fn local_addr(&self) -> SerialComResult<SocketAddr> {
+        match self.shared.endpoint {
+            Endpoint::Server(ref listener) => Ok(listener.local_addr()?),
+            Endpoint::Client(_) => match self.shared.current()? {
+                Some((stream, _)) => Ok(stream.local_addr()?),
+                None => Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+            },
+        }
+    }
+}
+
+impl Shared {
+    /// returns a handle to the connected stream and its generation, connecting first if needed
+    ///
+    /// returns Ok(None) if there is no connection and one couldn't be made right now
+    fn current(&self) -> SerialComResult<Option<(TcpStream, u64)>> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| io::Error::other("TcpTransport connection lock poisoned"))?;
+        if connection.stream.is_none() {
+            let new_stream = match self.endpoint {
+                Endpoint::Client(ref addrs) => connect_any(addrs).ok(),
+                Endpoint::Server(ref listener) => match listener.accept() {
+                    Ok((stream, _)) => Some(stream),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+                    Err(e) => return Err(e.into()),
+                },
+            };
+            if let Some(stream) = new_stream {
+                stream.set_nonblocking(false)?;
+                stream.set_nodelay(true)?;
+                connection.stream = Some(stream);
+                connection.generation += 1;
+            }
+        }
+        match connection.stream {
+            Some(ref stream) => Ok(Some((stream.try_clone()?, connection.generation))),
+            None => Ok(None),
+        }
+    }
+
+    /// Forget the stream of the given generation, so the next use reconnects
+    fn disconnect(&self, generation: u64) {
+        if let Ok(mut connection) = self.connection.lock() {
+            if connection.generation == generation {
+                connection.stream = None;
+            }
+        }
+    }
+}
+
+fn connect_any(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+impl Transport for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        let (mut stream, generation) = match self.shared.current()? {
+            Some(current) => current,
+            None => {
+                thread::sleep(self.read_timeout);
+                return Ok(0);
+            }
+        };
+        stream.set_read_timeout(Some(self.read_timeout))?;
+        match stream.read(buf) {
+            Ok(0) => {
+                // Closed by the other end
+                self.shared.disconnect(generation);
+                Ok(0)
+            }
+            Ok(n_read) => Ok(n_read),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(0)
+            }
+            Err(_) => {
+                self.shared.disconnect(generation);
+                Ok(0)
+            }
+        }
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        let (mut stream, generation) = self
+            .shared
+            .current()?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        if let Err(e) = stream.write_all(buf) {
+            self.shared.disconnect(generation);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        Ok(())
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(TcpTransport {
+            shared: Arc::clone(&self.shared),
+            read_timeout: self.read_timeout,
+        }))
+    }
+}
+
+#[cfg(test)]
+use crate::binarycom::app::{fake_device, BinaryComApp16, FakeRegisters, RegisterBitWidth};
+#[cfg(test)]
+use crate::binarycom::device::DeviceResponder;
+#[cfg(test)]
+use crate::binarycom::BinaryCom;
+
+#[cfg(test)]
+fn read_n(stream: &mut TcpStream, n: usize) -> Vec<u8> {
+    let mut result = vec![0; n];
+    stream
+        .read_exact(&mut result)
+        .expect("Couldn't read from stream");
+    result
+}
+
+#[test]
+fn test_tcp_client_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Couldn't bind");
+    let mut client =
+        TcpTransport::connect(listener.local_addr().unwrap()).expect("Couldn't connect");
+    let mut buf: arraydeque::ArrayDeque<u8, 16, arraydeque::Wrapping> =
+        arraydeque::ArrayDeque::new();
+    let frame_len = buf
+        .send_message(&1, &[0, 5])
+        .expect("Couldn't send_message");
+    let frame: Vec<u8> = buf.iter().copied().collect();
+
+    let (mut server_stream, _) = listener.accept().expect("Couldn't accept");
+    client.write(&frame).expect("Couldn't write");
+    assert_eq!(read_n(&mut server_stream, frame_len), frame);
+    server_stream.write_all(&frame).unwrap();
+    let mut readbuf: [u8; 64] = [0; 64];
+    let mut n_read = 0;
+    while n_read < frame_len {
+        n_read += client.read(&mut readbuf[n_read..]).expect("Couldn't read");
+    }
+    assert_eq!(readbuf[..n_read], frame[..]);
+
+    drop(server_stream);
+    // Read notices the socket was closed; the next write reconnects
+    assert_eq!(client.read(&mut readbuf).expect("Couldn't read"), 0);
+    client
+        .write(&frame)
+        .expect("Couldn't write after reconnect");
+    let (mut server_stream, _) = listener.accept().expect("Couldn't accept");
+    assert_eq!(read_n(&mut server_stream, frame_len), frame);
+}
+
+#[test]
+fn test_tcp_server_accepts_next_client() {
+    let mut server = TcpTransport::listen("127.0.0.1:0").expect("Couldn't listen");
+    let addr = server.local_addr().expect("Couldn't get address");
+    let mut readbuf: [u8; 64] = [0; 64];
+    assert_eq!(server.read(&mut readbuf).expect("Couldn't read"), 0);
+    server
+        .write(&[1, 2, 3])
+        .expect_err("Should be error, no client yet");
+    for i_client in 0..3u8 {
+        let mut client_stream = TcpStream::connect(addr).expect("Couldn't connect");
+        client_stream.write_all(&[i_client, 0]).unwrap();
+        let mut n_read = 0;
+        while n_read < 2 {
+            n_read += server.read(&mut readbuf[n_read..]).expect("Couldn't read");
+        }
+        assert_eq!(readbuf[..2], [i_client, 0]);
+        server.write(&[i_client + 10]).expect("Couldn't write");
+        assert_eq!(read_n(&mut client_stream, 1), [i_client + 10]);
+    }
+}
+
+#[test]
+fn test_tcp_app_read_write_reg() {
+    let device = TcpTransport::listen("127.0.0.1:0").expect("Couldn't listen");
+    let addr = device.local_addr().expect("Couldn't get address");
+    thread::spawn(move || fake_device(device, DeviceResponder::new(FakeRegisters)));
+    let host = TcpTransport::connect(addr).expect("Couldn't connect");
+    let mut app = BinaryComApp16::new(RegisterBitWidth::ThirtyTwo, Box::new(host))
+        .expect("Couldn't make app");
+    for reg_num in [0u16, 0x100, 0xABCD].iter() {
+        let reg_val = app.read_reg(*reg_num).expect("Couldn't read reg");
+        assert_eq!(reg_val, u32::from(*reg_num) + 0x1000);
+        app.write_reg(*reg_num, 42).expect("Couldn't write reg");
+    }
+}
@@ -0,0 +1,54 @@
+use crate::error::SerialComResult;
+use crate::transport::Transport;
+
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Unix domain socket transport, used to talk to a Broker
+pub struct UnixTransport {
+    stream: UnixStream,
+}
+
+impl UnixTransport {
+    /// Connect to the Unix domain socket at path
+    pub fn connect<P: AsRef<Path>>(path: P) -> SerialComResult<UnixTransport> {
+        UnixTransport::from_stream(UnixStream::connect(path)?)
+    }
+
+    /// Use an already connected stream
+    pub fn from_stream(stream: UnixStream) -> SerialComResult<UnixTransport> {
+        stream.set_read_timeout(Some(Duration::from_millis(50)))?;
+        Ok(UnixTransport { stream })
+    }
+}
+
+impl Transport for UnixTransport {
+    fn read(&mut self, buf: &mut [u8]) -> SerialComResult<usize> {
+        match self.stream.read(buf) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n_read) => Ok(n_read),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(0)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn write(&mut self, buf: &[u8]) -> SerialComResult<()> {
+        Ok(self.stream.write_all(buf)?)
+    }
+    fn flush(&mut self) -> SerialComResult<()> {
+        Ok(self.stream.flush()?)
+    }
+    fn try_clone(&self) -> SerialComResult<Box<dyn Transport>> {
+        Ok(Box::new(UnixTransport {
+            stream: self.stream.try_clone()?,
+        }))
+    }
+}